@@ -0,0 +1,34 @@
+//! Compares densely allocating the lowest IDs with and without
+//! [`IdaBuilder::capacity_hint`](ida_rs::IdaBuilder::capacity_hint).
+//!
+//! Run with `cargo bench --bench dense_alloc`.
+
+use ida_rs::Ida;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const IDS: usize = 100_000;
+const ROUNDS: u32 = 20;
+
+/// Returns the average time taken to allocate the first `IDS` IDs from
+/// allocators built by `make`. Construction is not included.
+fn bench(make: impl Fn() -> Ida) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let ida = make();
+        let start = Instant::now();
+        for _ in 0..IDS {
+            black_box(ida.alloc());
+        }
+        total += start.elapsed();
+    }
+    total / ROUNDS
+}
+
+fn main() {
+    let plain = bench(Ida::new);
+    let hinted = bench(|| Ida::builder().capacity_hint(IDS).build());
+    println!("dense alloc of {IDS} IDs, average of {ROUNDS} rounds");
+    println!("  without hint: {plain:?}");
+    println!("  with hint:    {hinted:?}");
+}
@@ -0,0 +1,43 @@
+//! Compares freeing a sorted, non-contiguous batch of IDs one at a time with
+//! freeing it through [`Ida::free_iter`].
+//!
+//! Run with `cargo bench --bench free_iter`.
+
+use ida_rs::Ida;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const IDS: usize = 100_000;
+const ROUNDS: u32 = 20;
+
+/// Returns the average time taken by `free` to release every third ID from
+/// an allocator holding the first `IDS` IDs. Setup is not included.
+fn bench(free: impl Fn(&Ida, &[usize])) -> Duration {
+    let batch: Vec<usize> = (0..IDS).step_by(3).collect();
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let ida = Ida::builder().capacity_hint(IDS).build();
+        for _ in 0..IDS {
+            ida.alloc();
+        }
+        let start = Instant::now();
+        free(&ida, &batch);
+        total += start.elapsed();
+        black_box(ida);
+    }
+    total / ROUNDS
+}
+
+fn main() {
+    let single = bench(|ida, batch| {
+        for &id in batch {
+            ida.free(id);
+        }
+    });
+    let batched = bench(|ida, batch| {
+        black_box(ida.free_iter(batch.iter().copied()));
+    });
+    println!("freeing every third of {IDS} IDs, average of {ROUNDS} rounds");
+    println!("  per-ID free: {single:?}");
+    println!("  free_iter:   {batched:?}");
+}
@@ -0,0 +1,475 @@
+//! A builder for configuring an [`Ida`] before it is used.
+
+use alloc::sync::Arc;
+use core::ops::Range;
+use spin::Mutex;
+
+#[cfg(feature = "allocator_api")]
+use core::alloc::Allocator;
+
+use crate::{AllocPolicy, IDA_MAX_LEVELS, Ida, IdaInner};
+#[cfg(feature = "allocator_api")]
+use crate::{IdaNode, NodeAlloc};
+
+/// Configures an [`Ida`] before it is created.
+///
+/// Start from [`Ida::builder`], chain the settings that differ from the
+/// defaults, and finish with [`build`](Self::build). The settings combine
+/// freely, so a bounded allocator can also have a recycle delay, a lazy one
+/// a blacklist, and so on. A builder with no settings builds the same
+/// allocator as [`Ida::new`].
+///
+/// # Examples
+///
+/// ```
+/// use ida_rs::{AllocPolicy, Ida};
+///
+/// let ida = Ida::builder()
+///     .policy(AllocPolicy::FifoReuse)
+///     .max(2)
+///     .blacklist(&[1])
+///     .build();
+///
+/// assert_eq!(ida.alloc(), Some(0));
+/// assert_eq!(ida.alloc(), Some(2));
+/// assert_eq!(ida.alloc(), None);
+/// ```
+#[derive(Debug)]
+#[must_use = "a builder does nothing until `build` is called"]
+pub struct IdaBuilder {
+    inner: IdaInner,
+    prealloc_depth: usize,
+    capacity_hint: usize,
+}
+
+impl Default for IdaBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdaBuilder {
+    /// Creates a builder with every setting at its default.
+    pub fn new() -> Self {
+        Self {
+            inner: IdaInner::new(AllocPolicy::default()),
+            prealloc_depth: 0,
+            capacity_hint: 0,
+        }
+    }
+
+    /// Reuses freed IDs according to `policy`.
+    ///
+    /// See [`AllocPolicy`] for the available strategies.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocPolicy, Ida};
+    ///
+    /// let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// ida.free(1);
+    /// ida.free(0);
+    ///
+    /// // Freed IDs come back in the order they were freed
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// assert_eq!(ida.alloc(), Some(3));
+    /// ```
+    pub fn policy(mut self, policy: AllocPolicy) -> Self {
+        self.inner.policy = policy;
+        self
+    }
+
+    /// Allocates all of the tree's nodes from `alloc`.
+    ///
+    /// Every node and child map the tree grows is allocated from, and
+    /// returned to, `alloc`, which suits arenas and allocators that track
+    /// kernel memory. Bookkeeping outside the tree, such as the
+    /// [`AllocPolicy::FifoReuse`] queue, still uses the global allocator.
+    /// Only available with the `allocator_api` feature, which needs a
+    /// nightly toolchain.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #![feature(allocator_api)]
+    /// use ida_rs::Ida;
+    /// use std::alloc::System;
+    ///
+    /// let ida = Ida::builder().allocator(System).build();
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    #[cfg(feature = "allocator_api")]
+    pub fn allocator<A: Allocator + Send + Sync + 'static>(mut self, alloc: A) -> Self {
+        self.inner.root = IdaNode::root_in(NodeAlloc::Custom(Arc::new(alloc)));
+        self
+    }
+
+    /// Holds freed IDs back from reuse until `min_allocs` further
+    /// allocations have been made.
+    ///
+    /// Handing a just-freed ID straight back makes a stale handle to it
+    /// silently valid again. With a recycle delay, a freed ID sits out at
+    /// least `min_allocs` allocations, made by [`Ida::alloc`] or any other
+    /// allocating method, before searches consider it again, which makes
+    /// such bugs show up reproducibly. The delay is counted in allocations
+    /// rather than time, so it behaves the same in every run and needs no
+    /// clock. If every free ID is still waiting, allocation fails;
+    /// [`Ida::alloc_or_recycle`] hands out the one freed longest ago
+    /// instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().recycle_delay(2).build();
+    /// let id = ida.alloc().unwrap();
+    /// ida.free(id);
+    ///
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// assert_eq!(ida.alloc(), Some(2));
+    /// // Two allocations have passed, so the freed ID is reusable
+    /// assert_eq!(ida.alloc(), Some(id));
+    /// ```
+    pub fn recycle_delay(mut self, min_allocs: u64) -> Self {
+        self.inner.recycle_delay = min_allocs;
+        self
+    }
+
+    /// Makes [`Ida::alloc`] walk the ID space in strides of `stride`.
+    ///
+    /// Free IDs are handed out in passes of 64 strides: `0, stride, ...,
+    /// 63 * stride`, then `1, 1 + stride, ...`, and so on until every gap
+    /// in those `64 * stride` IDs is filled, before moving on to the next
+    /// `64 * stride`. A pass also ends at the allocator's bound, if that
+    /// comes first. The order depends only on which IDs are free, so it is
+    /// the same on every run, yet it spreads allocations over many leaves
+    /// and subtrees, which exercises boundary handling that lowest-first
+    /// allocation rarely reaches. The stride replaces the allocation
+    /// policy; a stride of one is plain lowest-first allocation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stride` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().stride(3).max(6).build();
+    /// let order: Vec<usize> = std::iter::from_fn(|| ida.alloc()).collect();
+    /// assert_eq!(order, vec![0, 3, 6, 1, 4, 2, 5]);
+    /// ```
+    #[track_caller]
+    pub fn stride(mut self, stride: usize) -> Self {
+        assert!(stride > 0, "stride must be at least one");
+        self.inner.stride = stride;
+        self
+    }
+
+    /// Gives each [`Priority`](crate::Priority) class its own window of IDs
+    /// for [`Ida::alloc_class`].
+    ///
+    /// `windows` holds the `[lo, hi)` window of
+    /// [`Priority::High`](crate::Priority::High),
+    /// [`Priority::Normal`](crate::Priority::Normal) and
+    /// [`Priority::Low`](crate::Priority::Low), in that order. Windows are
+    /// expected not to overlap; a class is only isolated from the others to
+    /// the extent its window is. Plain [`Ida::alloc`] and the other methods
+    /// still draw from the whole ID space.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{Ida, Priority};
+    ///
+    /// let ida = Ida::builder()
+    ///     .class_windows([0..16, 16..256, 256..1024])
+    ///     .build();
+    /// assert_eq!(ida.alloc_class(Priority::Low), Some(256));
+    /// assert_eq!(ida.alloc_class(Priority::High), Some(0));
+    /// ```
+    pub fn class_windows(mut self, windows: [Range<usize>; 3]) -> Self {
+        self.inner.class_windows = Some(windows);
+        self
+    }
+
+    /// Defers pruning freed nodes until [`Ida::maintain`] is called.
+    ///
+    /// Normally [`Ida::free`] removes every node left empty on the freed
+    /// ID's path, which makes each free touch and possibly deallocate
+    /// several nodes. In lazy mode `free` only clears the ID and the
+    /// full-bits above it, and remembers the leaf so a later `maintain` can
+    /// prune it in bulk. Freed IDs are reusable immediately; only
+    /// [`Ida::node_count`] and [`Ida::memory_usage`] run high until the
+    /// next `maintain`. Range frees still prune as they go.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().lazy().build();
+    /// let id = ida.alloc().unwrap();
+    /// ida.free(id);
+    /// assert!(ida.node_count() > 1);
+    /// assert_eq!(ida.alloc(), Some(id));
+    /// ida.free(id);
+    ///
+    /// ida.maintain();
+    /// assert_eq!(ida.node_count(), 1);
+    /// ```
+    pub fn lazy(mut self) -> Self {
+        self.inner.lazy = true;
+        self
+    }
+
+    /// Builds the leftmost path of the tree down to `levels` levels below
+    /// the root up front.
+    ///
+    /// Normally the first allocation has to create every node between the
+    /// root and the leaf holding ID `0`. Pre-building that path moves the
+    /// memory allocation to construction time, so latency-sensitive callers
+    /// don't pay for it on the hot path. Values of `levels` beyond the depth
+    /// of the tree are clamped, so `usize::MAX` builds the full path.
+    ///
+    /// The pre-built nodes are empty and are pruned like any other node once
+    /// the IDs beneath them are freed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+    /// let nodes = ida.node_count();
+    ///
+    /// // The first allocation reuses the pre-built path.
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// assert_eq!(ida.node_count(), nodes);
+    /// ```
+    pub fn prealloc_depth(mut self, levels: usize) -> Self {
+        self.prealloc_depth = levels;
+        self
+    }
+
+    /// Builds every tree node needed to hold IDs `0..capacity` up front.
+    ///
+    /// This is [`prealloc_depth`](Self::prealloc_depth) for workloads known
+    /// to densely use the low IDs: instead of the tree growing one node at a
+    /// time as allocation crosses each leaf boundary, all of the nodes are
+    /// created up front. Allocation behaves exactly as it would otherwise.
+    /// A `capacity` of zero builds nothing.
+    ///
+    /// The pre-built nodes are empty and are pruned like any other node once
+    /// the IDs beneath them are freed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().capacity_hint(1_000).build();
+    /// let nodes = ida.node_count();
+    ///
+    /// for expected in 0..1_000 {
+    ///     assert_eq!(ida.alloc(), Some(expected));
+    /// }
+    /// assert_eq!(ida.node_count(), nodes);
+    /// ```
+    pub fn capacity_hint(mut self, capacity: usize) -> Self {
+        self.capacity_hint = capacity;
+        self
+    }
+
+    /// Only hands out IDs up to and including `max`.
+    ///
+    /// Once every ID up to and including `max` is allocated, allocation
+    /// returns `None` until an ID is freed. This replaces the upper end of
+    /// a [`span`](Self::span) and keeps its lower end.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().max(1).build();
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// assert_eq!(ida.alloc(), None);
+    ///
+    /// ida.free(0);
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    pub fn max(mut self, max: usize) -> Self {
+        self.inner.max = max;
+        self
+    }
+
+    /// Only hands out IDs in the half-open span `start..end`.
+    ///
+    /// This is [`max`](Self::max) with a lower bound as well, for carving
+    /// one ID space into per-service slabs: the first allocation returns
+    /// `start`, and allocation returns `None` once every ID below `end` is
+    /// taken. [`Ida::total_capacity`] reports the size of the span. IDs
+    /// outside it are never allocated, so [`Ida::alloc_at`] rejects them
+    /// with [`AllocError::OutOfRange`](crate::AllocError::OutOfRange),
+    /// [`Ida::is_allocated`] reports them as free and [`Ida::free`] ignores
+    /// them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the span is empty, that is if `start >= end`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().span(1000, 1002).build();
+    /// assert_eq!(ida.alloc(), Some(1000));
+    /// assert_eq!(ida.alloc(), Some(1001));
+    /// assert_eq!(ida.alloc(), None);
+    /// assert_eq!(ida.total_capacity(), 2);
+    /// ```
+    #[track_caller]
+    pub fn span(mut self, start: usize, end: usize) -> Self {
+        assert!(start < end, "span {start}..{end} is empty");
+        self.inner.min = start;
+        self.inner.max = end - 1;
+        self
+    }
+
+    /// Never hands out any of the IDs in `blacklist`.
+    ///
+    /// This suits reserved values scattered through the ID space. Unlike
+    /// reserving them with [`Ida::alloc_at`], blacklisted IDs are not
+    /// counted as allocated: [`Ida::is_allocated`] returns `false` for them
+    /// and [`Ida::len`] does not include them. Allocation methods skip them,
+    /// `alloc_at` rejects them, and bulk methods such as [`Ida::reset_to`]
+    /// and [`Ida::apply_bitset`] leave them unset. The blacklist is stored
+    /// as a sorted list and lasts for the allocator's lifetime. Calling this
+    /// again adds to it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().blacklist(&[0, 2]).build();
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// assert_eq!(ida.alloc(), Some(3));
+    ///
+    /// assert!(!ida.is_allocated(0));
+    /// assert_eq!(ida.len(), 2);
+    /// ```
+    pub fn blacklist(mut self, blacklist: &[usize]) -> Self {
+        let list = &mut self.inner.blacklist;
+        list.extend_from_slice(blacklist);
+        list.sort_unstable();
+        list.dedup();
+        self
+    }
+
+    /// Creates the allocator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`max`](Self::max) was set below the start of the
+    /// [`span`](Self::span), leaving no ID to hand out.
+    #[track_caller]
+    pub fn build(self) -> Ida {
+        let Self {
+            mut inner,
+            prealloc_depth,
+            capacity_hint,
+        } = self;
+        assert!(
+            inner.min <= inner.max,
+            "bound {} is below the span's start {}",
+            inner.max,
+            inner.min
+        );
+        let mut node = &mut inner.root;
+        for _ in 0..prealloc_depth.min(IDA_MAX_LEVELS - 1) {
+            node = Arc::make_mut(
+                node.children
+                    .entry(0)
+                    .or_insert_with(|| node.alloc.new_child()),
+            );
+        }
+        if capacity_hint > 0 {
+            inner
+                .root
+                .build_through(IDA_MAX_LEVELS - 1, 0, capacity_hint - 1);
+        }
+        Ida {
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllocPolicy;
+
+    #[test]
+    fn test_settings_combine() {
+        // A stride within a span walks the span.
+        let ida = Ida::builder().span(100, 108).stride(4).build();
+        let order: Vec<usize> = core::iter::from_fn(|| ida.alloc()).collect();
+        assert_eq!(order, [100, 104, 101, 105, 102, 106, 103, 107]);
+
+        // A recycle delay under FIFO reuse, with a bound.
+        let ida = Ida::builder()
+            .policy(AllocPolicy::FifoReuse)
+            .recycle_delay(2)
+            .max(3)
+            .build();
+        for _ in 0..3 {
+            ida.alloc();
+        }
+        ida.free(0);
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), None);
+        assert_eq!(ida.alloc_or_recycle(), Some(0));
+
+        // Lazy mode with a blacklist, and blacklists that accumulate.
+        let ida = Ida::builder()
+            .lazy()
+            .blacklist(&[1])
+            .blacklist(&[3, 1])
+            .build();
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(4));
+        ida.free(4);
+        let nodes = ida.node_count();
+        ida.free(0);
+        ida.free(2);
+        assert_eq!(ida.node_count(), nodes);
+        ida.maintain();
+        assert_eq!(ida.node_count(), 1);
+
+        // Pre-building the tree does not depend on the order of settings.
+        let hinted = Ida::builder().capacity_hint(5_000).max(10_000).build();
+        let nodes = hinted.node_count();
+        assert!(nodes > 1);
+        assert_eq!(
+            Ida::builder()
+                .max(10_000)
+                .capacity_hint(5_000)
+                .build()
+                .node_count(),
+            nodes
+        );
+    }
+}
@@ -1,4 +1,5 @@
 #![cfg_attr(not(test), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api, btreemap_alloc))]
 
 //!
 //! # An ID Allocator for Sparse ID Spaces
@@ -15,6 +16,17 @@
 //! - **Thread-Safe:** All public methods are thread-safe, using a spinlock for synchronization.
 //! - **Memory-Efficient for Sparse Sets:** Ideal when allocated IDs are far apart.
 //!
+//! ## Panics
+//! A few methods panic on arguments that can never be valid, and the slabs
+//! panic when they run out of IDs. Each documents this under `# Panics` and
+//! is marked `#[track_caller]`, so the reported location is the line that
+//! made the call rather than a line inside this crate. They are
+//! [`IdaBuilder::stride`], [`IdaBuilder::span`], [`IdaBuilder::build`],
+//! [`Ida::region_of`],
+//! [`Ida::alloc_aligned_in`], [`Ida::subtree_usage`], [`Ida::iter_chunks`],
+//! [`ShardedIda::new`], [`IdaSlab::insert`], [`IdaSlab::insert_default`]
+//! and [`GenSlab::insert`].
+//!
 //! ## Example
 //! ```
 //! use ida_rs::Ida;
@@ -38,18 +50,152 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, collections::btree_map::BTreeMap};
-use core::fmt::Debug;
-use spin::Mutex;
+mod builder;
+mod magazine;
+mod sharded;
+mod slab;
+mod two_pool;
+
+pub use builder::IdaBuilder;
+pub use magazine::MagazineIda;
+pub use sharded::ShardedIda;
+pub use slab::{Entity, GenGuard, GenGuardMut, GenSlab, GuardMut, IdaSlab};
+pub use two_pool::TwoPoolIda;
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet, vec_deque::VecDeque},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::{self, Debug, Write},
+    mem,
+    ops::{Bound, Deref, Range, RangeBounds},
+};
+use spin::{Mutex, MutexGuard};
+
+#[cfg(feature = "allocator_api")]
+use alloc::alloc::Global;
+#[cfg(feature = "allocator_api")]
+use core::{
+    alloc::{Allocator, Layout},
+    ptr::NonNull,
+};
 
 const IDA_SHIFT: usize = 6;
 const IDA_BITMAP_BITS: usize = 1 << IDA_SHIFT;
-// This calculation is the integer division equivalent of `ceil(64 / IDA_SHIFT)`
-// and ensures that we have enough levels to cover the entire 64-bit ID space.
+const IDA_ID_BITS: usize = usize::BITS as usize;
+// This calculation is the integer division equivalent of `ceil(IDA_ID_BITS / IDA_SHIFT)`
+// and ensures that we have enough levels to cover the entire `usize` ID space.
 // We intentionally use this arithmetic to maintain compatibility with older Rust versions
 // that do not have the `div_ceil` function stabilized.
 #[allow(clippy::manual_div_ceil)]
-const IDA_MAX_LEVELS: usize = (64 + IDA_SHIFT - 1) / IDA_SHIFT;
+const IDA_MAX_LEVELS: usize = (IDA_ID_BITS + IDA_SHIFT - 1) / IDA_SHIFT;
+// The root only has room for the ID bits left over by the levels below it
+// (4 bits, or 16 children, on 64-bit targets). Any higher child index would
+// shift bits out of the top of the ID.
+const IDA_ROOT_CHILDREN: usize = 1 << (IDA_ID_BITS - (IDA_MAX_LEVELS - 1) * IDA_SHIFT);
+
+// `AllocPolicy::WarmSubtree` clusters allocations within blocks the size of
+// a first-level subtree: one leaf's worth of leaves, or 4096 IDs.
+const WARM_BLOCK_SHIFT: usize = 2 * IDA_SHIFT;
+
+// The walk set up by `IdaBuilder::stride` takes this many steps of the
+// stride before it wraps around to the next remainder.
+const STRIDE_PASS: usize = IDA_BITMAP_BITS;
+
+// Estimated heap cost of one tree node: the node itself, its reference
+// counts, and its entry in the parent's child map.
+const IDA_NODE_BYTES: usize =
+    size_of::<IdaNode>() + 2 * size_of::<usize>() + size_of::<(usize, Child)>();
+
+// Number of gaps listed in a `HealthReport`.
+const HEALTH_REPORT_GAPS: usize = 8;
+
+/// Returns the lowest ID at or after `id` whose position within its leaf is
+/// set in `leaf_mask`.
+fn first_masked_at_or_after(id: usize, leaf_mask: u64) -> Option<usize> {
+    let leaf_base = id & !(IDA_BITMAP_BITS - 1);
+    let bits = leaf_mask & (u64::MAX << (id & (IDA_BITMAP_BITS - 1)));
+    if bits != 0 {
+        return Some(leaf_base + bits.trailing_zeros() as usize);
+    }
+    if leaf_mask == 0 {
+        return None;
+    }
+    let next_base = leaf_base.checked_add(IDA_BITMAP_BITS)?;
+    Some(next_base + leaf_mask.trailing_zeros() as usize)
+}
+
+/// Converts `range` into inclusive `(lo, hi)` bounds, or `None` if it is
+/// empty.
+fn inclusive_bounds(range: &impl RangeBounds<usize>) -> Option<(usize, usize)> {
+    let lo = match range.start_bound() {
+        Bound::Included(&lo) => lo,
+        Bound::Excluded(&lo) => lo.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let hi = match range.end_bound() {
+        Bound::Included(&hi) => hi,
+        Bound::Excluded(&hi) => hi.checked_sub(1)?,
+        Bound::Unbounded => usize::MAX,
+    };
+    (lo <= hi).then_some((lo, hi))
+}
+
+/// Returns a mask with bits `first..=last` set.
+fn bit_range_mask(first: usize, last: usize) -> u64 {
+    (u64::MAX >> (IDA_BITMAP_BITS - 1 - last)) & (u64::MAX << first)
+}
+
+/// Queues `id` at the back of `queue`, dropping the oldest entry if that
+/// leaves more than `window` queued.
+fn push_bounded(queue: &mut VecDeque<usize>, id: usize, window: usize) {
+    queue.push_back(id);
+    if queue.len() > window {
+        queue.pop_front();
+    }
+}
+
+/// Advances a SplitMix64 generator and returns its next output.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Appends `value` to `out` as an unsigned LEB128 varint.
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads an unsigned LEB128 varint from the front of `bytes`, advancing past
+/// it.
+fn read_varint(bytes: &mut &[u8]) -> Result<usize, DecodeError> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = bytes.split_first().ok_or(DecodeError::Truncated)?;
+        *bytes = rest;
+        let low = (byte & 0x7f) as usize;
+        if shift >= usize::BITS || (low << shift) >> shift != low {
+            return Err(DecodeError::Overflow);
+        }
+        value |= low << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
 
 /// A thread-safe ID allocator for sparse ID spaces.
 ///
@@ -104,426 +250,9678 @@ const IDA_MAX_LEVELS: usize = (64 + IDA_SHIFT - 1) / IDA_SHIFT;
 /// ```
 #[derive(Debug)]
 pub struct Ida {
-    root: Mutex<IdaNode>,
+    inner: Mutex<IdaInner>,
 }
 
-#[derive(Debug)]
-struct IdaNode {
-    bitmap: u64,
-    children: BTreeMap<usize, Box<IdaNode>>,
+/// The strategy an [`Ida`] uses to pick which free ID to hand out next.
+///
+/// The policy is fixed at construction time via [`IdaBuilder::policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocPolicy {
+    /// Always return the lowest free ID. Freed IDs are reused immediately.
+    #[default]
+    LowestFirst,
+    /// Reuse freed IDs in the order they were freed (oldest-freed first),
+    /// falling back to the lowest free ID once no freed IDs are pending.
+    ///
+    /// This keeps a just-freed ID from being handed straight back, which
+    /// makes use-after-free bugs easier to spot.
+    FifoReuse,
+    /// Keep allocating from the block of 4096 IDs (one first-level subtree)
+    /// that the last allocation came from, moving on to the lowest free ID
+    /// elsewhere only once that block is full.
+    ///
+    /// This keeps related IDs clustered instead of scattering them into
+    /// holes left by frees in distant parts of the tree.
+    WarmSubtree,
+    /// Reuse the most recently freed ID first, falling back to the lowest
+    /// free ID once no freed IDs are pending.
+    ///
+    /// Handing back the ID whose associated data was touched last keeps
+    /// that data warm in cache. Only the given number of most recent frees
+    /// are remembered; older ones drop off the stack but stay free in the
+    /// tree, where the fallback search finds them.
+    LifoReuse(usize),
 }
 
-impl IdaNode {
-    pub fn new() -> Self {
-        Self {
-            bitmap: 0,
-            children: BTreeMap::new(),
+/// The error type for allocation requests that can fail for more than one
+/// reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The requested ID is already allocated.
+    AlreadyAllocated,
+    /// The requested ID is outside the range this allocator may hand out.
+    OutOfRange,
+    /// Every ID the allocator may hand out is already allocated.
+    Exhausted,
+    /// The search gave up after visiting its allotted number of nodes.
+    EffortExceeded,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::AlreadyAllocated => f.write_str("ID is already allocated"),
+            AllocError::OutOfRange => f.write_str("ID is outside the allocatable range"),
+            AllocError::Exhausted => f.write_str("no free IDs remain"),
+            AllocError::EffortExceeded => f.write_str("search effort limit exceeded"),
         }
     }
+}
 
-    pub fn alloc(&mut self, level: usize) -> Option<usize> {
-        // CASE: We are at a leaf node
-        // The bitmap here represents individual IDs
-        if level == 0 {
-            // All ones means no free IDs
-            if self.bitmap == u64::MAX {
-                return None;
+impl core::error::Error for AllocError {}
+
+/// The error type for [`Ida::relocate_range`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocateError {
+    /// The target range holds an allocated ID that is not being moved.
+    Conflict(usize),
+    /// The source or target range runs past the allocator's bound.
+    OutOfRange,
+}
+
+impl fmt::Display for RelocateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelocateError::Conflict(id) => write!(f, "target ID {id} is already allocated"),
+            RelocateError::OutOfRange => f.write_str("range is outside the allocatable range"),
+        }
+    }
+}
+
+impl core::error::Error for RelocateError {}
+
+/// The error returned by [`Ida::set_max`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeError {
+    /// An ID above the requested bound is still allocated. Holds the lowest
+    /// such ID.
+    Occupied(usize),
+    /// The requested bound is below the lowest ID of the allocator's span.
+    /// Holds that lowest ID.
+    BelowMin(usize),
+}
+
+impl fmt::Display for ResizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResizeError::Occupied(id) => write!(f, "ID {id} above the new bound is allocated"),
+            ResizeError::BelowMin(min) => write!(f, "new bound is below the lowest ID {min}"),
+        }
+    }
+}
+
+impl core::error::Error for ResizeError {}
+
+/// The error returned by [`Ida::load_checked`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The IDs are not in strictly ascending order. Holds the index of the
+    /// first ID that is not greater than the one before it.
+    NotAscending(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotAscending(index) => {
+                write!(f, "ID at index {index} is not in ascending order")
             }
-            // Using trailing_ones to find the first zero bit,
-            // which is an unallocated ID
-            let bit = self.bitmap.trailing_ones() as usize;
-            self.bitmap |= 1 << bit;
-            return Some(bit);
         }
+    }
+}
 
-        // CASE: We are at an internal node
-        // The bitmap here represents child nodes. We iterate through the unset bits
-        // (0s), which correspond to children that are not full.
-        while self.bitmap != u64::MAX {
-            let i = self.bitmap.trailing_ones() as usize; // Find index of first 0 bit.
+impl core::error::Error for LoadError {}
 
-            // The child node is either unallocated or not fully allocated, get it.
-            let child = self
-                .children
-                .entry(i)
-                .or_insert_with(|| Box::new(IdaNode::new()));
+/// The error returned by [`Ida::from_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended in the middle of the encoding.
+    Truncated,
+    /// A number does not fit in a `usize`, or a range runs past
+    /// `usize::MAX`.
+    Overflow,
+    /// A range is empty, or does not start after the end of the one before
+    /// it.
+    Malformed,
+    /// Bytes were left over after the last range.
+    TrailingBytes,
+    /// The ranges hold more IDs than the caller's limit.
+    TooManyIds,
+}
 
-            // Recursively allocate in the child node.
-            if let Some(id_in_child) = child.alloc(level - 1) {
-                // After the allocation, check if the child is now fully allocated.
-                // If so, set the corresponding bit in this node's bitmap.
-                if child.bitmap == u64::MAX {
-                    self.bitmap |= 1 << i;
-                }
-                // Compute the full ID by combining the index and the child's ID.
-                let id = (i << (level * IDA_SHIFT)) | id_in_child;
-                return Some(id);
-            } else {
-                // The child was marked as having space in our bitmap, but the recursive
-                // alloc returned None, implying it's actually full. We fix this
-                // inconsistency here and continue the search in the next available child.
-                self.bitmap |= 1 << i;
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => f.write_str("buffer ends in the middle of the encoding"),
+            DecodeError::Overflow => f.write_str("encoded value does not fit in a usize"),
+            DecodeError::Malformed => f.write_str("ranges are empty, overlapping or out of order"),
+            DecodeError::TrailingBytes => f.write_str("unexpected bytes after the last range"),
+            DecodeError::TooManyIds => f.write_str("ranges hold more IDs than the limit"),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// The error returned by [`Ida::alloc_if_unfragmented`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FragmentationError {
+    /// The allocation would have left fragmentation above the threshold.
+    /// Holds the fragmentation it would have left behind.
+    TooHigh(f64),
+    /// Every ID the allocator may hand out is already allocated.
+    Exhausted,
+}
+
+impl fmt::Display for FragmentationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentationError::TooHigh(fragmentation) => {
+                write!(f, "allocation would leave fragmentation at {fragmentation}")
             }
+            FragmentationError::Exhausted => f.write_str("no free IDs remain"),
         }
+    }
+}
 
-        None
+impl core::error::Error for FragmentationError {}
+
+/// The parity of an ID, used by [`Ida::alloc_parity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    /// IDs divisible by two.
+    Even,
+    /// IDs not divisible by two.
+    Odd,
+}
+
+impl Parity {
+    /// The positions within a leaf bitmap that have this parity. Leaves
+    /// always start at a multiple of 64, so this is the same for every leaf.
+    fn leaf_mask(self) -> u64 {
+        match self {
+            Parity::Even => 0x5555_5555_5555_5555,
+            Parity::Odd => 0xAAAA_AAAA_AAAA_AAAA,
+        }
     }
+}
 
-    pub fn free(&mut self, id: usize, level: usize) {
-        // Determine which bit index to clear at this level
-        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+/// The priority class of an allocation made with [`Ida::alloc_class`].
+///
+/// Classes are ordered from highest to lowest priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Latency-critical allocations, such as real-time work.
+    High,
+    /// Ordinary allocations.
+    Normal,
+    /// Best-effort allocations, the first to be refused under pressure.
+    Low,
+}
 
-        // CASE: We are at a leaf node
-        if level == 0 {
-            // Simply clear the bit corresponding to the ID
-            self.bitmap &= !(1 << bit_index);
+/// An iterator over the allocated IDs of an [`Ida`] that holds its lock.
+///
+/// Created by [`Ida::iter_locked`]. The allocator is locked until the
+/// iterator is dropped.
+pub struct LockedIter<'a> {
+    inner: MutexGuard<'a, IdaInner>,
+    next: Option<usize>,
+}
+
+impl Iterator for LockedIter<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let id = self
+            .inner
+            .root
+            .find_allocated(IDA_MAX_LEVELS - 1, 0, self.next?, usize::MAX);
+        self.next = id.and_then(|id| id.checked_add(1));
+        id
+    }
+}
+
+impl Debug for LockedIter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockedIter")
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+/// The path an ID takes through the radix tree, as returned by
+/// [`Ida::describe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdPath {
+    /// One step per tree level, from the root down to the leaf.
+    pub steps: Vec<PathStep>,
+    /// Whether the ID's bit is set in its leaf.
+    pub allocated: bool,
+}
+
+/// An ID tentatively claimed by [`Ida::alloc_prepare`].
+///
+/// The ID is allocated, so no one else can be handed it, but
+/// [`Ida::is_committed`] reports it as pending until [`commit`](Self::commit)
+/// is called. Calling [`rollback`](Self::rollback), or dropping the token
+/// without committing, frees the ID again.
+#[must_use = "dropping a PreparedId rolls the allocation back"]
+#[derive(Debug)]
+pub struct PreparedId<'a> {
+    ida: &'a Ida,
+    id: usize,
+    committed: bool,
+}
+
+impl PreparedId<'_> {
+    /// Returns the claimed ID.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Makes the allocation permanent, returning the ID.
+    pub fn commit(mut self) -> usize {
+        self.ida.inner.lock().pending.remove(&self.id);
+        self.committed = true;
+        self.id
+    }
+
+    /// Abandons the allocation, freeing the ID for reuse.
+    pub fn rollback(self) {
+        // Dropping does the work.
+    }
+}
+
+impl Drop for PreparedId<'_> {
+    fn drop(&mut self) {
+        if self.committed {
             return;
         }
+        let mut inner = self.ida.inner.lock();
+        // If the ID was freed by other means it may since have been handed
+        // to someone else, so only free it if it is still ours.
+        if inner.pending.remove(&self.id) {
+            inner.free(self.id);
+            self.ida.notify_watchers(inner);
+        }
+    }
+}
 
-        // CASE: We are at an internal node
-        // Clear the bit in this node's bitmap,
-        // mark the child as not full or non-existent
-        self.bitmap &= !(1 << bit_index);
-        // Recurse into the appropriate child node
-        // if it exists, clearing the ID there
-        if let Some(child) = self.children.get_mut(&bit_index) {
-            // Recurse into the child node
-            child.free(id, level - 1);
-            // If the child is now empty, remove it to save space
-            if child.bitmap == 0 && child.children.is_empty() {
-                self.children.remove(&bit_index);
+/// A group of IDs reserved together by [`Ida::reserve_scoped`].
+///
+/// Every ID stays allocated while the guard is alive. Dropping the guard
+/// frees all of them again unless [`commit`](Self::commit) was called, so a
+/// setup step that fails part-way leaves nothing behind. IDs that were
+/// freed by other means in the meantime are left alone, as they may since
+/// belong to someone else.
+#[must_use = "dropping a ScopedReservation releases its IDs"]
+#[derive(Debug)]
+pub struct ScopedReservation<'a> {
+    ida: &'a Ida,
+    ids: Vec<usize>,
+    committed: bool,
+}
+
+impl ScopedReservation<'_> {
+    /// Returns the reserved IDs, in the order they were requested.
+    pub fn ids(&self) -> &[usize] {
+        &self.ids
+    }
+
+    /// Keeps every reserved ID allocated after the guard is dropped.
+    pub fn commit(mut self) {
+        let mut inner = self.ida.inner.lock();
+        for id in &self.ids {
+            inner.pending.remove(id);
+        }
+        self.committed = true;
+    }
+}
+
+impl Drop for ScopedReservation<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let mut inner = self.ida.inner.lock();
+        // IDs freed by other means may since have been handed to someone
+        // else, so only free the ones that are still ours.
+        for &id in &self.ids {
+            if inner.pending.remove(&id) {
+                inner.free(id);
             }
         }
+        self.ida.notify_watchers(inner);
     }
+}
 
-    pub fn is_allocated(&self, id: usize, level: usize) -> bool {
-        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+/// An ID allocated by [`Ida::alloc_pinned`] that is freed when dropped.
+///
+/// The token borrows the allocator, so the borrow checker rejects any use of
+/// it after the allocator is gone, and the ID can never be freed into an
+/// allocator other than the one it came from. It dereferences to the ID.
+/// If the ID is freed by other means while the token is alive, dropping the
+/// token leaves it alone.
+///
+/// The token cannot outlive its allocator:
+///
+/// ```compile_fail,E0597
+/// use ida_rs::Ida;
+///
+/// let pinned = {
+///     let ida = Ida::new();
+///     ida.alloc_pinned().unwrap()
+/// };
+/// println!("{}", *pinned);
+/// ```
+#[must_use = "dropping a PinnedId frees its ID"]
+#[derive(Debug)]
+pub struct PinnedId<'ctx> {
+    ida: &'ctx Ida,
+    id: usize,
+}
 
-        if level == 0 {
-            return (self.bitmap >> bit_index) & 1 == 1;
-        }
+impl Deref for PinnedId<'_> {
+    type Target = usize;
 
-        if let Some(child) = self.children.get(&bit_index) {
-            child.is_allocated(id, level - 1)
-        } else {
-            // If the child node doesn't exist, no IDs in that range can be allocated.
-            false
+    fn deref(&self) -> &usize {
+        &self.id
+    }
+}
+
+impl Drop for PinnedId<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.ida.inner.lock();
+        // If the ID was freed by other means it may since have been handed
+        // to someone else, so only free it if it is still ours.
+        if inner.pending.remove(&self.id) {
+            inner.free(self.id);
+            self.ida.notify_watchers(inner);
         }
     }
 }
 
-impl Ida {
-    /// Creates a new, empty ID allocator.
-    ///
-    /// The allocator starts with no IDs allocated. The first call to [`alloc`](Self::alloc)
-    /// will return ID `0`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use ida_rs::Ida;
-    ///
-    /// let ida = Ida::new();
-    /// assert_eq!(ida.alloc(), Some(0));
-    /// ```
-    pub fn new() -> Self {
+/// A single level of an [`IdPath`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathStep {
+    /// The level of the node, where `0` is the leaf level.
+    pub level: usize,
+    /// The bit index the ID maps to within the node at this level.
+    pub index: usize,
+    /// Whether the node at this level exists in the tree.
+    pub present: bool,
+}
+
+/// A journaled allocator operation, replayed by [`Ida::replay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// The ID was allocated.
+    Alloc(usize),
+    /// The ID was freed.
+    Free(usize),
+}
+
+/// The result of freeing an ID with [`Ida::free_report`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeOutcome {
+    /// Whether the ID was allocated before the call.
+    pub was_allocated: bool,
+    /// Whether the allocator held no IDs once the ID was freed.
+    pub now_empty: bool,
+}
+
+/// The cost of a single allocation, as reported by [`Ida::alloc_metered`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AllocMetrics {
+    /// The number of internal tree nodes visited across every search the
+    /// allocation performed. This is the same count that
+    /// [`Ida::alloc_bounded_effort`] limits.
+    pub nodes_visited: usize,
+    /// The number of subtrees that were descended into but turned out to
+    /// hold no usable free ID, forcing the search to back out.
+    pub backtracks: usize,
+}
+
+/// Point-in-time statistics about an [`Ida`], as returned by
+/// [`Ida::snapshot_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdaSnapshot {
+    /// The number of IDs allocated.
+    pub len: usize,
+    /// The highest allocated ID, if any.
+    pub highest_allocated: Option<usize>,
+    /// The lowest free ID the allocator may hand out, if any.
+    pub lowest_free: Option<usize>,
+    /// The number of maximal runs of consecutive allocated IDs. A compact
+    /// allocation has one run; each hole in it adds another.
+    pub allocated_runs: usize,
+    /// The number of nodes in the radix tree.
+    pub node_count: usize,
+}
+
+/// A health summary of an [`Ida`], as returned by [`Ida::health_report`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// The number of IDs allocated.
+    pub len: usize,
+    /// The highest allocated ID, if any. This is the allocator's
+    /// high-water mark.
+    pub peak: Option<usize>,
+    /// The number of nodes in the radix tree.
+    pub node_count: usize,
+    /// The estimated heap memory used by the radix tree, in bytes, as
+    /// reported by [`Ida::memory_usage`].
+    pub memory_usage: usize,
+    /// The length of the longest run of free IDs below the highest
+    /// allocated ID.
+    pub longest_free_run: usize,
+    /// The fragmentation, as reported by [`Ida::fragmentation`].
+    pub fragmentation: f64,
+    /// The largest gaps below the highest allocated ID as `(start, len)`
+    /// pairs, longest first. At most eight are listed, and gaps of equal
+    /// length are listed lowest first.
+    pub largest_gaps: Vec<(usize, usize)>,
+}
+
+/// The lock-protected state of an [`Ida`].
+#[derive(Debug)]
+struct IdaInner {
+    root: IdaNode,
+    // Number of IDs currently allocated, maintained on every alloc/free so
+    // that `len` does not need to walk the tree.
+    len: usize,
+    policy: AllocPolicy,
+    // Freed IDs awaiting reuse, oldest first. Only used by `FifoReuse` and
+    // `LifoReuse`.
+    free_list: VecDeque<usize>,
+    // Where tree searches begin. Zero means a plain search from the root.
+    search_start: usize,
+    // The highest ID that may be allocated. `usize::MAX` when unbounded.
+    max: usize,
+    // Sequence number handed out with the next versioned allocation.
+    next_version: u64,
+    // Callbacks registered with `on_utilization`.
+    watchers: Vec<UtilizationWatcher>,
+    // Tags of IDs allocated with `alloc_tagged`, removed when they are freed.
+    tags: BTreeMap<usize, u16>,
+    // IDs claimed by `alloc_prepare` that are not yet committed.
+    pending: BTreeSet<usize>,
+    // IDs that are never handed out, sorted and deduplicated.
+    blacklist: Vec<usize>,
+    // Index of the 4096-ID block last allocated from, for
+    // `AllocPolicy::WarmSubtree`.
+    warm_block: usize,
+    // Whether `free` leaves emptied nodes in place for `Ida::maintain`.
+    lazy: bool,
+    // Indices of the leaves freed into since the last `Ida::maintain` in
+    // lazy mode.
+    dirty_leaves: BTreeSet<usize>,
+    // Number of allocations a freed ID must wait before reuse, set by
+    // `IdaBuilder::recycle_delay`. Zero disables the delay.
+    recycle_delay: u64,
+    // Allocations made through `alloc` so far, the clock for the delay.
+    alloc_count: u64,
+    // Freed IDs still waiting out the delay, with the `alloc_count` at which
+    // they were freed.
+    cooling: BTreeMap<usize, u64>,
+    // The same entries in the order they were freed. Entries for IDs that
+    // were freed again later are stale and skipped.
+    cooling_queue: VecDeque<(usize, u64)>,
+    // Callbacks registered with `on_empty`.
+    empty_callbacks: Vec<EmptyCallback>,
+    // Set when a free brings `len` to zero, until the callbacks have run.
+    drained: bool,
+    // The window of each `Priority`, indexed by class, set by
+    // `IdaBuilder::class_windows`. `None` lets every class use the whole
+    // space.
+    class_windows: Option<[Range<usize>; 3]>,
+    // Indices of the 4096-ID blocks `alloc` has handed out IDs from since
+    // construction or the last `Ida::reset_profiling`.
+    touched_blocks: BTreeSet<usize>,
+    // The stride set by `IdaBuilder::stride`. Anything below two leaves the
+    // policy in charge.
+    stride: usize,
+    // The lowest ID that may be handed out, set by `IdaBuilder::span`.
+    min: usize,
+}
+
+/// A utilization threshold registered with [`Ida::on_utilization`].
+struct UtilizationWatcher {
+    threshold: f64,
+    // Whether utilization was at or above `threshold` when last checked, so
+    // that the callback only fires on an upward crossing.
+    above: bool,
+    callback: Arc<dyn Fn(f64) + Send + Sync>,
+}
+
+impl Debug for UtilizationWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtilizationWatcher")
+            .field("threshold", &self.threshold)
+            .field("above", &self.above)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A callback registered with [`Ida::on_empty`].
+#[derive(Clone)]
+struct EmptyCallback(Arc<dyn Fn() + Send + Sync>);
+
+impl Debug for EmptyCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmptyCallback").finish_non_exhaustive()
+    }
+}
+
+impl IdaInner {
+    fn new(policy: AllocPolicy) -> Self {
         Self {
-            root: Mutex::new(IdaNode::new()),
+            root: IdaNode::root(),
+            len: 0,
+            policy,
+            free_list: VecDeque::new(),
+            search_start: 0,
+            max: usize::MAX,
+            next_version: 0,
+            watchers: Vec::new(),
+            tags: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            blacklist: Vec::new(),
+            warm_block: 0,
+            lazy: false,
+            dirty_leaves: BTreeSet::new(),
+            recycle_delay: 0,
+            alloc_count: 0,
+            cooling: BTreeMap::new(),
+            cooling_queue: VecDeque::new(),
+            empty_callbacks: Vec::new(),
+            drained: false,
+            class_windows: None,
+            touched_blocks: BTreeSet::new(),
+            stride: 0,
+            min: 0,
         }
     }
 
-    /// Allocates and returns the next available ID.
-    ///
-    /// This method always returns the lowest available ID. If an ID has been freed,
-    /// it will be reused before allocating higher IDs.
-    ///
-    /// # Returns
-    ///
-    /// - `Some(id)` - The allocated ID (a `usize` value)
-    /// - `None` - If the allocator has exhausted the available ID space
-    ///
-    /// # Thread Safety
+    /// Returns whether `id` can never be handed out, because it lies below
+    /// the span or is blacklisted. IDs above the bound are checked
+    /// separately.
+    fn is_excluded(&self, id: usize) -> bool {
+        id < self.min || (!self.blacklist.is_empty() && self.blacklist.binary_search(&id).is_ok())
+    }
+
+    /// Returns the highest ID in `start..=end` that can never be handed
+    /// out, as [`is_excluded`](Self::is_excluded) decides.
+    fn last_excluded_in(&self, start: usize, end: usize) -> Option<usize> {
+        let covered = self.blacklist.partition_point(|&id| id <= end);
+        let blacklisted = self.blacklist[..covered].last().copied();
+        let below_min = (start < self.min).then(|| end.min(self.min - 1));
+        blacklisted.filter(|&id| id >= start).max(below_min)
+    }
+
+    /// Returns whether searches must pass over `id` even if it is free:
+    /// because it is excluded, or still waiting out the recycle delay.
+    fn is_withheld(&self, id: usize) -> bool {
+        self.is_excluded(id) || (!self.cooling.is_empty() && self.cooling.contains_key(&id))
+    }
+
+    /// Starts the recycle delay for the just-freed `id`, if there is one.
+    fn cool(&mut self, id: usize) {
+        if self.recycle_delay > 0 {
+            self.cooling.insert(id, self.alloc_count);
+            self.cooling_queue.push_back((id, self.alloc_count));
+        }
+    }
+
+    /// Makes the freed IDs that have waited out the recycle delay
+    /// available again.
+    fn release_cooled(&mut self) {
+        while let Some(&(id, freed_at)) = self.cooling_queue.front() {
+            if self.alloc_count - freed_at < self.recycle_delay {
+                break;
+            }
+            self.cooling_queue.pop_front();
+            if self.cooling.get(&id) == Some(&freed_at) {
+                self.cooling.remove(&id);
+            }
+        }
+    }
+
+    /// Allocates the longest-waiting freed ID before its delay is up.
     ///
-    /// This method is thread-safe and can be called concurrently from multiple threads.
-    /// Each call is guaranteed to return a unique ID.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use ida_rs::Ida;
-    ///
-    /// let ida = Ida::new();
-    ///
-    /// // Allocate IDs sequentially
-    /// let id1 = ida.alloc().unwrap();
-    /// let id2 = ida.alloc().unwrap();
-    /// assert_eq!(id1, 0);
-    /// assert_eq!(id2, 1);
-    ///
-    /// // Free an ID and reallocate
-    /// ida.free(id1);
-    /// let id3 = ida.alloc().unwrap();
-    /// assert_eq!(id3, 0); // Reuses the freed ID
-    /// ```
-    pub fn alloc(&self) -> Option<usize> {
-        let mut root = self.root.lock();
-        root.alloc(IDA_MAX_LEVELS - 1)
+    /// Used only by [`Ida::alloc_or_recycle`], when nothing else is free.
+    fn alloc_coolest(&mut self) -> Option<usize> {
+        while let Some((id, freed_at)) = self.cooling_queue.pop_front() {
+            if self.cooling.get(&id) != Some(&freed_at) {
+                continue;
+            }
+            self.cooling.remove(&id);
+            if id <= self.max && self.claim(id) {
+                return Some(id);
+            }
+        }
+        None
     }
 
-    /// Frees a previously allocated ID, making it available for reuse.
-    ///
-    /// Once freed, the ID becomes available for future allocations. The next call
-    /// to [`alloc`](Self::alloc) will prefer reusing freed IDs before allocating
-    /// new ones.
-    ///
-    /// # Parameters
-    ///
-    /// - `id` - The ID to free. Must be a value that was previously returned by
-    ///   [`alloc`](Self::alloc).
-    ///
-    /// # Behavior
-    ///
-    /// - Freeing an already-free ID is safe but has no effect
-    /// - Freeing an ID that was never allocated is safe but has no effect
-    /// - After freeing, the internal tree structure may be compacted to save memory
-    ///
-    /// # Thread Safety
-    ///
-    /// This method is thread-safe and can be called concurrently from multiple threads.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use ida_rs::Ida;
-    ///
-    /// let ida = Ida::new();
-    /// let id = ida.alloc().unwrap();
-    ///
-    /// // Use the ID...
-    ///
-    /// // Free it when done
-    /// ida.free(id);
-    ///
-    /// // The ID can now be reallocated
-    /// let reused_id = ida.alloc().unwrap();
-    /// assert_eq!(id, reused_id);
-    /// ```
-    pub fn free(&self, id: usize) {
-        let mut root = self.root.lock();
-        root.free(id, IDA_MAX_LEVELS - 1);
+    /// Returns the lowest free ID in `lo..=hi` that is not blacklisted.
+    fn find_free(&self, lo: usize, hi: usize) -> Option<usize> {
+        self.find_free_masked(lo, hi, u64::MAX)
     }
 
-    /// Checks if a given ID is currently allocated.
-    ///
-    /// This method queries whether a specific ID has been allocated and not yet freed.
-    /// It's useful for debugging, validation, or tracking ID states.
-    ///
-    /// # Parameters
-    ///
-    /// - `id` - The ID to check
-    ///
-    /// # Returns
-    ///
-    /// - `true` - If the ID is currently allocated
-    /// - `false` - If the ID is free or has never been allocated
-    ///
-    /// # Thread Safety
-    ///
-    /// This method is thread-safe and provides a consistent view at the time of the call.
-    /// Note that in concurrent scenarios, the status may change immediately after this
-    /// method returns.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use ida_rs::Ida;
-    ///
-    /// let ida = Ida::new();
+    /// Returns the lowest free ID in `lo..=hi` that is not blacklisted and
+    /// leaves `residue` when divided by `m`.
     ///
-    /// // Initially, no IDs are allocated
-    /// assert!(!ida.is_allocated(0));
-    /// assert!(!ida.is_allocated(100));
+    /// The search jumps from one free ID to the next candidate at or after
+    /// it, so fully allocated subtrees are skipped. The cost of the searches
+    /// is recorded in `metrics`, if given.
+    fn find_free_congruent(
+        &self,
+        residue: usize,
+        m: usize,
+        lo: usize,
+        hi: usize,
+        mut metrics: Option<&mut AllocMetrics>,
+    ) -> Option<usize> {
+        let hi = hi.min(self.max);
+        let mut candidate = lo;
+        loop {
+            if candidate > hi {
+                return None;
+            }
+            let free = self.find_free_metered(candidate, hi, metrics.as_deref_mut())?;
+            let r = free % m;
+            if r == residue {
+                return Some(free);
+            }
+            let gap = if r < residue {
+                residue - r
+            } else {
+                m - r + residue
+            };
+            candidate = free.checked_add(gap)?;
+        }
+    }
+
+    /// Returns the first ID of the lowest run of `n` free IDs starting at or
+    /// after `lo`, none of them blacklisted and all within the bound.
+    fn find_free_run(&self, mut lo: usize, n: usize) -> Option<usize> {
+        loop {
+            let start = self.find_free(lo, self.max)?;
+            let end = start.checked_add(n - 1).filter(|&end| end <= self.max)?;
+            let blocked = self
+                .root
+                .find_allocated(IDA_MAX_LEVELS - 1, 0, start, end)
+                .or_else(|| self.last_excluded_in(start, end));
+            match blocked {
+                None => return Some(start),
+                Some(id) => lo = id.checked_add(1)?,
+            }
+        }
+    }
+
+    /// Returns the lowest free ID in `range` that is not blacklisted and is
+    /// within the allocator's bound.
+    fn find_free_within(&self, range: &impl RangeBounds<usize>) -> Option<usize> {
+        let (lo, hi) = inclusive_bounds(range)?;
+        if lo > self.max {
+            return None;
+        }
+        self.find_free(lo, hi.min(self.max))
+    }
+
+    /// Like [`find_free`](Self::find_free), but only considers IDs whose
+    /// position within their leaf is set in `leaf_mask`.
+    fn find_free_masked(&self, lo: usize, hi: usize, leaf_mask: u64) -> Option<usize> {
+        let mut lo = lo.max(self.min);
+        loop {
+            if lo > hi {
+                return None;
+            }
+            let id = self
+                .root
+                .find_free_masked(IDA_MAX_LEVELS - 1, 0, lo, hi, leaf_mask)?;
+            if !self.is_withheld(id) {
+                return Some(id);
+            }
+            lo = id.checked_add(1)?;
+        }
+    }
+
+    /// Returns the fragmentation the tree would have with `len` IDs
+    /// allocated, the highest of them being `highest`.
+    fn fragmentation_at(&self, highest: Option<usize>, len: usize) -> f64 {
+        let Some(highest) = highest else {
+            return 0.0;
+        };
+        let span = (highest - self.min) as f64 + 1.0;
+        (span - len as f64) / span
+    }
+
+    /// Returns the fraction of the allocator's capacity currently allocated.
+    fn utilization(&self) -> f64 {
+        self.len as f64 / (self.max as f64 + 1.0 - self.min as f64)
+    }
+
+    /// Updates every watcher against the current utilization, returning the
+    /// callbacks of those that crossed their threshold upward.
+    fn crossed_watchers(&mut self) -> Vec<Arc<dyn Fn(f64) + Send + Sync>> {
+        if self.watchers.is_empty() {
+            return Vec::new();
+        }
+        let utilization = self.utilization();
+        let mut crossed = Vec::new();
+        for watcher in &mut self.watchers {
+            let above = utilization >= watcher.threshold;
+            if above && !watcher.above {
+                crossed.push(watcher.callback.clone());
+            }
+            watcher.above = above;
+        }
+        crossed
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        self.alloc_metered(None)
+    }
+
+    /// Like [`alloc`](Self::alloc), but records the cost of the tree
+    /// searches it performs in `metrics`, if given.
     ///
-    /// // Allocate an ID
-    /// let id = ida.alloc().unwrap();
-    /// assert_eq!(id, 0);
+    /// The ID is one that is not waiting out the recycle delay, picked
+    /// following the policy and search hint.
+    fn alloc_metered(&mut self, mut metrics: Option<&mut AllocMetrics>) -> Option<usize> {
+        if self.stride > 1 {
+            let id = self.find_free_strided(metrics)?;
+            self.claim(id);
+            return Some(id);
+        }
+        if let Some(id) = self.alloc_queued() {
+            return Some(id);
+        }
+        if let Some((lo, hi)) = self.warm_range()
+            && let Some(id) = self.find_free_metered(lo, hi, metrics.as_deref_mut())
+        {
+            self.claim(id);
+            return Some(id);
+        }
+
+        let hinted = match self.search_start {
+            0 => None,
+            start if start > self.max => None,
+            start => self.find_free_metered(start, self.max, metrics.as_deref_mut()),
+        };
+        let id = match (hinted, metrics) {
+            (Some(id), _) => {
+                self.claim(id);
+                id
+            }
+            // Nothing is free at or above the hint, so fall back to a full
+            // search from the root to keep finding lower freed IDs.
+            (None, None) => self.alloc_lowest()?,
+            (None, Some(metrics)) => {
+                let id = self.find_free_metered(0, self.max, Some(metrics))?;
+                self.claim(id);
+                id
+            }
+        };
+        self.warm_block = id >> WARM_BLOCK_SHIFT;
+        Some(id)
+    }
+
+    /// Marks the free `id` as allocated, returning `false` if it already
+    /// was.
     ///
-    /// // Check allocation status
-    /// assert!(ida.is_allocated(0));
-    /// assert!(!ida.is_allocated(1));
+    /// Every allocation goes through here, or through
+    /// [`record_alloc`](Self::record_alloc) directly, so that the recycle
+    /// delay and the profiling counters see it. Restoring a saved state, as
+    /// `apply_bitset` or `reset_to` do, is not allocating.
+    fn claim(&mut self, id: usize) -> bool {
+        if !self.root.set(id, IDA_MAX_LEVELS - 1) {
+            return false;
+        }
+        self.record_alloc(id);
+        true
+    }
+
+    /// Like [`claim`](Self::claim), for the free IDs `lo..=hi`.
+    fn claim_range(&mut self, lo: usize, hi: usize) {
+        let claimed = self.root.set_range(IDA_MAX_LEVELS - 1, 0, lo, hi);
+        self.len += claimed;
+        self.alloc_count += claimed as u64;
+        self.touched_blocks
+            .extend((lo >> WARM_BLOCK_SHIFT)..=(hi >> WARM_BLOCK_SHIFT));
+        self.release_cooled();
+    }
+
+    /// Accounts for `id`, just set in the tree: counts it in `len`,
+    /// advances the recycle clock, releasing the IDs that have now waited
+    /// out their delay, and records its block for profiling.
+    fn record_alloc(&mut self, id: usize) {
+        self.len += 1;
+        self.alloc_count += 1;
+        self.touched_blocks.insert(id >> WARM_BLOCK_SHIFT);
+        self.release_cooled();
+    }
+
+    /// Returns the ID [`alloc`](Self::alloc) would hand out,
+    /// without allocating it.
+    fn next_fresh(&self) -> Option<usize> {
+        if self.stride > 1 {
+            return self.find_free_strided(None);
+        }
+        if let Some(index) = self.next_queued() {
+            return Some(self.free_list[index]);
+        }
+        if let Some((lo, hi)) = self.warm_range()
+            && let Some(id) = self.find_free(lo, hi)
+        {
+            return Some(id);
+        }
+        match self.search_start {
+            0 => None,
+            start if start > self.max => None,
+            start => self.find_free(start, self.max),
+        }
+        .or_else(|| self.find_free(0, self.max))
+    }
+
+    /// Returns the next free ID in the configured stride's walk.
     ///
-    /// // Free the ID
-    /// ida.free(0);
-    /// assert!(!ida.is_allocated(0));
-    /// ```
-    pub fn is_allocated(&self, id: usize) -> bool {
-        let root = self.root.lock();
-        root.is_allocated(id, IDA_MAX_LEVELS - 1)
+    /// The ID space is cut into windows of `STRIDE_PASS` strides, and the
+    /// walk covers the window holding the lowest free ID: its IDs in steps
+    /// of the stride from the window's start, then from one past it, and so
+    /// on until every remainder is done.
+    fn find_free_strided(&self, mut metrics: Option<&mut AllocMetrics>) -> Option<usize> {
+        let stride = self.stride;
+        let free = self.find_free_metered(0, self.max, metrics.as_deref_mut())?;
+        let window = stride.saturating_mul(STRIDE_PASS);
+        let lo = free - free % window;
+        let hi = lo.saturating_add(window - 1).min(self.max);
+        (0..stride)
+            .take_while(|&offset| offset <= hi - lo)
+            .find_map(|offset| {
+                let residue = (lo + offset) % stride;
+                self.find_free_congruent(residue, stride, lo, hi, metrics.as_deref_mut())
+            })
+    }
+
+    /// Returns the bounds of the block favoured by
+    /// [`AllocPolicy::WarmSubtree`], or `None` under any other policy.
+    fn warm_range(&self) -> Option<(usize, usize)> {
+        if self.policy != AllocPolicy::WarmSubtree {
+            return None;
+        }
+        let lo = self.warm_block << WARM_BLOCK_SHIFT;
+        let hi = (lo | ((1 << WARM_BLOCK_SHIFT) - 1)).min(self.max);
+        Some((lo, hi))
+    }
+
+    /// Allocates the oldest freed ID still pending under
+    /// [`AllocPolicy::FifoReuse`], or the newest under
+    /// [`AllocPolicy::LifoReuse`].
+    fn alloc_queued(&mut self) -> Option<usize> {
+        let index = self.next_queued()?;
+        let id = self.free_list[index];
+        // The entries passed over on the way had been re-allocated by other
+        // means, so they go too.
+        if let AllocPolicy::LifoReuse(_) = self.policy {
+            self.free_list.truncate(index);
+        } else {
+            self.free_list.drain(..=index);
+        }
+        self.claim(id);
+        Some(id)
+    }
+
+    /// Returns the position in the queue of the ID
+    /// [`alloc_queued`](Self::alloc_queued) would hand out.
+    fn next_queued(&self) -> Option<usize> {
+        let newest_first = match self.policy {
+            AllocPolicy::FifoReuse => false,
+            AllocPolicy::LifoReuse(_) => true,
+            _ => return None,
+        };
+        // Entries may have been re-allocated by other means since they were
+        // queued, so skip any that are no longer free. IDs are queued in the
+        // order they were freed, so once the oldest is still waiting out the
+        // recycle delay, all the others are too. The newest is the last to
+        // finish it, so while it is still waiting the tree search takes over.
+        let len = self.free_list.len();
+        (0..len)
+            .map(|i| if newest_first { len - 1 - i } else { i })
+            .take_while(|&index| !self.is_withheld(self.free_list[index]))
+            .find(|&index| {
+                !self
+                    .root
+                    .is_allocated(self.free_list[index], IDA_MAX_LEVELS - 1)
+            })
+    }
+
+    /// Like [`find_free`](Self::find_free), but records the internal nodes
+    /// visited and the subtrees backed out of in `metrics`, if given.
+    fn find_free_metered(
+        &self,
+        lo: usize,
+        hi: usize,
+        metrics: Option<&mut AllocMetrics>,
+    ) -> Option<usize> {
+        let Some(metrics) = metrics else {
+            return self.find_free(lo, hi);
+        };
+        let mut lo = lo.max(self.min);
+        loop {
+            if lo > hi {
+                return None;
+            }
+            let mut budget = usize::MAX;
+            let found = self.root.find_free_budgeted(
+                IDA_MAX_LEVELS - 1,
+                0,
+                lo,
+                hi,
+                &mut budget,
+                &mut metrics.backtracks,
+            );
+            metrics.nodes_visited += usize::MAX - budget;
+            // An unlimited budget cannot run out within a single search.
+            let id = found.ok().flatten()?;
+            if !self.is_withheld(id) {
+                return Some(id);
+            }
+            lo = id.checked_add(1)?;
+        }
+    }
+
+    /// Allocates the lowest free ID within the bound.
+    fn alloc_lowest(&mut self) -> Option<usize> {
+        if self.max == usize::MAX
+            && self.min == 0
+            && self.blacklist.is_empty()
+            && self.cooling.is_empty()
+        {
+            let id = self.root.alloc(IDA_MAX_LEVELS - 1)?;
+            self.record_alloc(id);
+            return Some(id);
+        }
+        let id = self.find_free(0, self.max)?;
+        self.claim(id);
+        Some(id)
+    }
+
+    /// Frees every allocated ID in `lo..=hi`, returning how many there were.
+    fn free_range(&mut self, lo: usize, hi: usize) -> usize {
+        let window = self.queue_window();
+        let delayed = self.recycle_delay > 0;
+        let free_list = &mut self.free_list;
+        let mut to_cool = Vec::new();
+        let mut freed = 0;
+        self.root
+            .free_range(IDA_MAX_LEVELS - 1, 0, lo, hi, &mut |leaf_base, mut bits| {
+                freed += bits.count_ones() as usize;
+                while (window.is_some() || delayed) && bits != 0 {
+                    let id = leaf_base | bits.trailing_zeros() as usize;
+                    if let Some(window) = window {
+                        push_bounded(free_list, id, window);
+                    }
+                    if delayed {
+                        to_cool.push(id);
+                    }
+                    bits &= bits - 1;
+                }
+            });
+        for id in to_cool {
+            self.cool(id);
+        }
+        self.len -= freed;
+        self.drained |= freed > 0 && self.len == 0;
+        if freed > 0 && !self.tags.is_empty() {
+            let tagged: Vec<usize> = self.tags.range(lo..=hi).map(|(&id, _)| id).collect();
+            for id in tagged {
+                self.tags.remove(&id);
+            }
+        }
+        if freed > 0 && !self.pending.is_empty() {
+            let pending: Vec<usize> = self.pending.range(lo..=hi).copied().collect();
+            for id in pending {
+                self.pending.remove(&id);
+            }
+        }
+        freed
+    }
+
+    /// Packs the allocation state of `range` into 64-bit words.
+    fn bitset_view(&self, range: Range<usize>) -> Vec<u64> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+        let len = range.end - range.start;
+        let mut words = vec![0u64; len.div_ceil(IDA_BITMAP_BITS)];
+
+        self.root.for_each_leaf_in(
+            IDA_MAX_LEVELS - 1,
+            0,
+            range.start,
+            range.end - 1,
+            &mut |leaf_base, bits| {
+                if bits == 0 {
+                    return;
+                }
+                if leaf_base < range.start {
+                    // Only the first leaf can start before the range.
+                    words[0] |= bits >> (range.start - leaf_base);
+                    return;
+                }
+                let offset = leaf_base - range.start;
+                let (word, shift) = (offset / IDA_BITMAP_BITS, offset % IDA_BITMAP_BITS);
+                words[word] |= bits << shift;
+                if shift > 0 && word + 1 < words.len() {
+                    words[word + 1] |= bits >> (IDA_BITMAP_BITS - shift);
+                }
+            },
+        );
+        words
+    }
+
+    /// Allocates every ID set in `words`, optionally freeing the clear ones.
+    fn apply_bitset(&mut self, start: usize, words: &[u64], overwrite: bool) {
+        if words.is_empty() || start > self.max {
+            return;
+        }
+        if overwrite {
+            let bits = words.len().saturating_mul(IDA_BITMAP_BITS);
+            let end = start.saturating_add(bits - 1).min(self.max);
+            self.free_range(start, end);
+        }
+
+        for (k, &word) in words.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                let offset = k * IDA_BITMAP_BITS + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let Some(id) = start.checked_add(offset).filter(|&id| id <= self.max) else {
+                    // Every remaining bit is even further out of range.
+                    return;
+                };
+                if !self.is_excluded(id) && self.root.set(id, IDA_MAX_LEVELS - 1) {
+                    self.len += 1;
+                }
+            }
+        }
+    }
+
+    fn free(&mut self, id: usize) -> bool {
+        if !self.root.free(id, IDA_MAX_LEVELS - 1, !self.lazy) {
+            return false;
+        }
+        if self.lazy {
+            self.dirty_leaves.insert(id >> IDA_SHIFT);
+        }
+        self.len -= 1;
+        self.drained |= self.len == 0;
+        self.queue_freed(id);
+        self.cool(id);
+        self.tags.remove(&id);
+        self.pending.remove(&id);
+        true
+    }
+
+    /// Queues a freed ID for reuse if the policy calls for it.
+    fn queue_freed(&mut self, id: usize) {
+        if let Some(window) = self.queue_window() {
+            push_bounded(&mut self.free_list, id, window);
+        }
+    }
+
+    /// Returns how many freed IDs the policy keeps queued for reuse, or
+    /// `None` if it does not queue them at all.
+    fn queue_window(&self) -> Option<usize> {
+        match self.policy {
+            AllocPolicy::FifoReuse => Some(usize::MAX),
+            AllocPolicy::LifoReuse(window) => Some(window),
+            _ => None,
+        }
+    }
+
+    /// Frees the IDs at the positions set in `mask` of the leaf starting at
+    /// `leaf_base`, returning how many were allocated.
+    fn free_leaf_bits(&mut self, leaf_base: usize, mask: u64) -> usize {
+        let cleared = self
+            .root
+            .free_leaf_bits(leaf_base, mask, IDA_MAX_LEVELS - 1);
+        let freed = cleared.count_ones() as usize;
+        self.len -= freed;
+        self.drained |= freed > 0 && self.len == 0;
+        let mut bits = cleared;
+        while bits != 0 {
+            let id = leaf_base | bits.trailing_zeros() as usize;
+            bits &= bits - 1;
+            self.queue_freed(id);
+            self.cool(id);
+            self.tags.remove(&id);
+            self.pending.remove(&id);
+        }
+        freed
+    }
+}
+
+/// The allocator every tree node and child map is allocated from.
+///
+/// Without the `allocator_api` feature this is a placeholder for the global
+/// allocator. With it, an [`Ida`] built with [`IdaBuilder::allocator`]
+/// carries a shared handle to its allocator and hands a clone to each node it
+/// creates.
+#[cfg(feature = "allocator_api")]
+#[derive(Clone, Default)]
+enum NodeAlloc {
+    #[default]
+    Global,
+    Custom(Arc<dyn Allocator + Send + Sync>),
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl Allocator for NodeAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        match self {
+            Self::Global => Global.allocate(layout),
+            Self::Custom(alloc) => alloc.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // SAFETY: Clones share the same underlying allocator, so whichever
+        // clone allocated `ptr` was backed by this one.
+        unsafe {
+            match self {
+                Self::Global => Global.deallocate(ptr, layout),
+                Self::Custom(alloc) => alloc.deallocate(ptr, layout),
+            }
+        }
+    }
+}
+
+// Where a node's memory comes from has no bearing on the IDs it holds.
+#[cfg(feature = "allocator_api")]
+impl PartialEq for NodeAlloc {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl Eq for NodeAlloc {}
+
+#[cfg(feature = "allocator_api")]
+impl Debug for NodeAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Global => f.write_str("Global"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+#[cfg(not(feature = "allocator_api"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct NodeAlloc;
+
+impl NodeAlloc {
+    /// Creates an empty child node allocated from this allocator.
+    fn new_child(&self) -> Child {
+        #[cfg(feature = "allocator_api")]
+        return Arc::new_in(IdaNode::new_in(self.clone()), self.clone());
+        #[cfg(not(feature = "allocator_api"))]
+        return Arc::new(IdaNode::new_in(self.clone()));
     }
 }
 
-impl Default for Ida {
-    /// Creates a new ID allocator using the default configuration.
-    ///
-    /// This is equivalent to calling [`Ida::new()`](Self::new).
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use ida_rs::Ida;
-    ///
-    /// let ida = Ida::default();
-    /// assert_eq!(ida.alloc(), Some(0));
-    /// ```
-    fn default() -> Self {
-        Self::new()
+#[cfg(feature = "allocator_api")]
+type Child = Arc<IdaNode, NodeAlloc>;
+#[cfg(not(feature = "allocator_api"))]
+type Child = Arc<IdaNode>;
+
+#[cfg(feature = "allocator_api")]
+type Children = BTreeMap<usize, Child, NodeAlloc>;
+#[cfg(not(feature = "allocator_api"))]
+type Children = BTreeMap<usize, Child>;
+
+// Children are reference counted so that forks made by `Ida::fork_cow` can
+// share subtrees. Mutation goes through `Arc::make_mut`, which copies a node
+// only while it is shared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IdaNode {
+    bitmap: u64,
+    children: Children,
+    alloc: NodeAlloc,
+}
+
+impl IdaNode {
+    /// Creates an empty node whose children are allocated from `alloc`.
+    #[cfg(feature = "allocator_api")]
+    fn new_in(alloc: NodeAlloc) -> Self {
+        Self {
+            bitmap: 0,
+            children: BTreeMap::new_in(alloc.clone()),
+            alloc,
+        }
+    }
+
+    #[cfg(not(feature = "allocator_api"))]
+    fn new_in(alloc: NodeAlloc) -> Self {
+        Self {
+            bitmap: 0,
+            children: BTreeMap::new(),
+            alloc,
+        }
+    }
+
+    /// Creates an empty root node.
+    ///
+    /// Root children beyond `IDA_ROOT_CHILDREN` would represent IDs that do
+    /// not fit in a `usize`, so they are permanently marked as full. This
+    /// makes the root report itself full once every representable ID is
+    /// allocated, instead of handing out IDs that wrap around.
+    // `NodeAlloc` is a unit struct only without the `allocator_api` feature.
+    #[allow(clippy::default_constructed_unit_structs)]
+    pub fn root() -> Self {
+        Self::root_in(NodeAlloc::default())
+    }
+
+    /// Creates an empty root node whose subtrees are allocated from `alloc`.
+    fn root_in(alloc: NodeAlloc) -> Self {
+        let mut root = Self::new_in(alloc);
+        if IDA_ROOT_CHILDREN < IDA_BITMAP_BITS {
+            root.bitmap = !bit_range_mask(0, IDA_ROOT_CHILDREN - 1);
+        }
+        root
+    }
+
+    pub fn alloc(&mut self, level: usize) -> Option<usize> {
+        // CASE: We are at a leaf node
+        // The bitmap here represents individual IDs
+        if level == 0 {
+            // All ones means no free IDs
+            if self.bitmap == u64::MAX {
+                return None;
+            }
+            // Using trailing_ones to find the first zero bit,
+            // which is an unallocated ID
+            let bit = self.bitmap.trailing_ones() as usize;
+            self.bitmap |= 1 << bit;
+            return Some(bit);
+        }
+
+        // CASE: We are at an internal node
+        // The bitmap here represents child nodes. We iterate through the unset bits
+        // (0s), which correspond to children that are not full.
+        while self.bitmap != u64::MAX {
+            let i = self.bitmap.trailing_ones() as usize; // Find index of first 0 bit.
+
+            // The child node is either unallocated or not fully allocated, get it.
+            let child = Arc::make_mut(
+                self.children
+                    .entry(i)
+                    .or_insert_with(|| self.alloc.new_child()),
+            );
+
+            // Recursively allocate in the child node.
+            if let Some(id_in_child) = child.alloc(level - 1) {
+                // After the allocation, check if the child is now fully allocated.
+                // If so, set the corresponding bit in this node's bitmap.
+                if child.bitmap == u64::MAX {
+                    self.bitmap |= 1 << i;
+                }
+                // Compute the full ID by combining the index and the child's ID.
+                let id = (i << (level * IDA_SHIFT)) | id_in_child;
+                return Some(id);
+            } else {
+                // The child was marked as having space in our bitmap, but the recursive
+                // alloc returned None, implying it's actually full. We fix this
+                // inconsistency here and continue the search in the next available child.
+                self.bitmap |= 1 << i;
+            }
+        }
+
+        None
+    }
+
+    /// Marks `id` as allocated in this subtree, creating nodes as needed.
+    ///
+    /// Returns `true` if the ID was previously free.
+    pub fn set(&mut self, id: usize, level: usize) -> bool {
+        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let was_set = (self.bitmap >> bit_index) & 1 == 1;
+            self.bitmap |= 1 << bit_index;
+            return !was_set;
+        }
+
+        let child = Arc::make_mut(
+            self.children
+                .entry(bit_index)
+                .or_insert_with(|| self.alloc.new_child()),
+        );
+        let newly_set = child.set(id, level - 1);
+        // Keep the full-bit in sync if this filled up the child.
+        if child.bitmap == u64::MAX {
+            self.bitmap |= 1 << bit_index;
+        }
+        newly_set
+    }
+
+    /// Clears `id` in this subtree, returning whether it was allocated.
+    ///
+    /// Children left empty are removed only if `prune` is set.
+    pub fn free(&mut self, id: usize, level: usize, prune: bool) -> bool {
+        // Determine which bit index to clear at this level
+        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+
+        // CASE: We are at a leaf node
+        if level == 0 {
+            let was_set = (self.bitmap >> bit_index) & 1 == 1;
+            // Simply clear the bit corresponding to the ID
+            self.bitmap &= !(1 << bit_index);
+            return was_set;
+        }
+
+        // CASE: We are at an internal node
+        // Clear the bit in this node's bitmap,
+        // mark the child as not full or non-existent
+        self.bitmap &= !(1 << bit_index);
+        // Recurse into the appropriate child node
+        // if it exists, clearing the ID there
+        if let Some(child) = self.children.get_mut(&bit_index) {
+            // Recurse into the child node
+            let child = Arc::make_mut(child);
+            let was_set = child.free(id, level - 1, prune);
+            // If the child is now empty, remove it to save space
+            if prune && child.bitmap == 0 && child.children.is_empty() {
+                self.children.remove(&bit_index);
+            }
+            was_set
+        } else {
+            false
+        }
+    }
+
+    /// Clears the bits in `mask` of the leaf starting at `leaf_base` in one
+    /// descent, pruning emptied children, and returns the bits that were
+    /// actually set.
+    pub fn free_leaf_bits(&mut self, leaf_base: usize, mask: u64, level: usize) -> u64 {
+        if level == 0 {
+            let cleared = self.bitmap & mask;
+            self.bitmap &= !mask;
+            return cleared;
+        }
+
+        let bit_index = (leaf_base >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+        self.bitmap &= !(1 << bit_index);
+        let Some(child) = self.children.get_mut(&bit_index) else {
+            return 0;
+        };
+        let child = Arc::make_mut(child);
+        let cleared = child.free_leaf_bits(leaf_base, mask, level - 1);
+        if child.bitmap == 0 && child.children.is_empty() {
+            self.children.remove(&bit_index);
+        }
+        cleared
+    }
+
+    /// Clears every allocated ID in `lo..=hi`, pruning emptied children.
+    ///
+    /// `base` is the first ID covered by this subtree, and `lo` must not be
+    /// below it. `f(leaf_base, bits)` is called with the bits cleared in each
+    /// affected leaf.
+    pub fn free_range(
+        &mut self,
+        level: usize,
+        base: usize,
+        lo: usize,
+        hi: usize,
+        f: &mut impl FnMut(usize, u64),
+    ) {
+        let shift = level * IDA_SHIFT;
+        let first = (lo - base) >> shift;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let cleared = self.bitmap & bit_range_mask(first, last);
+            if cleared != 0 {
+                self.bitmap &= !cleared;
+                f(base, cleared);
+            }
+            return;
+        }
+
+        let mut emptied = Vec::new();
+        for (&i, child) in self.children.range_mut(first..=last) {
+            let child_base = base + (i << shift);
+            let child = Arc::make_mut(child);
+            child.free_range(level - 1, child_base, lo.max(child_base), hi, f);
+            if child.bitmap == 0 && child.children.is_empty() {
+                emptied.push(i);
+            }
+        }
+        for i in emptied {
+            self.children.remove(&i);
+        }
+
+        // Only children that are still present and full keep their full-bit.
+        for i in first..=last {
+            let full = self.children.get(&i).is_some_and(|c| c.bitmap == u64::MAX);
+            if !full {
+                self.bitmap &= !(1 << i);
+            }
+        }
+    }
+
+    /// Marks every ID in `lo..=hi` as allocated a leaf at a time, returning
+    /// how many were newly set.
+    ///
+    /// `base` is the first ID covered by this subtree, and `lo` must not be
+    /// below it.
+    pub fn set_range(&mut self, level: usize, base: usize, lo: usize, hi: usize) -> usize {
+        let shift = level * IDA_SHIFT;
+        let first = (lo - base) >> shift;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let mask = bit_range_mask(first, last);
+            let newly_set = (mask & !self.bitmap).count_ones() as usize;
+            self.bitmap |= mask;
+            return newly_set;
+        }
+
+        let mut newly_set = 0;
+        for i in first..=last {
+            let child_base = base + (i << shift);
+            let child = Arc::make_mut(
+                self.children
+                    .entry(i)
+                    .or_insert_with(|| self.alloc.new_child()),
+            );
+            newly_set += child.set_range(level - 1, child_base, lo.max(child_base), hi);
+            if child.bitmap == u64::MAX {
+                self.bitmap |= 1 << i;
+            }
+        }
+        newly_set
+    }
+
+    /// Returns whether every ID from the start of this subtree up to and
+    /// including `max` is allocated, following only the path to `max`.
+    pub fn is_full_through(&self, level: usize, max: usize) -> bool {
+        let bit_index = (max >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+        let through = bit_range_mask(0, bit_index);
+
+        if level == 0 {
+            return self.bitmap & through == through;
+        }
+
+        // Every child before the one containing `max` must be full.
+        let before = through & !(1 << bit_index);
+        if self.bitmap & before != before {
+            return false;
+        }
+        if (self.bitmap >> bit_index) & 1 == 1 {
+            return true;
+        }
+        self.children
+            .get(&bit_index)
+            .is_some_and(|child| child.is_full_through(level - 1, max))
+    }
+
+    pub fn is_allocated(&self, id: usize, level: usize) -> bool {
+        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            return (self.bitmap >> bit_index) & 1 == 1;
+        }
+
+        if let Some(child) = self.children.get(&bit_index) {
+            child.is_allocated(id, level - 1)
+        } else {
+            // If the child node doesn't exist, no IDs in that range can be allocated.
+            false
+        }
+    }
+
+    /// Returns the bitmap of the leaf covering `id`, or zero if that leaf
+    /// does not exist.
+    pub fn leaf_bitmap(&self, id: usize, level: usize) -> u64 {
+        if level == 0 {
+            return self.bitmap;
+        }
+        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+        self.children
+            .get(&bit_index)
+            .map_or(0, |child| child.leaf_bitmap(id, level - 1))
+    }
+
+    /// Returns the lowest free ID in `lo..=hi` whose position within its
+    /// leaf is set in `leaf_mask`, without allocating it.
+    ///
+    /// `base` is the first ID covered by this subtree, and `lo` must not be
+    /// below it. Missing children are treated as entirely free.
+    pub fn find_free_masked(
+        &self,
+        level: usize,
+        base: usize,
+        lo: usize,
+        hi: usize,
+        leaf_mask: u64,
+    ) -> Option<usize> {
+        let shift = level * IDA_SHIFT;
+        let first = (lo - base) >> shift;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let free = !self.bitmap & bit_range_mask(first, last) & leaf_mask;
+            return (free != 0).then(|| base + free.trailing_zeros() as usize);
+        }
+
+        for i in first..=last {
+            // Skip children that are marked as full.
+            if (self.bitmap >> i) & 1 == 1 {
+                continue;
+            }
+            let child_base = base + (i << shift);
+            let child_lo = lo.max(child_base);
+            let found = match self.children.get(&i) {
+                // A missing child means its whole range is free, so the first
+                // ID the mask permits is the answer if it is still in range.
+                None => {
+                    let child_hi = hi.min(child_base + ((1 << shift) - 1));
+                    first_masked_at_or_after(child_lo, leaf_mask).filter(|&id| id <= child_hi)
+                }
+                // A leaf whose free bits miss the mask has nothing to offer,
+                // so test it here rather than descending into it.
+                Some(child) if level == 1 => {
+                    let free = !child.bitmap
+                        & bit_range_mask(
+                            child_lo - child_base,
+                            (hi - child_base).min(IDA_BITMAP_BITS - 1),
+                        )
+                        & leaf_mask;
+                    (free != 0).then(|| child_base + free.trailing_zeros() as usize)
+                }
+                Some(child) => {
+                    child.find_free_masked(level - 1, child_base, child_lo, hi, leaf_mask)
+                }
+            };
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        None
+    }
+
+    /// Returns the lowest allocated ID in `lo..=hi`.
+    ///
+    /// `base` is the first ID covered by this subtree, and `lo` must not be
+    /// below it.
+    pub fn find_allocated(&self, level: usize, base: usize, lo: usize, hi: usize) -> Option<usize> {
+        let shift = level * IDA_SHIFT;
+        let first = (lo - base) >> shift;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let bits = self.bitmap & bit_range_mask(first, last);
+            return (bits != 0).then(|| base + bits.trailing_zeros() as usize);
+        }
+
+        self.children.range(first..=last).find_map(|(&i, child)| {
+            let child_base = base + (i << shift);
+            child.find_allocated(level - 1, child_base, lo.max(child_base), hi)
+        })
+    }
+
+    /// Creates every missing node covering IDs `base..=hi`, without
+    /// allocating any of them.
+    ///
+    /// `base` is the first ID covered by this subtree.
+    pub fn build_through(&mut self, level: usize, base: usize, hi: usize) {
+        if level == 0 {
+            return;
+        }
+        let shift = level * IDA_SHIFT;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+        for i in 0..=last {
+            let child_base = base + (i << shift);
+            let child = self
+                .children
+                .entry(i)
+                .or_insert_with(|| self.alloc.new_child());
+            Arc::make_mut(child).build_through(level - 1, child_base, hi);
+        }
+    }
+
+    /// Returns the highest allocated ID in this subtree.
+    ///
+    /// `base` is the first ID covered by this subtree.
+    pub fn highest_allocated(&self, level: usize, base: usize) -> Option<usize> {
+        if level == 0 {
+            let top = IDA_BITMAP_BITS - 1;
+            return (self.bitmap != 0).then(|| base + (top - self.bitmap.leading_zeros() as usize));
+        }
+
+        let shift = level * IDA_SHIFT;
+        self.children
+            .iter()
+            .rev()
+            .find_map(|(&i, child)| child.highest_allocated(level - 1, base + (i << shift)))
+    }
+
+    /// Returns the highest allocated ID in this subtree that is at most `hi`.
+    ///
+    /// `base` is the first ID covered by this subtree, and `hi` must not be
+    /// below it.
+    pub fn highest_allocated_through(&self, level: usize, base: usize, hi: usize) -> Option<usize> {
+        let shift = level * IDA_SHIFT;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let bits = self.bitmap & bit_range_mask(0, last);
+            let top = IDA_BITMAP_BITS - 1;
+            return (bits != 0).then(|| base + (top - bits.leading_zeros() as usize));
+        }
+
+        self.children.range(..=last).rev().find_map(|(&i, child)| {
+            child.highest_allocated_through(level - 1, base + (i << shift), hi)
+        })
+    }
+
+    /// Like [`find_free_masked`](Self::find_free_masked) with every leaf
+    /// position allowed, but gives up once more than
+    /// `*budget` internal nodes have been visited.
+    ///
+    /// Each internal node visited decrements `budget`, and each descent into
+    /// a child that turns out to have no free ID in range despite not being
+    /// marked full increments `backtracks`. Returns
+    /// `Err(AllocError::EffortExceeded)` if the budget runs out before the
+    /// search completes.
+    pub fn find_free_budgeted(
+        &self,
+        level: usize,
+        base: usize,
+        lo: usize,
+        hi: usize,
+        budget: &mut usize,
+        backtracks: &mut usize,
+    ) -> Result<Option<usize>, AllocError> {
+        let shift = level * IDA_SHIFT;
+        let first = (lo - base) >> shift;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            let free = !self.bitmap & bit_range_mask(first, last);
+            return Ok((free != 0).then(|| base + free.trailing_zeros() as usize));
+        }
+
+        *budget = budget.checked_sub(1).ok_or(AllocError::EffortExceeded)?;
+        for i in first..=last {
+            if (self.bitmap >> i) & 1 == 1 {
+                continue;
+            }
+            let child_base = base + (i << shift);
+            let child_lo = lo.max(child_base);
+            match self.children.get(&i) {
+                None => return Ok(Some(child_lo)),
+                Some(child) => {
+                    let found = child.find_free_budgeted(
+                        level - 1,
+                        child_base,
+                        child_lo,
+                        hi,
+                        budget,
+                        backtracks,
+                    )?;
+                    if found.is_some() {
+                        return Ok(found);
+                    }
+                    *backtracks += 1;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Calls `f(base, bitmap)` for every leaf in this subtree in ascending ID
+    /// order, where `base` is the first ID covered by the leaf.
+    pub fn for_each_leaf(&self, level: usize, base: usize, f: &mut impl FnMut(usize, u64)) {
+        if level == 0 {
+            f(base, self.bitmap);
+            return;
+        }
+
+        for (&i, child) in &self.children {
+            child.for_each_leaf(level - 1, base | (i << (level * IDA_SHIFT)), f);
+        }
+    }
+
+    /// Like [`for_each_leaf`](Self::for_each_leaf), but only visits leaves
+    /// overlapping `lo..=hi`, with their bitmaps masked to that range.
+    ///
+    /// `base` is the first ID covered by this subtree, and `lo` must not be
+    /// below it.
+    pub fn for_each_leaf_in(
+        &self,
+        level: usize,
+        base: usize,
+        lo: usize,
+        hi: usize,
+        f: &mut impl FnMut(usize, u64),
+    ) {
+        let shift = level * IDA_SHIFT;
+        let first = (lo - base) >> shift;
+        let last = ((hi - base) >> shift).min(IDA_BITMAP_BITS - 1);
+
+        if level == 0 {
+            f(base, self.bitmap & bit_range_mask(first, last));
+            return;
+        }
+
+        for (&i, child) in self.children.range(first..=last) {
+            let child_base = base + (i << shift);
+            child.for_each_leaf_in(level - 1, child_base, lo.max(child_base), hi, f);
+        }
+    }
+
+    /// Calls `f(id)` for every allocated ID in this subtree in ascending order.
+    #[cfg_attr(not(feature = "roaring"), allow(dead_code))]
+    pub fn for_each(&self, level: usize, base: usize, f: &mut impl FnMut(usize)) {
+        self.for_each_leaf(level, base, &mut |leaf_base, mut bitmap| {
+            while bitmap != 0 {
+                let bit = bitmap.trailing_zeros() as usize;
+                f(leaf_base | bit);
+                bitmap &= bitmap - 1;
+            }
+        });
+    }
+
+    /// Walks `a` and `b` in parallel, collecting IDs set only in `b` into
+    /// `added` and IDs set only in `a` into `removed`.
+    ///
+    /// Subtrees that are absent from both, or marked full in both, are
+    /// skipped without descending.
+    pub fn diff_into(
+        a: Option<&Self>,
+        b: Option<&Self>,
+        level: usize,
+        base: usize,
+        added: &mut Vec<usize>,
+        removed: &mut Vec<usize>,
+    ) {
+        match (a, b) {
+            (None, None) => {}
+            (Some(a), None) => a.for_each(level, base, &mut |id| removed.push(id)),
+            (None, Some(b)) => b.for_each(level, base, &mut |id| added.push(id)),
+            (Some(a), Some(b)) if level == 0 => {
+                let mut only_b = b.bitmap & !a.bitmap;
+                while only_b != 0 {
+                    added.push(base | only_b.trailing_zeros() as usize);
+                    only_b &= only_b - 1;
+                }
+                let mut only_a = a.bitmap & !b.bitmap;
+                while only_a != 0 {
+                    removed.push(base | only_a.trailing_zeros() as usize);
+                    only_a &= only_a - 1;
+                }
+            }
+            (Some(a), Some(b)) => {
+                let both_full = a.bitmap & b.bitmap;
+                let mut keys: Vec<usize> = a
+                    .children
+                    .keys()
+                    .chain(b.children.keys())
+                    .copied()
+                    .collect();
+                keys.sort_unstable();
+                keys.dedup();
+                for i in keys {
+                    if (both_full >> i) & 1 == 1 {
+                        continue;
+                    }
+                    Self::diff_into(
+                        a.children.get(&i).map(|c| &**c),
+                        b.children.get(&i).map(|c| &**c),
+                        level - 1,
+                        base | (i << (level * IDA_SHIFT)),
+                        added,
+                        removed,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Sets every ID that is set in `other`, returning how many were not
+    /// already set.
+    ///
+    /// Subtrees absent from this tree are shared with `other` rather than
+    /// copied, and leaves are merged a whole bitmap at a time.
+    pub fn union_from(&mut self, other: &Self, level: usize) -> usize {
+        if level == 0 {
+            let newly_set = (other.bitmap & !self.bitmap).count_ones() as usize;
+            self.bitmap |= other.bitmap;
+            return newly_set;
+        }
+
+        let mut newly_set = 0;
+        for (&i, other_child) in &other.children {
+            let child = match self.children.get_mut(&i) {
+                Some(child) => {
+                    let child = Arc::make_mut(child);
+                    newly_set += child.union_from(other_child, level - 1);
+                    &*child
+                }
+                None => {
+                    newly_set += other_child.count_ones(level - 1);
+                    self.children.insert(i, other_child.clone());
+                    &**other_child
+                }
+            };
+            if child.bitmap == u64::MAX {
+                self.bitmap |= 1 << i;
+            }
+        }
+        newly_set
+    }
+
+    /// Removes the empty nodes on the path from this node down to the leaf
+    /// covering `id`.
+    pub fn prune_path(&mut self, id: usize, level: usize) {
+        if level == 0 {
+            return;
+        }
+        let bit_index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+        if let Some(child) = self.children.get_mut(&bit_index) {
+            let child = Arc::make_mut(child);
+            child.prune_path(id, level - 1);
+            if child.bitmap == 0 && child.children.is_empty() {
+                self.children.remove(&bit_index);
+            }
+        }
+    }
+
+    /// Removes every empty node below this one.
+    pub fn prune(&mut self) {
+        self.children.retain(|_, child| {
+            Arc::make_mut(child).prune();
+            child.bitmap != 0 || !child.children.is_empty()
+        });
+    }
+
+    /// Returns whether every full-bit in this subtree is set exactly for the
+    /// children that are present and full.
+    ///
+    /// The root's bits for children beyond `IDA_ROOT_CHILDREN` are expected
+    /// to be set, since those children can never exist.
+    #[cfg(test)]
+    pub fn is_consistent(&self, level: usize) -> bool {
+        if level == 0 {
+            return true;
+        }
+        let children = if level == IDA_MAX_LEVELS - 1 {
+            IDA_ROOT_CHILDREN
+        } else {
+            IDA_BITMAP_BITS
+        };
+        let bits_ok = (0..IDA_BITMAP_BITS).all(|i| {
+            let full = i >= children || self.children.get(&i).is_some_and(|c| c.bitmap == u64::MAX);
+            (self.bitmap >> i & 1 == 1) == full
+        });
+        bits_ok
+            && self.children.keys().all(|&i| i < children)
+            && self
+                .children
+                .values()
+                .all(|child| child.is_consistent(level - 1))
+    }
+
+    /// Counts the nodes in this subtree, including this one.
+    pub fn node_count(&self) -> usize {
+        1 + self
+            .children
+            .values()
+            .map(|child| child.node_count())
+            .sum::<usize>()
+    }
+
+    /// Counts the allocated IDs in this subtree by summing leaf popcounts.
+    pub fn count_ones(&self, level: usize) -> usize {
+        if level == 0 {
+            return self.bitmap.count_ones() as usize;
+        }
+
+        self.children
+            .values()
+            .map(|child| child.count_ones(level - 1))
+            .sum()
+    }
+
+    /// Appends one line for this node to `out`, indented by `depth`, then
+    /// the lines of each child subtree that holds any allocated IDs.
+    ///
+    /// Returns whether this subtree holds any allocated IDs. A child's lines
+    /// are rolled back if it turns out to hold none.
+    fn write_tree(
+        &self,
+        out: &mut String,
+        level: usize,
+        index: Option<usize>,
+        depth: usize,
+    ) -> bool {
+        let indent = depth * 2;
+        let _ = match index {
+            Some(index) => writeln!(
+                out,
+                "{:indent$}level {level} index {index} bitmap {:#018x}",
+                "", self.bitmap
+            ),
+            None => writeln!(
+                out,
+                "{:indent$}level {level} root bitmap {:#018x}",
+                "", self.bitmap
+            ),
+        };
+        if level == 0 {
+            return self.bitmap != 0;
+        }
+        let mut populated = false;
+        for (&index, child) in &self.children {
+            let start = out.len();
+            if child.write_tree(out, level - 1, Some(index), depth + 1) {
+                populated = true;
+            } else {
+                out.truncate(start);
+            }
+        }
+        populated
+    }
+}
+
+impl Ida {
+    /// Creates a new, empty ID allocator.
+    ///
+    /// The allocator starts with no IDs allocated. The first call to [`alloc`](Self::alloc)
+    /// will return ID `0`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    pub fn new() -> Self {
+        IdaBuilder::new().build()
+    }
+
+    /// Returns a builder for an allocator with settings other than the
+    /// defaults.
+    ///
+    /// See [`IdaBuilder`] for the settings available.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().span(10, 20).recycle_delay(1).build();
+    /// assert_eq!(ida.alloc(), Some(10));
+    /// ```
+    pub fn builder() -> IdaBuilder {
+        IdaBuilder::new()
+    }
+
+    /// Prunes the nodes left empty by frees since the last call, for an
+    /// allocator built with [`IdaBuilder::lazy`].
+    ///
+    /// Only the paths of leaves that were freed into are visited, so the
+    /// cost is proportional to the frees being caught up on rather than to
+    /// the size of the tree. On a non-lazy allocator this does nothing,
+    /// since frees already prune as they go.
+    pub fn maintain(&self) {
+        let mut inner = self.inner.lock();
+        let dirty = mem::take(&mut inner.dirty_leaves);
+        for leaf in dirty {
+            inner.root.prune_path(leaf << IDA_SHIFT, IDA_MAX_LEVELS - 1);
+        }
+    }
+
+    /// Changes the highest ID the allocator may hand out to `new_max`.
+    ///
+    /// Raising the bound always succeeds and makes the new IDs available
+    /// immediately. Lowering it succeeds only if no ID above `new_max` is
+    /// allocated; otherwise nothing changes. Passing `usize::MAX` makes the
+    /// allocator unbounded.
+    ///
+    /// # Errors
+    ///
+    /// - [`ResizeError::Occupied`] - If an ID above `new_max` is allocated
+    /// - [`ResizeError::BelowMin`] - If `new_max` is below the lowest ID of
+    ///   the span
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{Ida, ResizeError};
+    ///
+    /// let ida = Ida::builder().max(1).build();
+    /// ida.alloc();
+    /// ida.alloc();
+    /// assert_eq!(ida.alloc(), None);
+    ///
+    /// ida.set_max(2).unwrap();
+    /// assert_eq!(ida.alloc(), Some(2));
+    ///
+    /// assert_eq!(ida.set_max(0), Err(ResizeError::Occupied(1)));
+    /// ```
+    pub fn set_max(&self, new_max: usize) -> Result<(), ResizeError> {
+        let mut inner = self.inner.lock();
+        if new_max < inner.min {
+            return Err(ResizeError::BelowMin(inner.min));
+        }
+        if new_max < inner.max {
+            let occupied =
+                inner
+                    .root
+                    .find_allocated(IDA_MAX_LEVELS - 1, 0, new_max + 1, usize::MAX);
+            if let Some(id) = occupied {
+                return Err(ResizeError::Occupied(id));
+            }
+            // Queued IDs above the new bound must not be handed out again.
+            inner.free_list.retain(|&id| id <= new_max);
+        }
+        inner.max = new_max;
+        self.notify_watchers(inner);
+        Ok(())
+    }
+
+    /// Allocates and returns the next available ID.
+    ///
+    /// With the default [`AllocPolicy::LowestFirst`] policy this method always
+    /// returns the lowest available ID. If an ID has been freed, it will be
+    /// reused before allocating higher IDs.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID (a `usize` value)
+    /// - `None` - If the allocator has exhausted the available ID space
+    ///
+    /// # Thread Safety
+    ///
+    /// This method is thread-safe and can be called concurrently from multiple threads.
+    /// Each call is guaranteed to return a unique ID.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    ///
+    /// // Allocate IDs sequentially
+    /// let id1 = ida.alloc().unwrap();
+    /// let id2 = ida.alloc().unwrap();
+    /// assert_eq!(id1, 0);
+    /// assert_eq!(id2, 1);
+    ///
+    /// // Free an ID and reallocate
+    /// ida.free(id1);
+    /// let id3 = ida.alloc().unwrap();
+    /// assert_eq!(id3, 0); // Reuses the freed ID
+    /// ```
+    pub fn alloc(&self) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let id = inner.alloc();
+        self.notify_watchers(inner);
+        id
+    }
+
+    /// Allocates like [`alloc`](Self::alloc), but when every free ID is
+    /// still waiting out the recycle delay, hands out the one freed longest
+    /// ago instead of failing.
+    ///
+    /// A small allocator with a long recycle delay can otherwise stall:
+    /// allocations fail, so the clock that ages the waiting IDs never
+    /// moves. This gives up the guarantee of [`IdaBuilder::recycle_delay`]
+    /// only in that case.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no ID is free, waiting or not
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().recycle_delay(100).max(0).build();
+    /// ida.alloc();
+    /// ida.free(0);
+    ///
+    /// assert_eq!(ida.alloc(), None);
+    /// assert_eq!(ida.alloc_or_recycle(), Some(0));
+    /// ```
+    pub fn alloc_or_recycle(&self) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let id = inner.alloc().or_else(|| inner.alloc_coolest());
+        self.notify_watchers(inner);
+        id
+    }
+
+    /// Allocates the next available ID and attaches `tag` to it.
+    ///
+    /// The tag is a small caller-defined value, such as the ID of the
+    /// subsystem that owns the allocation, and can be read back with
+    /// [`tag_of`](Self::tag_of) to debug ownership. It is dropped when the ID
+    /// is freed. The ID is chosen exactly as [`alloc`](Self::alloc) would.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no ID is free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// let id = ida.alloc_tagged(7).unwrap();
+    /// assert_eq!(ida.tag_of(id), Some(7));
+    ///
+    /// ida.free(id);
+    /// assert_eq!(ida.tag_of(id), None);
+    /// ```
+    pub fn alloc_tagged(&self, tag: u16) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let id = inner.alloc()?;
+        inner.tags.insert(id, tag);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Returns the tag `id` was allocated with by
+    /// [`alloc_tagged`](Self::alloc_tagged).
+    ///
+    /// Returns `None` if `id` is free or was allocated without a tag. IDs
+    /// moved by [`relocate_range`](Self::relocate_range) lose their tags.
+    pub fn tag_of(&self, id: usize) -> Option<u16> {
+        self.inner.lock().tags.get(&id).copied()
+    }
+
+    /// Returns how many currently allocated IDs carry `tag`.
+    ///
+    /// This walks every tagged ID, so it takes time proportional to the
+    /// number of tagged allocations.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_tagged(1);
+    /// ida.alloc_tagged(2);
+    /// ida.alloc_tagged(1);
+    /// ida.alloc();
+    ///
+    /// assert_eq!(ida.count_by_tag(1), 2);
+    /// assert_eq!(ida.count_by_tag(2), 1);
+    /// ```
+    pub fn count_by_tag(&self, tag: u16) -> usize {
+        let inner = self.inner.lock();
+        inner.tags.values().filter(|&&t| t == tag).count()
+    }
+
+    /// Tentatively allocates the next available ID as part of a transaction.
+    ///
+    /// The ID is chosen as by [`alloc`](Self::alloc) and cannot be handed
+    /// out to anyone else, but stays pending until the returned token is
+    /// committed. Rolling the token back, or dropping it, frees the ID. Use
+    /// [`is_committed`](Self::is_committed) to tell pending IDs from
+    /// committed ones.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(token)` - The pending allocation
+    /// - `None` - If no ID is free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    ///
+    /// let prepared = ida.alloc_prepare().unwrap();
+    /// let id = prepared.id();
+    /// assert!(ida.is_allocated(id) && !ida.is_committed(id));
+    /// assert_eq!(prepared.commit(), id);
+    /// assert!(ida.is_committed(id));
+    ///
+    /// // A rolled-back ID is free again
+    /// let prepared = ida.alloc_prepare().unwrap();
+    /// let id = prepared.id();
+    /// prepared.rollback();
+    /// assert!(!ida.is_allocated(id));
+    /// ```
+    pub fn alloc_prepare(&self) -> Option<PreparedId<'_>> {
+        let mut inner = self.inner.lock();
+        let id = inner.alloc()?;
+        inner.pending.insert(id);
+        self.notify_watchers(inner);
+        Some(PreparedId {
+            ida: self,
+            id,
+            committed: false,
+        })
+    }
+
+    /// Reserves every ID in `ids`, returning a guard that releases them all
+    /// when dropped unless committed.
+    ///
+    /// Reservation is all-or-nothing: the IDs are checked and allocated
+    /// under a single lock acquisition, and if any of them cannot be
+    /// reserved, none are.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first ID in `ids` that is already allocated, appears
+    /// twice, is above the allocator's bound, or is blacklisted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// {
+    ///     let group = ida.reserve_scoped(&[3, 7, 9]).unwrap();
+    ///     assert!(ida.is_allocated(7));
+    ///     // Setup fails, so the group is dropped without committing
+    /// }
+    /// assert!(ida.is_empty());
+    ///
+    /// ida.reserve_scoped(&[3, 7]).unwrap().commit();
+    /// assert_eq!(ida.reserve_scoped(&[1, 7]).unwrap_err(), 7);
+    /// assert_eq!(ida.snapshot(), vec![3, 7]);
+    /// ```
+    pub fn reserve_scoped(&self, ids: &[usize]) -> Result<ScopedReservation<'_>, usize> {
+        let mut inner = self.inner.lock();
+        for (k, &id) in ids.iter().enumerate() {
+            if id > inner.max
+                || inner.is_excluded(id)
+                || ids[..k].contains(&id)
+                || inner.root.is_allocated(id, IDA_MAX_LEVELS - 1)
+            {
+                return Err(id);
+            }
+        }
+        for &id in ids {
+            inner.claim(id);
+            inner.pending.insert(id);
+        }
+        self.notify_watchers(inner);
+        Ok(ScopedReservation {
+            ida: self,
+            ids: ids.to_vec(),
+            committed: false,
+        })
+    }
+
+    /// Allocates the next available ID as a token that frees it when
+    /// dropped.
+    ///
+    /// The ID is allocated exactly as by [`alloc`](Self::alloc). Because the
+    /// returned [`PinnedId`] borrows `self`, the ID cannot escape the scope
+    /// of the allocator that owns it.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(token)` - A token for the allocated ID
+    /// - `None` - If no IDs are available
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// {
+    ///     let id = ida.alloc_pinned().unwrap();
+    ///     assert_eq!(*id, 0);
+    ///     assert!(ida.is_allocated(*id));
+    /// }
+    /// // The token freed the ID when it went out of scope
+    /// assert!(ida.is_empty());
+    /// ```
+    pub fn alloc_pinned(&self) -> Option<PinnedId<'_>> {
+        let mut inner = self.inner.lock();
+        let id = inner.alloc()?;
+        inner.pending.insert(id);
+        self.notify_watchers(inner);
+        Some(PinnedId { ida: self, id })
+    }
+
+    /// Returns `true` if `id` is allocated and not pending a commit from
+    /// [`alloc_prepare`](Self::alloc_prepare) or
+    /// [`reserve_scoped`](Self::reserve_scoped). IDs held by a
+    /// [`PinnedId`] are never committed.
+    pub fn is_committed(&self, id: usize) -> bool {
+        let inner = self.inner.lock();
+        inner.root.is_allocated(id, IDA_MAX_LEVELS - 1) && !inner.pending.contains(&id)
+    }
+
+    /// Allocates and returns the next available ID, reporting exhaustion as
+    /// an error.
+    ///
+    /// This behaves exactly like [`alloc`](Self::alloc), for callers that
+    /// prefer to propagate failures with `?`.
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::Exhausted`] - If every ID the allocator may hand out is
+    ///   already allocated
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocError, Ida};
+    ///
+    /// let ida = Ida::builder().max(0).build();
+    /// assert_eq!(ida.try_alloc(), Ok(0));
+    /// assert_eq!(ida.try_alloc(), Err(AllocError::Exhausted));
+    /// ```
+    pub fn try_alloc(&self) -> Result<usize, AllocError> {
+        self.alloc().ok_or(AllocError::Exhausted)
+    }
+
+    /// Allocates and returns the lowest free ID within `range`.
+    ///
+    /// Any kind of range is accepted, so `start..` allocates at or above
+    /// `start`.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If every ID in `range` (up to the allocator's bound) is
+    ///   already allocated, or the range is empty
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_in_range(100..102), Some(100));
+    /// assert_eq!(ida.alloc_in_range(100..102), Some(101));
+    /// assert_eq!(ida.alloc_in_range(100..102), None);
+    /// assert_eq!(ida.alloc_in_range(100..), Some(102));
+    /// ```
+    pub fn alloc_in_range(&self, range: impl RangeBounds<usize>) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let id = inner.find_free_within(&range)?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates the lowest free ID in the window of `class`, spilling into
+    /// the windows of lower-priority classes when it is full.
+    ///
+    /// The windows are set with [`IdaBuilder::class_windows`]. Under normal
+    /// load each class stays inside its own window. When a window fills up, the
+    /// class falls back to the windows below it in priority order, so a
+    /// [`Priority::High`] allocation can take a [`Priority::Normal`] or
+    /// [`Priority::Low`] ID, but a lower class never reaches into a higher
+    /// one's window. That keeps the high-priority window in reserve however
+    /// busy the others get. Without configured windows every class draws
+    /// the lowest free ID from the whole space.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If the class's window and every window below it are full
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{Ida, Priority};
+    ///
+    /// let ida = Ida::builder().class_windows([0..2, 2..4, 4..6]).build();
+    /// for _ in 0..2 {
+    ///     ida.alloc_class(Priority::Low);
+    /// }
+    ///
+    /// // The low window is full and cannot borrow from above
+    /// assert_eq!(ida.alloc_class(Priority::Low), None);
+    ///
+    /// // High priority fills its own window, then spills downward
+    /// assert_eq!(ida.alloc_class(Priority::High), Some(0));
+    /// assert_eq!(ida.alloc_class(Priority::High), Some(1));
+    /// assert_eq!(ida.alloc_class(Priority::High), Some(2));
+    /// ```
+    pub fn alloc_class(&self, class: Priority) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let id = match &inner.class_windows {
+            None => inner.find_free(0, inner.max),
+            Some(windows) => windows[class as usize..]
+                .iter()
+                .find_map(|window| inner.find_free_within(window)),
+        }?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates the lowest free ID from the first of `ranges` that has one.
+    ///
+    /// Each `(lo, hi)` pair is the half-open window `lo..hi`. The windows are
+    /// tried in the order given, like successive calls to
+    /// [`alloc_in_range`](Self::alloc_in_range), so a later window is only
+    /// used once every earlier one is full. All of them are searched under
+    /// a single lock acquisition.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If every window is full or empty
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// let plan = [(100, 102), (10, 12)];
+    /// assert_eq!(ida.alloc_chain(&plan), Some(100));
+    /// assert_eq!(ida.alloc_chain(&plan), Some(101));
+    /// assert_eq!(ida.alloc_chain(&plan), Some(10));
+    /// ```
+    pub fn alloc_chain(&self, ranges: &[(usize, usize)]) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let id = ranges
+            .iter()
+            .find_map(|&(lo, hi)| inner.find_free_within(&(lo..hi)))?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates the lowest free ID, giving up if the search would visit
+    /// more than `max_nodes` internal tree nodes.
+    ///
+    /// In a nearly full allocator a single search can descend into and back
+    /// out of many subtrees before finding a free ID. This bounds that cost,
+    /// giving real-time callers a predictable worst case: when the limit is
+    /// hit, nothing is allocated and the caller can retry later. Reaching a
+    /// leaf from the root visits one internal node per level, so limits below
+    /// the depth of the tree can only succeed through absent subtrees.
+    ///
+    /// This always searches for the lowest free ID within the bound; the
+    /// allocator's reuse policy and search hint are not consulted.
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::EffortExceeded`] - If the search hit the node limit
+    /// - [`AllocError::Exhausted`] - If the search completed without finding
+    ///   a free ID
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocError, Ida};
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    ///
+    /// assert_eq!(ida.alloc_bounded_effort(1), Err(AllocError::EffortExceeded));
+    /// assert_eq!(ida.alloc_bounded_effort(64), Ok(1));
+    /// ```
+    pub fn alloc_bounded_effort(&self, max_nodes: usize) -> Result<usize, AllocError> {
+        let mut inner = self.inner.lock();
+        let mut budget = max_nodes;
+        let max = inner.max;
+        let mut lo = inner.min;
+        let id = loop {
+            let id = inner
+                .root
+                .find_free_budgeted(IDA_MAX_LEVELS - 1, 0, lo, max, &mut budget, &mut 0)?
+                .ok_or(AllocError::Exhausted)?;
+            if !inner.is_withheld(id) {
+                break id;
+            }
+            lo = id
+                .checked_add(1)
+                .filter(|&lo| lo <= max)
+                .ok_or(AllocError::Exhausted)?;
+        };
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Ok(id)
+    }
+
+    /// Allocates an ID like [`alloc`](Self::alloc), also reporting how much
+    /// work the tree search took.
+    ///
+    /// The reuse policy, search hint and bound are honoured exactly as by
+    /// `alloc`. IDs taken from the [`AllocPolicy::FifoReuse`] queue involve
+    /// no search and report zero cost. This is meant for profiling: a high
+    /// `backtracks` count means freed holes are scattered through the
+    /// subtrees the search passes, and `nodes_visited` is a good starting
+    /// point for the limit given to
+    /// [`alloc_bounded_effort`](Self::alloc_bounded_effort).
+    ///
+    /// # Returns
+    ///
+    /// - `Some((id, metrics))` - The allocated ID and the cost of finding it
+    /// - `None` - If no ID is available
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    ///
+    /// let (id, metrics) = ida.alloc_metered().unwrap();
+    /// assert_eq!(id, 1);
+    /// assert_eq!(ida.alloc_bounded_effort(metrics.nodes_visited), Ok(2));
+    /// ```
+    pub fn alloc_metered(&self) -> Option<(usize, AllocMetrics)> {
+        let mut inner = self.inner.lock();
+        let mut metrics = AllocMetrics::default();
+        let id = inner.alloc_metered(Some(&mut metrics))?;
+        self.notify_watchers(inner);
+        Some((id, metrics))
+    }
+
+    /// Allocates the lowest free ID in region `region`, where region `r`
+    /// covers IDs `r * region_size..(r + 1) * region_size`.
+    ///
+    /// This maps ID ranges onto something like NUMA nodes while keeping a
+    /// single logical allocator: IDs from one region stay clustered
+    /// together. Use [`region_of`](Self::region_of) to map an ID back to its
+    /// region.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If the region is full, `region_size` is zero, or the
+    ///   region starts beyond `usize::MAX`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_region(2, 1000), Some(2000));
+    /// assert_eq!(ida.alloc_region(2, 1000), Some(2001));
+    /// assert_eq!(ida.alloc_region(0, 1000), Some(0));
+    /// assert_eq!(Ida::region_of(2001, 1000), 2);
+    /// ```
+    pub fn alloc_region(&self, region: usize, region_size: usize) -> Option<usize> {
+        if region_size == 0 {
+            return None;
+        }
+        let start = region.checked_mul(region_size)?;
+        // The last region may be cut short by the top of the ID space.
+        let end = start.saturating_add(region_size - 1);
+        self.alloc_in_range(start..=end)
+    }
+
+    /// Returns the region that `id` falls in for regions of `region_size`
+    /// IDs, as used by [`alloc_region`](Self::alloc_region).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `region_size` is zero.
+    #[track_caller]
+    pub fn region_of(id: usize, region_size: usize) -> usize {
+        id / region_size
+    }
+
+    /// Allocates the next available ID along with a sequence number.
+    ///
+    /// Sequence numbers start at `0` and increase by one with every call,
+    /// across all IDs. They are assigned under the same lock as the ID, so a
+    /// later allocation always carries a higher number. When an ID is freed
+    /// and handed out again, the new holder gets a different sequence number
+    /// than the old one, which lets consumers of ID-indexed slots detect
+    /// reuse (the ABA problem) without tracking generations per ID.
+    ///
+    /// # Returns
+    ///
+    /// - `Some((id, version))` - The allocated ID and its sequence number
+    /// - `None` - If no ID is free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// let (id, first) = ida.alloc_versioned().unwrap();
+    ///
+    /// ida.free(id);
+    /// let (reused, second) = ida.alloc_versioned().unwrap();
+    ///
+    /// assert_eq!(id, reused);
+    /// assert!(second > first);
+    /// ```
+    pub fn alloc_versioned(&self) -> Option<(usize, u64)> {
+        let mut inner = self.inner.lock();
+        let id = inner.alloc()?;
+        let version = inner.next_version;
+        inner.next_version += 1;
+        self.notify_watchers(inner);
+        Some((id, version))
+    }
+
+    /// Allocates the specific ID `id`.
+    ///
+    /// This is useful for reserving well-known IDs, or for restoring state
+    /// that was allocated elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::AlreadyAllocated`] - If `id` is already allocated
+    /// - [`AllocError::OutOfRange`] - If `id` is outside the span set with
+    ///   [`IdaBuilder::max`] or [`IdaBuilder::span`], or is blacklisted with
+    ///   [`IdaBuilder::blacklist`]
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocError, Ida};
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_at(1), Ok(()));
+    /// assert_eq!(ida.alloc_at(1), Err(AllocError::AlreadyAllocated));
+    ///
+    /// // Regular allocation skips the reserved ID
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// assert_eq!(ida.alloc(), Some(2));
+    /// ```
+    pub fn alloc_at(&self, id: usize) -> Result<(), AllocError> {
+        let mut inner = self.inner.lock();
+        if id > inner.max || inner.is_excluded(id) {
+            return Err(AllocError::OutOfRange);
+        }
+        if !inner.claim(id) {
+            return Err(AllocError::AlreadyAllocated);
+        }
+        self.notify_watchers(inner);
+        Ok(())
+    }
+
+    /// Makes sure the specific ID `id` is allocated, allocating it if it is
+    /// free.
+    ///
+    /// Unlike [`alloc_at`](Self::alloc_at), finding `id` already allocated
+    /// is not an error, so setup code that reserves well-known IDs can run
+    /// more than once. Either way, `id` is allocated afterwards.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(true)` - If `id` was free and has now been allocated
+    /// - `Ok(false)` - If `id` was already allocated
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::OutOfRange`] - If `id` can never be allocated: it is
+    ///   outside the allocator's bounds or blacklisted
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocError, Ida};
+    ///
+    /// let ida = Ida::builder().max(10).build();
+    /// assert_eq!(ida.ensure_reserved(3), Ok(true));
+    /// assert_eq!(ida.ensure_reserved(3), Ok(false));
+    /// assert!(ida.is_allocated(3));
+    ///
+    /// assert_eq!(ida.ensure_reserved(11), Err(AllocError::OutOfRange));
+    /// ```
+    pub fn ensure_reserved(&self, id: usize) -> Result<bool, AllocError> {
+        match self.alloc_at(id) {
+            Ok(()) => Ok(true),
+            Err(AllocError::AlreadyAllocated) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Takes over an ID that was handed out by something other than this
+    /// allocator, so that it is never issued again until freed.
+    ///
+    /// This has the same effect as [`alloc_at`](Self::alloc_at), under a
+    /// name that says why: the ID already exists and is in use elsewhere,
+    /// typically in legacy code, and this allocator is becoming responsible
+    /// for it. Once adopted, the ID is freed like any other.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(id)` if `id` is already managed by this allocator, or
+    /// can never be, because it is above the bound set with
+    /// [`IdaBuilder::max`] or blacklisted with [`IdaBuilder::blacklist`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for legacy in [0, 2] {
+    ///     ida.adopt(legacy).unwrap();
+    /// }
+    /// assert_eq!(ida.adopt(2), Err(2));
+    ///
+    /// // New IDs never collide with the adopted ones
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// assert_eq!(ida.alloc(), Some(3));
+    /// ```
+    pub fn adopt(&self, id: usize) -> Result<(), usize> {
+        self.alloc_at(id).map_err(|_| id)
+    }
+
+    /// Allocates the lowest free ID below `hard`, reporting whether it had
+    /// to reach past the soft watermark `soft` to find one.
+    ///
+    /// IDs below `soft` are the pool's normal working range. Once they are
+    /// all taken, allocation keeps succeeding from `soft..hard` so that a
+    /// burst degrades gracefully rather than failing outright, and the
+    /// returned flag lets the caller react, for example by shedding load.
+    /// Only when every ID below `hard` is taken does allocation fail. A
+    /// `soft` at or above `hard` never reports a crossing.
+    ///
+    /// # Returns
+    ///
+    /// `(id, exceeded)`, where `exceeded` is `true` if `id` is at or above
+    /// `soft`.
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::Exhausted`] - If every ID below `hard` (and within the
+    ///   bound set with [`IdaBuilder::max`]) is allocated
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocError, Ida};
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_soft(2, 3), Ok((0, false)));
+    /// assert_eq!(ida.alloc_soft(2, 3), Ok((1, false)));
+    /// assert_eq!(ida.alloc_soft(2, 3), Ok((2, true)));
+    /// assert_eq!(ida.alloc_soft(2, 3), Err(AllocError::Exhausted));
+    /// ```
+    pub fn alloc_soft(&self, soft: usize, hard: usize) -> Result<(usize, bool), AllocError> {
+        let mut inner = self.inner.lock();
+        let hi = hard.checked_sub(1).ok_or(AllocError::Exhausted)?;
+        let hi = hi.min(inner.max);
+        let id = inner.find_free(0, hi).ok_or(AllocError::Exhausted)?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Ok((id, id >= soft))
+    }
+
+    /// Allocates `preferred` if it is free, and otherwise the next free ID
+    /// after it, reporting which one was granted.
+    ///
+    /// If nothing above `preferred` is free, the lowest free ID is used
+    /// instead. The check and the allocation happen under one lock
+    /// acquisition, so the answer cannot be invalidated by another thread in
+    /// between.
+    ///
+    /// # Returns
+    ///
+    /// - `Some((id, true))` - If `preferred` itself was allocated
+    /// - `Some((id, false))` - If `preferred` was taken, out of range or
+    ///   blacklisted, and `id` was allocated in its place
+    /// - `None` - If no ID is available
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_exact_or_next(5), Some((5, true)));
+    /// assert_eq!(ida.alloc_exact_or_next(5), Some((6, false)));
+    /// ```
+    pub fn alloc_exact_or_next(&self, preferred: usize) -> Option<(usize, bool)> {
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let id = inner
+            .find_free(preferred, max)
+            .or_else(|| inner.find_free(0, max))?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some((id, id == preferred))
+    }
+
+    /// Allocates and returns the lowest free ID accepted by `accept`.
+    ///
+    /// IDs for which `accept` returns `false` are skipped but left free, so
+    /// they remain available to other allocation methods. This suits IDs that
+    /// are free as far as the allocator is concerned but unusable externally,
+    /// such as reserved vendor values.
+    ///
+    /// `accept` is called without holding the internal lock, so it may take
+    /// its time or even call back into the allocator. Each candidate is found
+    /// under the lock, tested with the lock released, and then claimed; if
+    /// another thread claims the candidate in between, the search resumes
+    /// from there. The search only moves upwards, so IDs freed below the
+    /// current candidate by other threads during the call are not revisited.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no free ID is accepted
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    ///
+    /// // Skip the IDs that are multiples of 10
+    /// assert_eq!(ida.alloc_where(|id| id % 10 != 0), Some(1));
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    pub fn alloc_where<F: FnMut(usize) -> bool>(&self, mut accept: F) -> Option<usize> {
+        let mut cursor = 0;
+        loop {
+            let candidate = {
+                let inner = self.inner.lock();
+                if cursor > inner.max {
+                    return None;
+                }
+                inner.find_free(cursor, inner.max)?
+            };
+
+            if !accept(candidate) {
+                cursor = candidate.checked_add(1)?;
+                continue;
+            }
+            match self.alloc_at(candidate) {
+                Ok(()) => return Some(candidate),
+                // Another thread took the candidate after we found it, so
+                // look again from the same place.
+                Err(_) => cursor = candidate,
+            }
+        }
+    }
+
+    /// Frees a previously allocated ID, making it available for reuse.
+    ///
+    /// Once freed, the ID becomes available for future allocations. The next call
+    /// to [`alloc`](Self::alloc) will prefer reusing freed IDs before allocating
+    /// new ones.
+    ///
+    /// # Parameters
+    ///
+    /// - `id` - The ID to free. Must be a value that was previously returned by
+    ///   [`alloc`](Self::alloc).
+    ///
+    /// # Behavior
+    ///
+    /// - Freeing an already-free ID is safe but has no effect
+    /// - Freeing an ID that was never allocated is safe but has no effect
+    /// - After freeing, the internal tree structure may be compacted to save memory
+    ///
+    /// # Thread Safety
+    ///
+    /// This method is thread-safe and can be called concurrently from multiple threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// let id = ida.alloc().unwrap();
+    ///
+    /// // Use the ID...
+    ///
+    /// // Free it when done
+    /// ida.free(id);
+    ///
+    /// // The ID can now be reallocated
+    /// let reused_id = ida.alloc().unwrap();
+    /// assert_eq!(id, reused_id);
+    /// ```
+    pub fn free(&self, id: usize) {
+        let mut inner = self.inner.lock();
+        inner.free(id);
+        self.notify_watchers(inner);
+    }
+
+    /// Frees every ID yielded by `ids` in a single locked pass, returning how
+    /// many of them were allocated.
+    ///
+    /// Consecutive IDs that fall in the same 64-ID leaf are freed together
+    /// with one descent of the tree, and emptied nodes are pruned along the
+    /// way. Sorted input, such as a sorted batch of expired handles, gets
+    /// the most out of this. Unsorted input is still freed correctly, but
+    /// costs about as much as freeing each ID separately. IDs that are not
+    /// allocated, or that appear more than once, are ignored.
+    ///
+    /// The lock is held while `ids` is iterated, so the iterator must not
+    /// call back into this allocator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..200 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// let expired = [3, 4, 10, 70, 150, 150, 500];
+    /// assert_eq!(ida.free_iter(expired.into_iter()), 5);
+    /// assert_eq!(ida.len(), 195);
+    /// assert!(!ida.is_allocated(70));
+    /// ```
+    pub fn free_iter(&self, ids: impl Iterator<Item = usize>) -> usize {
+        let mut inner = self.inner.lock();
+        let mut freed = 0;
+        let mut pending: Option<(usize, u64)> = None;
+        for id in ids {
+            let leaf_base = id & !(IDA_BITMAP_BITS - 1);
+            let bit = 1 << (id & (IDA_BITMAP_BITS - 1));
+            match &mut pending {
+                Some((base, mask)) if *base == leaf_base => *mask |= bit,
+                _ => {
+                    if let Some((base, mask)) = pending.replace((leaf_base, bit)) {
+                        freed += inner.free_leaf_bits(base, mask);
+                    }
+                }
+            }
+        }
+        if let Some((base, mask)) = pending {
+            freed += inner.free_leaf_bits(base, mask);
+        }
+        self.notify_watchers(inner);
+        freed
+    }
+
+    /// Frees each ID in `candidates` for which `pred` returns `true`,
+    /// returning how many were allocated and freed.
+    ///
+    /// `pred` is called for every candidate, in order, before the lock is
+    /// taken, so it may consult external state or even call back into this
+    /// allocator. The selected IDs are then freed under a single lock
+    /// acquisition. An ID chosen by `pred` that another thread frees in the
+    /// meantime is simply not counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// let expired = [2, 3, 4, 5];
+    /// assert_eq!(ida.free_if(&expired, |id| id % 2 == 0), 2);
+    /// assert!(!ida.is_allocated(4));
+    /// assert!(ida.is_allocated(5));
+    /// ```
+    pub fn free_if<F: FnMut(usize) -> bool>(&self, candidates: &[usize], mut pred: F) -> usize {
+        let selected: Vec<usize> = candidates.iter().copied().filter(|&id| pred(id)).collect();
+        if selected.is_empty() {
+            return 0;
+        }
+        let mut inner = self.inner.lock();
+        let freed = selected.into_iter().filter(|&id| inner.free(id)).count();
+        self.notify_watchers(inner);
+        freed
+    }
+
+    /// Frees every allocated ID for which `pred` returns `true`, returning
+    /// the freed IDs in ascending order.
+    ///
+    /// This is [`free_if`](Self::free_if) over every allocated ID, handing
+    /// back the IDs themselves so the caller can clean up after them. The
+    /// allocated IDs are copied out first and `pred` runs with the lock
+    /// released, so it may consult external state or call back into this
+    /// allocator. The selected IDs are then freed under a single lock
+    /// acquisition; an ID another thread frees in the meantime is left out
+    /// of the result, and IDs allocated in the meantime are not considered.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..6 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// assert_eq!(ida.drain_filter(|id| id >= 4), vec![4, 5]);
+    /// assert_eq!(ida.snapshot(), vec![0, 1, 2, 3]);
+    /// ```
+    pub fn drain_filter<F: FnMut(usize) -> bool>(&self, mut pred: F) -> Vec<usize> {
+        let selected: Vec<usize> = self.snapshot().into_iter().filter(|&id| pred(id)).collect();
+        if selected.is_empty() {
+            return selected;
+        }
+        let mut inner = self.inner.lock();
+        let drained = selected.into_iter().filter(|&id| inner.free(id)).collect();
+        self.notify_watchers(inner);
+        drained
+    }
+
+    /// Frees `id` like [`free`](Self::free), reporting whether it was
+    /// allocated and whether the allocator is now empty.
+    ///
+    /// Both answers are taken under the same lock as the free itself, so
+    /// when several threads free the last IDs concurrently, exactly one of
+    /// them sees both `was_allocated` and `now_empty`. That makes this
+    /// suitable for triggering teardown of state shared by a group of IDs,
+    /// where calling [`is_empty`](Self::is_empty) afterwards could race.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// let a = ida.alloc().unwrap();
+    /// let b = ida.alloc().unwrap();
+    ///
+    /// assert!(!ida.free_report(a).now_empty);
+    /// let last = ida.free_report(b);
+    /// assert!(last.was_allocated && last.now_empty);
+    ///
+    /// // Freeing an already-free ID is reported as such
+    /// assert!(!ida.free_report(b).was_allocated);
+    /// ```
+    pub fn free_report(&self, id: usize) -> FreeOutcome {
+        let mut inner = self.inner.lock();
+        let was_allocated = inner.free(id);
+        let now_empty = inner.len == 0;
+        self.notify_watchers(inner);
+        FreeOutcome {
+            was_allocated,
+            now_empty,
+        }
+    }
+
+    /// Frees the `len` IDs starting at `start`, returning how many of them
+    /// were actually allocated.
+    ///
+    /// IDs in the range that are already free are skipped, so the return
+    /// value is the number of IDs that went from allocated to free. This is
+    /// handy for keeping external counters in sync.
+    ///
+    /// The range is cleared a leaf at a time rather than one ID at a time,
+    /// and emptied nodes are pruned as with [`free`](Self::free). A range
+    /// running past `usize::MAX` is truncated there.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(5);
+    ///
+    /// // IDs 4..8 were requested, but 5 was already free
+    /// assert_eq!(ida.free_range_counted(4, 4), 3);
+    /// assert_eq!(ida.len(), 6);
+    /// ```
+    pub fn free_range_counted(&self, start: usize, len: usize) -> usize {
+        if len == 0 {
+            return 0;
+        }
+        let end = start.saturating_add(len - 1);
+        let mut inner = self.inner.lock();
+        let freed = inner.free_range(start, end);
+        self.notify_watchers(inner);
+        freed
+    }
+
+    /// Checks if a given ID is currently allocated.
+    ///
+    /// This method queries whether a specific ID has been allocated and not yet freed.
+    /// It's useful for debugging, validation, or tracking ID states.
+    ///
+    /// # Parameters
+    ///
+    /// - `id` - The ID to check
+    ///
+    /// # Returns
+    ///
+    /// - `true` - If the ID is currently allocated
+    /// - `false` - If the ID is free or has never been allocated
+    ///
+    /// # Thread Safety
+    ///
+    /// This method is thread-safe and provides a consistent view at the time of the call.
+    /// Note that in concurrent scenarios, the status may change immediately after this
+    /// method returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    ///
+    /// // Initially, no IDs are allocated
+    /// assert!(!ida.is_allocated(0));
+    /// assert!(!ida.is_allocated(100));
+    ///
+    /// // Allocate an ID
+    /// let id = ida.alloc().unwrap();
+    /// assert_eq!(id, 0);
+    ///
+    /// // Check allocation status
+    /// assert!(ida.is_allocated(0));
+    /// assert!(!ida.is_allocated(1));
+    ///
+    /// // Free the ID
+    /// ida.free(0);
+    /// assert!(!ida.is_allocated(0));
+    /// ```
+    pub fn is_allocated(&self, id: usize) -> bool {
+        let inner = self.inner.lock();
+        inner.root.is_allocated(id, IDA_MAX_LEVELS - 1)
+    }
+
+    /// Returns `true` if every ID in `ids` is currently allocated.
+    ///
+    /// All IDs are checked under a single lock acquisition, so the answer
+    /// reflects one consistent state. Checking stops at the first free ID.
+    /// An empty slice returns `true`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// assert!(ida.contains_all(&[0, 2]));
+    /// assert!(!ida.contains_all(&[0, 3]));
+    /// ```
+    pub fn contains_all(&self, ids: &[usize]) -> bool {
+        let inner = self.inner.lock();
+        ids.iter()
+            .all(|&id| inner.root.is_allocated(id, IDA_MAX_LEVELS - 1))
+    }
+
+    /// Returns `true` if every ID in `start..start + len` is currently
+    /// allocated.
+    ///
+    /// This confirms that a block allocated in one piece, such as by
+    /// [`alloc_blocks`](Self::alloc_blocks), is still held in full. The
+    /// search stops at the first free ID, and subtrees marked as full are
+    /// passed over without descending into them, so a fully allocated range
+    /// costs about one path per subtree it spans rather than one check per
+    /// ID. An empty range returns `true`; a range running past `usize::MAX`
+    /// returns `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..100 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// assert!(ida.is_range_allocated(10, 90));
+    /// assert!(!ida.is_range_allocated(10, 91));
+    /// ```
+    pub fn is_range_allocated(&self, start: usize, len: usize) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let Some(end) = start.checked_add(len - 1) else {
+            return false;
+        };
+        self.inner
+            .lock()
+            .root
+            .find_free_masked(IDA_MAX_LEVELS - 1, 0, start, end, u64::MAX)
+            .is_none()
+    }
+
+    /// Returns whether each ID in `ids` is currently allocated, in the same
+    /// order as `ids`.
+    ///
+    /// All IDs are checked under a single lock acquisition, so the answers
+    /// reflect one consistent state. Consecutive IDs that fall in the same
+    /// leaf share one descent of the tree, so batches that are sorted or
+    /// clustered are answered fastest, but any order is accepted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    /// ida.alloc();
+    /// ida.free(0);
+    ///
+    /// assert_eq!(ida.bulk_is_allocated(&[1, 0, 7, 1]), vec![true, false, false, true]);
+    /// ```
+    pub fn bulk_is_allocated(&self, ids: &[usize]) -> Vec<bool> {
+        let inner = self.inner.lock();
+        let mut cached: Option<(usize, u64)> = None;
+        ids.iter()
+            .map(|&id| {
+                let leaf = id >> IDA_SHIFT;
+                let bitmap = match cached {
+                    Some((cached_leaf, bitmap)) if cached_leaf == leaf => bitmap,
+                    _ => {
+                        let bitmap = inner.root.leaf_bitmap(id, IDA_MAX_LEVELS - 1);
+                        cached = Some((leaf, bitmap));
+                        bitmap
+                    }
+                };
+                (bitmap >> (id & (IDA_BITMAP_BITS - 1))) & 1 == 1
+            })
+            .collect()
+    }
+
+    /// Returns `true` if none of the IDs in `ids` are currently allocated.
+    ///
+    /// All IDs are checked under a single lock acquisition, so the answer
+    /// reflects one consistent state. Checking stops at the first allocated
+    /// ID. An empty slice returns `true`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    ///
+    /// assert!(ida.contains_none(&[1, 2]));
+    /// assert!(!ida.contains_none(&[1, 0]));
+    /// ```
+    pub fn contains_none(&self, ids: &[usize]) -> bool {
+        let inner = self.inner.lock();
+        !ids.iter()
+            .any(|&id| inner.root.is_allocated(id, IDA_MAX_LEVELS - 1))
+    }
+
+    /// Makes subsequent allocations begin their search at `id`.
+    ///
+    /// This is useful for phased workloads where the low part of the ID space
+    /// is known to be densely allocated and scanning it is wasted work. The
+    /// hint only affects where the search begins: once no free ID remains at
+    /// or above `id`, allocation falls back to a search from the root, so
+    /// lower freed IDs are still found eventually.
+    ///
+    /// The hint stays in effect until it is replaced or cleared with
+    /// [`hint_invalidate`](Self::hint_invalidate).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.set_search_start(1000);
+    /// assert_eq!(ida.alloc(), Some(1000));
+    /// assert_eq!(ida.alloc(), Some(1001));
+    ///
+    /// ida.hint_invalidate();
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    pub fn set_search_start(&self, id: usize) {
+        self.inner.lock().search_start = id;
+    }
+
+    /// Clears any hint set by [`set_search_start`](Self::set_search_start).
+    ///
+    /// Subsequent allocations search from the root again, returning the
+    /// lowest free ID.
+    pub fn hint_invalidate(&self) {
+        self.inner.lock().search_start = 0;
+    }
+
+    /// Allocates and returns the lowest free ID with the given parity.
+    ///
+    /// This supports protocols that split a shared ID space between two
+    /// parties by parity, such as SCTP stream IDs where initiators use even
+    /// IDs and responders use odd ones.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no ID with the requested parity is free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{Ida, Parity};
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_parity(Parity::Odd), Some(1));
+    /// assert_eq!(ida.alloc_parity(Parity::Odd), Some(3));
+    /// assert_eq!(ida.alloc_parity(Parity::Even), Some(0));
+    ///
+    /// // Plain allocation still fills the gaps
+    /// assert_eq!(ida.alloc(), Some(2));
+    /// ```
+    pub fn alloc_parity(&self, parity: Parity) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let id = inner.find_free_masked(0, max, parity.leaf_mask())?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates and returns the lowest free ID whose position within its
+    /// 64-ID leaf is set in `leaf_mask`.
+    ///
+    /// Bit `i` of `leaf_mask` permits the IDs `64 * k + i` for every `k`,
+    /// which models hardware that only allows certain slots within each
+    /// bank of 64. Subtrees marked as full are skipped without descending
+    /// into them. [`alloc_parity`](Self::alloc_parity) is the special case
+    /// of an alternating mask.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no permitted ID is free, or `leaf_mask` is zero
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// // Only the first two slots of each bank are usable
+    /// assert_eq!(ida.alloc_masked(0b11), Some(0));
+    /// assert_eq!(ida.alloc_masked(0b11), Some(1));
+    /// assert_eq!(ida.alloc_masked(0b11), Some(64));
+    /// ```
+    pub fn alloc_masked(&self, leaf_mask: u64) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let id = inner.find_free_masked(0, max, leaf_mask)?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates the lowest free ID that is also clear in `overlay`.
+    ///
+    /// `overlay` is a packed bitmap of IDs the caller treats as taken on top
+    /// of the allocator's own state: bit `b` of word `k` stands for ID
+    /// `64 * k + b`, and IDs past its end count as clear. This composes
+    /// short-lived claims the caller tracks itself, such as IDs picked this
+    /// frame but not yet committed, with the allocator, without recording
+    /// them here. Only the returned ID is allocated. Each overlay word is
+    /// combined with the matching leaf in one step, so a run of claimed IDs
+    /// costs one check per leaf rather than one per ID.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If every free ID within the bound is claimed in `overlay`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    ///
+    /// // IDs 1 and 2 are claimed elsewhere
+    /// assert_eq!(ida.alloc_avoiding(&[0b110]), Some(3));
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// ```
+    pub fn alloc_avoiding(&self, overlay: &[u64]) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let mut lo = 0;
+        let id = loop {
+            let id = inner.find_free(lo, max)?;
+            let Some(&claimed) = overlay.get(id / IDA_BITMAP_BITS) else {
+                break id;
+            };
+            if (claimed >> (id % IDA_BITMAP_BITS)) & 1 == 0 {
+                break id;
+            }
+            // Look for an ID clear in both the leaf and the overlay word,
+            // then move on to the next leaf.
+            let leaf_end = id | (IDA_BITMAP_BITS - 1);
+            if let Some(id) = inner.find_free_masked(id, leaf_end.min(max), !claimed) {
+                break id;
+            }
+            lo = leaf_end.checked_add(1)?;
+        };
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates and returns the lowest free ID that is a multiple of `m`.
+    ///
+    /// `m` need not be a power of two. The search jumps from one free ID to
+    /// the next multiple of `m` at or after it, so fully allocated subtrees
+    /// are skipped, but each multiple that turns out to be taken costs a
+    /// separate descent of the tree.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no multiple of `m` is free, or `m` is zero
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_multiple_of(3), Some(0));
+    /// assert_eq!(ida.alloc_multiple_of(3), Some(3));
+    /// assert_eq!(ida.alloc_multiple_of(10), Some(10));
+    ///
+    /// // Plain allocation still fills the gaps
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// ```
+    pub fn alloc_multiple_of(&self, m: usize) -> Option<usize> {
+        if m == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock();
+        let id = inner.find_free_congruent(0, m, 0, usize::MAX, None)?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates the lowest free ID in `range` that is a multiple of
+    /// `align`.
+    ///
+    /// This combines the alignment of
+    /// [`alloc_multiple_of`](Self::alloc_multiple_of) with the window of
+    /// [`alloc_in_range`](Self::alloc_in_range), as needed for interrupt
+    /// vectors that must be aligned and come from a device-specific window.
+    /// Taken aligned IDs are skipped by jumping to the next multiple, so
+    /// fully allocated subtrees are not searched.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If `range` holds no multiple of `align` within the
+    ///   allocator's bound, or every one it holds is taken
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_aligned_in(8, 20..64), Some(24));
+    /// assert_eq!(ida.alloc_aligned_in(8, 20..64), Some(32));
+    ///
+    /// // No multiple of 16 lies in 33..48
+    /// assert_eq!(ida.alloc_aligned_in(16, 33..48), None);
+    /// ```
+    #[track_caller]
+    pub fn alloc_aligned_in(&self, align: usize, range: impl RangeBounds<usize>) -> Option<usize> {
+        assert!(
+            align.is_power_of_two(),
+            "alignment {align} is not a power of two"
+        );
+        let (lo, hi) = inclusive_bounds(&range)?;
+        let mut inner = self.inner.lock();
+        let id = inner.find_free_congruent(0, align, lo, hi, None)?;
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates the lowest free ID that has no allocated ID within `band`
+    /// of it on either side, falling back to the lowest free ID if there is
+    /// none.
+    ///
+    /// Leaving unallocated guard IDs around each allocation makes an
+    /// off-by-one or overrun in code that indexes by ID land on an unused
+    /// slot, where it is easier to catch. The guard IDs are only skipped,
+    /// not reserved: plain [`alloc`](Self::alloc) still hands them out, and
+    /// a freed ID is reused here only once its neighbours within `band` are
+    /// free too. A `band` of zero behaves like a lowest-first `alloc`.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no ID is available at all
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.alloc_spaced(2), Some(0));
+    /// assert_eq!(ida.alloc_spaced(2), Some(3));
+    /// assert_eq!(ida.alloc_spaced(2), Some(6));
+    /// ```
+    pub fn alloc_spaced(&self, band: usize) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let mut lo = 0;
+        let spaced = loop {
+            let Some(candidate) = inner.find_free(lo, max) else {
+                break None;
+            };
+            let window_lo = candidate.saturating_sub(band);
+            let window_hi = candidate.saturating_add(band);
+            match inner
+                .root
+                .find_allocated(IDA_MAX_LEVELS - 1, 0, window_lo, window_hi)
+            {
+                None => break Some(candidate),
+                // Every ID up to `neighbour + band` is too close to it.
+                Some(neighbour) => match neighbour.checked_add(band).and_then(|n| n.checked_add(1))
+                {
+                    Some(next) => lo = next,
+                    None => break None,
+                },
+            }
+        };
+        let id = match spaced {
+            Some(id) => id,
+            None => inner.find_free(0, max)?,
+        };
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Allocates a block of `n` consecutive IDs directly above the highest
+    /// allocated ID, returning the first ID of the block.
+    ///
+    /// Successive blocks stack upward, so a subsystem that frees blocks in
+    /// the reverse order it allocated them keeps its IDs compact. Holes below
+    /// the highest allocated ID are never used; an empty allocator places the
+    /// first block at `0`.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(start)` - The first ID of the allocated block
+    /// - `None` - If `n` is zero or the block would not fit below the
+    ///   allocator's bound
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(10).unwrap();
+    ///
+    /// assert_eq!(ida.alloc_contiguous_high(4), Some(11));
+    /// assert_eq!(ida.alloc_contiguous_high(2), Some(15));
+    ///
+    /// // Freeing the top block lets the next one take its place
+    /// ida.free_range_counted(15, 2);
+    /// assert_eq!(ida.alloc_contiguous_high(3), Some(15));
+    /// ```
+    pub fn alloc_contiguous_high(&self, n: usize) -> Option<usize> {
+        if n == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock();
+        let mut start = match inner.root.highest_allocated(IDA_MAX_LEVELS - 1, 0) {
+            Some(highest) => highest.checked_add(1)?,
+            None => 0,
+        };
+        let end = loop {
+            let end = start.checked_add(n - 1).filter(|&end| end <= inner.max)?;
+            // Move the block past any excluded ID it would cover.
+            match inner.last_excluded_in(start, end) {
+                Some(id) => start = id.checked_add(1)?,
+                None => break end,
+            }
+        };
+        inner.claim_range(start, end);
+        self.notify_watchers(inner);
+        Some(start)
+    }
+
+    /// Allocates the lowest free block of `2^order` consecutive IDs whose
+    /// first ID is a multiple of `2^order`, returning that first ID.
+    ///
+    /// This is the classic buddy-allocator request, where a block's size and
+    /// alignment are the same. Blocks of up to 64 IDs (`order <= 6`) lie
+    /// within a single leaf and are checked with one bitmap mask; larger
+    /// blocks span whole leaves. Fully allocated subtrees are skipped. Free
+    /// the block with [`free_buddy`](Self::free_buddy).
+    ///
+    /// # Returns
+    ///
+    /// - `Some(base)` - The first ID of the allocated block
+    /// - `None` - If no suitable block is free within the allocator's bound
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(1).unwrap();
+    ///
+    /// assert_eq!(ida.alloc_buddy(0), Some(0));
+    /// assert_eq!(ida.alloc_buddy(2), Some(4));
+    /// assert_eq!(ida.alloc_buddy(3), Some(8));
+    ///
+    /// ida.free_buddy(4, 2);
+    /// assert_eq!(ida.alloc_buddy(2), Some(4));
+    /// ```
+    pub fn alloc_buddy(&self, order: usize) -> Option<usize> {
+        if order >= IDA_ID_BITS {
+            return None;
+        }
+        let size = 1usize << order;
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let mut candidate = 0;
+        loop {
+            let free = inner.find_free(candidate, max)?;
+            let base = free & !(size - 1);
+            let end = base.checked_add(size - 1).filter(|&end| end <= max)?;
+            let blocked = inner
+                .root
+                .find_allocated(IDA_MAX_LEVELS - 1, 0, base, end)
+                .or_else(|| inner.last_excluded_in(base, end));
+            match blocked {
+                None => {
+                    inner.claim_range(base, end);
+                    self.notify_watchers(inner);
+                    return Some(base);
+                }
+                // Move on to the next aligned block past the obstacle.
+                Some(id) => candidate = (id & !(size - 1)).checked_add(size)?,
+            }
+        }
+    }
+
+    /// Frees a block allocated by [`alloc_buddy`](Self::alloc_buddy).
+    ///
+    /// Every ID in `base..base + 2^order` is freed, as with
+    /// [`free_range_counted`](Self::free_range_counted).
+    pub fn free_buddy(&self, base: usize, order: usize) {
+        if order >= IDA_ID_BITS {
+            return;
+        }
+        let end = base.saturating_add((1 << order) - 1);
+        let mut inner = self.inner.lock();
+        inner.free_range(base, end);
+        self.notify_watchers(inner);
+    }
+
+    /// Allocates a free ID chosen pseudo-randomly rather than the lowest one.
+    ///
+    /// A starting point is drawn uniformly from the allocatable range using
+    /// the SplitMix64 generator whose state is `rng_state`, and the lowest
+    /// free ID at or above it is allocated, wrapping around to the bottom
+    /// of the range if needed. This makes IDs harder to predict while still
+    /// guaranteeing success whenever any ID is free. Keeping the generator
+    /// state with the caller means no entropy source is needed here; seed it
+    /// from one appropriate to the platform.
+    ///
+    /// Random IDs share few tree nodes, so each one may cost a full path of
+    /// nodes. Bound the allocator with [`IdaBuilder::max`] to keep the tree
+    /// compact.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If no ID is free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().max(1023).build();
+    /// let mut rng = 0x1234_5678;
+    ///
+    /// let a = ida.alloc_random(&mut rng).unwrap();
+    /// let b = ida.alloc_random(&mut rng).unwrap();
+    /// assert_ne!(a, b);
+    /// assert!(a <= 1023 && b <= 1023);
+    /// ```
+    pub fn alloc_random(&self, rng_state: &mut u64) -> Option<usize> {
+        let random = splitmix64(rng_state) as usize;
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+        let start = match max.checked_add(1) {
+            Some(span) => random % span,
+            None => random,
+        };
+        let id = match inner.find_free(start, max) {
+            Some(id) => id,
+            None if start > 0 => inner.find_free(0, start - 1)?,
+            None => return None,
+        };
+        inner.claim(id);
+        self.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Returns the number of IDs currently allocated.
+    ///
+    /// The count is maintained incrementally by [`alloc`](Self::alloc) and
+    /// [`free`](Self::free), so this is an O(1) operation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.len(), 0);
+    ///
+    /// ida.alloc();
+    /// ida.alloc();
+    /// assert_eq!(ida.len(), 2);
+    ///
+    /// ida.free(0);
+    /// ida.free(0); // Freeing an already-free ID does not change the count
+    /// assert_eq!(ida.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.inner.lock().len
+    }
+
+    /// Returns `true` if no IDs are currently allocated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert!(ida.is_empty());
+    ///
+    /// let id = ida.alloc().unwrap();
+    /// assert!(!ida.is_empty());
+    ///
+    /// ida.free(id);
+    /// assert!(ida.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Registers `callback` to run whenever utilization crosses `threshold`
+    /// upward.
+    ///
+    /// Utilization is [`len`](Self::len) divided by the number of IDs the
+    /// allocator may hand out, so this is mainly useful for allocators
+    /// built with [`IdaBuilder::max`]. The callback receives the
+    /// utilization that triggered it. It fires once per crossing: after
+    /// firing it will not fire again until utilization has dropped back
+    /// below `threshold` and risen to meet it again. A threshold that is
+    /// already met at registration does not fire until it is re-crossed.
+    ///
+    /// Thresholds are checked after every mutating method.
+    ///
+    /// # Thread Safety
+    ///
+    /// Callbacks run on the thread whose call caused the crossing, after the
+    /// allocator's lock has been released, so they may safely call back into
+    /// the allocator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let ida = Ida::builder().max(9).build();
+    /// let fired = Arc::new(AtomicUsize::new(0));
+    /// let counter = fired.clone();
+    /// ida.on_utilization(0.5, move |_| {
+    ///     counter.fetch_add(1, Ordering::SeqCst);
+    /// });
+    ///
+    /// for _ in 0..4 {
+    ///     ida.alloc();
+    /// }
+    /// assert_eq!(fired.load(Ordering::SeqCst), 0);
+    ///
+    /// ida.alloc(); // 5 of 10 IDs allocated
+    /// assert_eq!(fired.load(Ordering::SeqCst), 1);
+    /// ```
+    pub fn on_utilization<F>(&self, threshold: f64, callback: F)
+    where
+        F: Fn(f64) + Send + Sync + 'static,
+    {
+        let mut inner = self.inner.lock();
+        let above = inner.utilization() >= threshold;
+        inner.watchers.push(UtilizationWatcher {
+            threshold,
+            above,
+            callback: Arc::new(callback),
+        });
+    }
+
+    /// Registers `callback` to run whenever freeing IDs leaves the allocator
+    /// empty.
+    ///
+    /// The callback fires on every transition from non-empty to empty, so
+    /// cleanup of a pool can run as soon as its last outstanding ID is
+    /// returned, without polling [`is_empty`](Self::is_empty). Freeing an ID
+    /// that is not allocated never fires it, even if the allocator is
+    /// already empty.
+    ///
+    /// # Thread Safety
+    ///
+    /// Callbacks run on the thread whose free emptied the allocator, after
+    /// the allocator's lock has been released, so they may safely call back
+    /// into the allocator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let ida = Ida::new();
+    /// let drained = Arc::new(AtomicUsize::new(0));
+    /// let counter = drained.clone();
+    /// ida.on_empty(move || {
+    ///     counter.fetch_add(1, Ordering::SeqCst);
+    /// });
+    ///
+    /// let a = ida.alloc().unwrap();
+    /// let b = ida.alloc().unwrap();
+    /// ida.free(a);
+    /// assert_eq!(drained.load(Ordering::SeqCst), 0);
+    ///
+    /// ida.free(b);
+    /// assert_eq!(drained.load(Ordering::SeqCst), 1);
+    /// ```
+    pub fn on_empty<F>(&self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.inner
+            .lock()
+            .empty_callbacks
+            .push(EmptyCallback(Arc::new(callback)));
+    }
+
+    /// Releases `inner` and then runs the callbacks of any utilization
+    /// thresholds crossed since they were last checked, followed by the
+    /// [`on_empty`](Self::on_empty) callbacks if a free emptied the
+    /// allocator.
+    fn notify_watchers(&self, mut inner: MutexGuard<'_, IdaInner>) {
+        let crossed = inner.crossed_watchers();
+        let emptied = if mem::take(&mut inner.drained) && inner.len == 0 {
+            inner.empty_callbacks.clone()
+        } else {
+            Vec::new()
+        };
+        if crossed.is_empty() && emptied.is_empty() {
+            return;
+        }
+        let utilization = inner.utilization();
+        drop(inner);
+        for callback in crossed {
+            callback(utilization);
+        }
+        for EmptyCallback(callback) in emptied {
+            callback();
+        }
+    }
+
+    /// Returns an iterator over the free IDs in `range`, in ascending order.
+    ///
+    /// IDs in subtrees that have never been allocated are treated as free.
+    /// IDs beyond the bound set with [`IdaBuilder::max`] can never be
+    /// allocated and are not yielded.
+    /// Because the free space is usually far larger than the allocated set,
+    /// this only accepts a bounded range.
+    ///
+    /// # Thread Safety
+    ///
+    /// The lock is acquired separately for each step rather than held for the
+    /// lifetime of the iterator, so other threads may allocate or free IDs
+    /// while iteration is in progress. Each yielded ID was free at the time it
+    /// was found.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..5 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(1);
+    /// ida.free(3);
+    ///
+    /// let free: Vec<usize> = ida.iter_free_in(0..7).collect();
+    /// assert_eq!(free, vec![1, 3, 5, 6]);
+    /// ```
+    pub fn iter_free_in(&self, range: Range<usize>) -> impl Iterator<Item = usize> + '_ {
+        let mut next = range.start;
+        let end = range.end;
+        core::iter::from_fn(move || {
+            if next >= end {
+                return None;
+            }
+            let inner = self.inner.lock();
+            if next > inner.max {
+                return None;
+            }
+            let hi = inner.max.min(end - 1);
+            let id = inner.find_free(next, hi);
+            drop(inner);
+            // Stop for good once the window has no free IDs left.
+            next = id.map_or(end, |id| id + 1);
+            id
+        })
+    }
+
+    /// Returns an iterator over the allocated IDs at or above `start`, in
+    /// ascending order.
+    ///
+    /// Each step descends straight to the next allocated ID rather than
+    /// scanning from zero, which makes this suitable for paging through the
+    /// allocated set: pass one past the last ID of a page to get the next.
+    ///
+    /// # Thread Safety
+    ///
+    /// The lock is acquired separately for each step rather than held for the
+    /// lifetime of the iterator, so other threads may allocate or free IDs
+    /// while iteration is in progress. Each yielded ID was allocated at the
+    /// time it was found, and IDs are never yielded twice.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(6);
+    ///
+    /// let page: Vec<usize> = ida.iter_from(4).take(3).collect();
+    /// assert_eq!(page, vec![4, 5, 7]);
+    /// let next: Vec<usize> = ida.iter_from(page[2] + 1).collect();
+    /// assert_eq!(next, vec![8, 9]);
+    /// ```
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut next = Some(start);
+        core::iter::from_fn(move || {
+            let id =
+                self.inner
+                    .lock()
+                    .root
+                    .find_allocated(IDA_MAX_LEVELS - 1, 0, next?, usize::MAX);
+            next = id.and_then(|id| id.checked_add(1));
+            id
+        })
+    }
+
+    /// Returns an iterator over the allocated IDs at or below `start`, in
+    /// descending order.
+    ///
+    /// Each step descends straight to the next lower allocated ID, which
+    /// suits routines that work down from the top of the ID space, such as
+    /// moving high IDs into holes lower down.
+    ///
+    /// # Thread Safety
+    ///
+    /// The lock is acquired separately for each step rather than held for the
+    /// lifetime of the iterator, so other threads may allocate or free IDs
+    /// while iteration is in progress. Each yielded ID was allocated at the
+    /// time it was found, and IDs are never yielded twice.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(6);
+    ///
+    /// let top: Vec<usize> = ida.iter_rev_from(7).take(3).collect();
+    /// assert_eq!(top, vec![7, 5, 4]);
+    /// ```
+    pub fn iter_rev_from(&self, start: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut next = Some(start);
+        core::iter::from_fn(move || {
+            let id = self
+                .inner
+                .lock()
+                .root
+                .highest_allocated_through(IDA_MAX_LEVELS - 1, 0, next?);
+            next = id.and_then(|id| id.checked_sub(1));
+            id
+        })
+    }
+
+    /// Allocates `count` separate blocks of `block_size` consecutive IDs in
+    /// one call, returning the first ID of each block.
+    ///
+    /// Each block is placed at the lowest run of free IDs long enough to
+    /// hold it, above the block before it; the blocks need not be adjacent.
+    /// All of them are found under a single lock acquisition, and the
+    /// allocation is all-or-nothing: either every block is allocated or
+    /// none is. Free a block with
+    /// [`free_range_counted`](Self::free_range_counted).
+    ///
+    /// # Returns
+    ///
+    /// - `Some(bases)` - The first ID of each block, in ascending order
+    /// - `None` - If fewer than `count` blocks fit below the allocator's
+    ///   bound, or `block_size` is zero
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(5).unwrap();
+    ///
+    /// // The first block does not fit below ID 5
+    /// assert_eq!(ida.alloc_blocks(8, 2), Some(vec![6, 14]));
+    /// assert_eq!(ida.alloc_blocks(4, 1), Some(vec![0]));
+    /// ```
+    pub fn alloc_blocks(&self, block_size: usize, count: usize) -> Option<Vec<usize>> {
+        if block_size == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock();
+        let mut bases = Vec::with_capacity(count);
+        let mut lo = 0;
+        for _ in 0..count {
+            let base = inner.find_free_run(lo, block_size)?;
+            bases.push(base);
+            // The block ends at or below the bound, so this can only
+            // overflow once the last ID has been used.
+            lo = base + (block_size - 1);
+            if bases.len() < count {
+                lo = lo.checked_add(1)?;
+            }
+        }
+        for &base in &bases {
+            let end = base + (block_size - 1);
+            inner.claim_range(base, end);
+        }
+        self.notify_watchers(inner);
+        Some(bases)
+    }
+
+    /// Allocates `n` IDs that each fall in a different 64-ID leaf.
+    ///
+    /// This is useful for fault isolation, where losing a single leaf (or the
+    /// resource bank it maps to) should affect at most one of the returned
+    /// IDs. Empty leaves are preferred, followed by the least-full partially
+    /// allocated leaves; ties are broken by choosing the lower leaf. Within
+    /// each chosen leaf the lowest free ID is allocated.
+    ///
+    /// The allocation is all-or-nothing: either all `n` IDs are allocated or
+    /// none are.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(ids)` - The allocated IDs in ascending order
+    /// - `None` - If fewer than `n` leaves have a free ID
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    ///
+    /// // Leaf 0 already holds an ID, so the empty leaves are used first.
+    /// let ids = ida.alloc_spread(3).unwrap();
+    /// assert_eq!(ids, vec![64, 128, 192]);
+    /// ```
+    pub fn alloc_spread(&self, n: usize) -> Option<Vec<usize>> {
+        let mut inner = self.inner.lock();
+        let max = inner.max;
+
+        // Popcount of every leaf currently present in the tree, along with
+        // whether it still has a free ID within the bound.
+        let mut leaves = BTreeMap::new();
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, bitmap| {
+                let has_free = base <= max
+                    && inner
+                        .find_free(base, max.min(base + (IDA_BITMAP_BITS - 1)))
+                        .is_some();
+                leaves.insert(base, (bitmap.count_ones(), has_free));
+            });
+
+        let mut candidates: Vec<(u32, usize)> = leaves
+            .iter()
+            .filter(|&(_, &(_, has_free))| has_free)
+            .map(|(&base, &(count, _))| (count, base))
+            .collect();
+
+        // Absent leaves are entirely free. Only the lowest `n` of them can
+        // ever be chosen, so stop once that many have been found. Each
+        // search jumps straight to the next leaf with a free ID, past full
+        // subtrees and whatever lies below the span.
+        let mut absent = 0;
+        let mut base = inner.min & !(IDA_BITMAP_BITS - 1);
+        while absent < n
+            && let Some(free) = inner.find_free(base, max)
+        {
+            let leaf = free & !(IDA_BITMAP_BITS - 1);
+            if !leaves.contains_key(&leaf) {
+                candidates.push((0, leaf));
+                absent += 1;
+            }
+            match leaf.checked_add(IDA_BITMAP_BITS) {
+                Some(next) => base = next,
+                None => break,
+            }
+        }
+
+        if candidates.len() < n {
+            return None;
+        }
+        candidates.sort_unstable();
+        candidates.truncate(n);
+
+        let mut ids: Vec<usize> = candidates
+            .into_iter()
+            .map(|(_, base)| {
+                let id = inner
+                    .find_free(base, max.min(base + IDA_BITMAP_BITS - 1))
+                    .expect("candidate leaf has a free ID");
+                inner.claim(id);
+                id
+            })
+            .collect();
+        self.notify_watchers(inner);
+        ids.sort_unstable();
+        Some(ids)
+    }
+
+    /// Returns `true` if no ID can currently be allocated.
+    ///
+    /// For an allocator built with [`IdaBuilder::max`] this checks whether
+    /// every ID in `0..=max` is allocated or still waiting out the recycle
+    /// delay. It only follows the path to `max` in the tree,
+    /// so it is cheap enough to use as an admission-control gate. An
+    /// unbounded allocator is never considered full.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().max(1).build();
+    /// ida.alloc();
+    /// assert!(!ida.is_full());
+    ///
+    /// ida.alloc();
+    /// assert!(ida.is_full());
+    /// ```
+    pub fn is_full(&self) -> bool {
+        let inner = self.inner.lock();
+        if inner.max == usize::MAX {
+            return false;
+        }
+        if inner.blacklist.is_empty() && inner.min == 0 && inner.cooling.is_empty() {
+            inner.root.is_full_through(IDA_MAX_LEVELS - 1, inner.max)
+        } else {
+            inner.find_free(0, inner.max).is_none()
+        }
+    }
+
+    /// Describes the path `id` takes through the radix tree.
+    ///
+    /// The result lists the bit index used at each level, from the root down
+    /// to the leaf, along with whether the node at that level currently
+    /// exists and whether the ID itself is allocated. This is a focused
+    /// diagnostic for a single ID, as opposed to the full `Debug` dump.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    ///
+    /// // 4097 = 1 * 4096 + 0 * 64 + 1
+    /// let path = ida.describe(4097);
+    /// let indices: Vec<usize> = path.steps.iter().rev().take(3).map(|s| s.index).collect();
+    /// assert_eq!(indices, vec![1, 0, 1]);
+    /// assert!(!path.allocated);
+    /// ```
+    pub fn describe(&self, id: usize) -> IdPath {
+        let inner = self.inner.lock();
+        let mut steps = Vec::with_capacity(IDA_MAX_LEVELS);
+        let mut node = Some(&inner.root);
+        let mut allocated = false;
+
+        for level in (0..IDA_MAX_LEVELS).rev() {
+            let index = (id >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+            steps.push(PathStep {
+                level,
+                index,
+                present: node.is_some(),
+            });
+            if level == 0 {
+                allocated = node.is_some_and(|leaf| (leaf.bitmap >> index) & 1 == 1);
+            }
+            node = node.and_then(|n| n.children.get(&index).map(|child| &**child));
+        }
+
+        IdPath { steps, allocated }
+    }
+
+    /// Returns how many IDs are allocated in the subtree containing
+    /// `prefix_id` at `level`, and how many IDs that subtree covers.
+    ///
+    /// Level `0` is a leaf of 64 IDs, level `1` a first-level subtree of
+    /// 4096, and each level above covers 64 times as many. The subtree is
+    /// the one `prefix_id` falls in, so any ID inside it names it. The
+    /// allocated count is a popcount over the subtree's leaves, so it costs
+    /// time proportional to the subtree's populated nodes. The capacity is
+    /// structural and ignores any [`IdaBuilder::max`] bound or blacklist;
+    /// for the root it saturates at `usize::MAX`.
+    ///
+    /// # Returns
+    ///
+    /// `(allocated, capacity)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `level` is above the root's level.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..100 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// assert_eq!(ida.subtree_usage(0, 0), (64, 64));
+    /// assert_eq!(ida.subtree_usage(64, 0), (36, 64));
+    /// assert_eq!(ida.subtree_usage(0, 1), (100, 4096));
+    /// ```
+    #[track_caller]
+    pub fn subtree_usage(&self, prefix_id: usize, level: usize) -> (usize, usize) {
+        let top = IDA_MAX_LEVELS - 1;
+        assert!(level <= top, "level {level} is above the root level {top}");
+        let capacity = 1usize
+            .checked_shl(((level + 1) * IDA_SHIFT) as u32)
+            .unwrap_or(usize::MAX);
+
+        let inner = self.inner.lock();
+        let mut node = &inner.root;
+        for l in (level + 1..=top).rev() {
+            let index = (prefix_id >> (l * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+            match node.children.get(&index) {
+                Some(child) => node = child,
+                None => return (0, capacity),
+            }
+        }
+        (node.count_ones(level), capacity)
+    }
+
+    /// Renders the radix tree as indented text, one line per node.
+    ///
+    /// Each line shows the node's level, its index within its parent, and
+    /// its bitmap in hex; children are indented below their parent. Nodes
+    /// holding no allocated IDs are left out, so the dump of a sparse
+    /// allocator stays as small as its layout. The root's bitmap has the
+    /// bits for its unrepresentable children set. This is meant for reading
+    /// by people, as opposed to the flat `Debug` output, and its exact
+    /// format is not stable.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    /// let dump = ida.debug_tree();
+    ///
+    /// // The leaf holding ID 0 sits ten levels below the root
+    /// assert_eq!(dump.lines().count(), 11);
+    /// assert!(dump.ends_with("level 0 index 0 bitmap 0x0000000000000001\n"));
+    /// ```
+    pub fn debug_tree(&self) -> String {
+        let mut out = String::new();
+        self.inner
+            .lock()
+            .root
+            .write_tree(&mut out, IDA_MAX_LEVELS - 1, None, 0);
+        out
+    }
+
+    /// Returns the number of IDs this allocator can represent.
+    ///
+    /// For an allocator built with [`IdaBuilder::max`] this is `max + 1`,
+    /// and for one built with [`IdaBuilder::span`] it is `end - start`. For an unbounded allocator it is the size of the
+    /// full `usize` space, which is why the result is a `u128`.
+    ///
+    /// Together with [`len`](Self::len) this gives the utilization of the
+    /// allocator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// assert_eq!(Ida::builder().max(255).build().total_capacity(), 256);
+    /// assert_eq!(Ida::new().total_capacity(), 1 << usize::BITS);
+    /// ```
+    pub fn total_capacity(&self) -> u128 {
+        let inner = self.inner.lock();
+        (inner.max as u128 + 1).saturating_sub(inner.min as u128)
+    }
+
+    /// Returns the number of nodes currently in the radix tree, including
+    /// the root.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// assert_eq!(ida.node_count(), 1);
+    ///
+    /// let id = ida.alloc().unwrap();
+    /// assert!(ida.node_count() > 1);
+    ///
+    /// // Freeing prunes the nodes that are no longer needed
+    /// ida.free(id);
+    /// assert_eq!(ida.node_count(), 1);
+    /// ```
+    pub fn node_count(&self) -> usize {
+        self.inner.lock().root.node_count()
+    }
+
+    /// Returns how many distinct first-level subtrees (blocks of 4096 IDs)
+    /// IDs have been allocated from since construction or the last
+    /// [`reset_profiling`](Self::reset_profiling).
+    ///
+    /// The count is cumulative: a block stays counted after its IDs are
+    /// freed. Compared with the number of allocations made, it shows how
+    /// widely a workload spreads over the ID space: a dense workload touches
+    /// one block per 4096 allocations, a scattered one up to one per
+    /// allocation. Every allocating method is counted; restoring a saved
+    /// state, with [`apply_bitset`](Self::apply_bitset) or
+    /// [`reset_to`](Self::reset_to) for example, is not.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    /// ida.set_search_start(10_000);
+    /// ida.alloc();
+    /// assert_eq!(ida.subtrees_touched(), 2);
+    ///
+    /// ida.reset_profiling();
+    /// assert_eq!(ida.subtrees_touched(), 0);
+    /// ```
+    pub fn subtrees_touched(&self) -> usize {
+        self.inner.lock().touched_blocks.len()
+    }
+
+    /// Clears the profiling counters, so that
+    /// [`subtrees_touched`](Self::subtrees_touched) starts again from zero.
+    pub fn reset_profiling(&self) {
+        self.inner.lock().touched_blocks.clear();
+    }
+
+    /// Returns the fraction of IDs below the highest allocated ID that are
+    /// free.
+    ///
+    /// Holes left by frees under the high-water mark make searches longer
+    /// and the tree larger than the number of allocated IDs needs. This is
+    /// `0.0` when the allocated IDs form one run from the lowest ID the
+    /// allocator hands out, and approaches `1.0` as they scatter. An empty
+    /// allocator reports `0.0`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..4 {
+    ///     ida.alloc();
+    /// }
+    /// assert_eq!(ida.fragmentation(), 0.0);
+    ///
+    /// ida.free(1);
+    /// assert_eq!(ida.fragmentation(), 0.25);
+    /// ```
+    pub fn fragmentation(&self) -> f64 {
+        let inner = self.inner.lock();
+        let highest = inner.root.highest_allocated(IDA_MAX_LEVELS - 1, 0);
+        inner.fragmentation_at(highest, inner.len)
+    }
+
+    /// Allocates the next ID, unless the allocator would be more
+    /// fragmented than `max_frag` afterwards.
+    ///
+    /// The ID is the one [`alloc`](Self::alloc) would hand out, following
+    /// the allocation policy, and the [`fragmentation`](Self::fragmentation)
+    /// its allocation would leave behind is computed before anything is
+    /// changed. A refusal therefore leaves the allocator untouched and tells
+    /// the caller that compacting, for example with
+    /// [`relocate_range`](Self::relocate_range), is due.
+    ///
+    /// # Errors
+    ///
+    /// - [`FragmentationError::TooHigh`] - If fragmentation would exceed
+    ///   `max_frag`
+    /// - [`FragmentationError::Exhausted`] - If no ID is free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{FragmentationError, Ida};
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    /// for id in 0..5 {
+    ///     ida.free(id);
+    /// }
+    ///
+    /// // Filling one of five holes leaves 4 of 10 IDs free
+    /// let err = ida.alloc_if_unfragmented(0.25).unwrap_err();
+    /// assert_eq!(err, FragmentationError::TooHigh(0.4));
+    /// assert_eq!(ida.alloc_if_unfragmented(0.5), Ok(0));
+    /// ```
+    pub fn alloc_if_unfragmented(&self, max_frag: f64) -> Result<usize, FragmentationError> {
+        let mut inner = self.inner.lock();
+        let id = inner.next_fresh().ok_or(FragmentationError::Exhausted)?;
+        let highest = inner
+            .root
+            .highest_allocated(IDA_MAX_LEVELS - 1, 0)
+            .max(Some(id));
+        let fragmentation = inner.fragmentation_at(highest, inner.len + 1);
+        if fragmentation > max_frag {
+            return Err(FragmentationError::TooHigh(fragmentation));
+        }
+        let allocated = inner.alloc();
+        debug_assert_eq!(allocated, Some(id));
+        self.notify_watchers(inner);
+        Ok(id)
+    }
+
+    /// Returns an estimate of the heap memory used by the radix tree, in
+    /// bytes.
+    ///
+    /// The estimate counts the size of every node and its entry in the
+    /// parent's child map. It does not account for the internal bookkeeping
+    /// of the allocator or for the global allocator's own overhead. Nodes
+    /// shared with a [`fork_cow`](Self::fork_cow) fork are counted in full by
+    /// both allocators.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// let empty = ida.memory_usage();
+    ///
+    /// ida.alloc();
+    /// assert!(ida.memory_usage() > empty);
+    /// ```
+    pub fn memory_usage(&self) -> usize {
+        self.node_count() * IDA_NODE_BYTES
+    }
+
+    /// Copies the allocation state within `range` into a new allocator,
+    /// leaving this one unchanged.
+    ///
+    /// If `rebase` is `true`, IDs are shifted down by `range.start` so the
+    /// new allocator is zero-based; otherwise they keep their original
+    /// values. The new allocator uses the default policy and is unbounded.
+    ///
+    /// See [`split_off_range`](Self::split_off_range) to move the IDs
+    /// instead of copying them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// let part = ida.extract_range(4..6, true);
+    /// assert_eq!(part.len(), 2);
+    /// assert!(part.is_allocated(0) && part.is_allocated(1));
+    ///
+    /// // The source still holds its IDs
+    /// assert_eq!(ida.len(), 10);
+    /// ```
+    pub fn extract_range(&self, range: Range<usize>, rebase: bool) -> Ida {
+        let inner = self.inner.lock();
+        Self::copy_range(&inner, &range, rebase)
+    }
+
+    /// Moves the allocation state within `range` into a new allocator,
+    /// freeing those IDs in this one.
+    ///
+    /// This behaves like [`extract_range`](Self::extract_range) followed by
+    /// freeing the range, but happens under a single lock acquisition so no
+    /// other thread can observe the IDs in both allocators or in neither.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// let part = ida.split_off_range(8..10, false);
+    /// assert!(part.is_allocated(8) && part.is_allocated(9));
+    /// assert!(!ida.is_allocated(8));
+    /// assert_eq!(ida.len(), 8);
+    /// ```
+    pub fn split_off_range(&self, range: Range<usize>, rebase: bool) -> Ida {
+        let mut inner = self.inner.lock();
+        let part = Self::copy_range(&inner, &range, rebase);
+        if !range.is_empty() {
+            inner.free_range(range.start, range.end - 1);
+        }
+        self.notify_watchers(inner);
+        part
+    }
+
+    fn copy_range(inner: &IdaInner, range: &Range<usize>, rebase: bool) -> Ida {
+        let part = Ida::new();
+        if range.is_empty() {
+            return part;
+        }
+        let offset = if rebase { range.start } else { 0 };
+        {
+            let mut dest = part.inner.lock();
+            inner.root.for_each_leaf_in(
+                IDA_MAX_LEVELS - 1,
+                0,
+                range.start,
+                range.end - 1,
+                &mut |leaf_base, mut bits| {
+                    while bits != 0 {
+                        let id = (leaf_base | bits.trailing_zeros() as usize) - offset;
+                        dest.root.set(id, IDA_MAX_LEVELS - 1);
+                        dest.len += 1;
+                        bits &= bits - 1;
+                    }
+                },
+            );
+        }
+        part
+    }
+
+    /// Returns the base ID of every leaf that is present in the tree but has
+    /// no IDs allocated, in ascending order.
+    ///
+    /// These are the leaves whose memory [`compact`](Self::compact) would
+    /// reclaim. A higher-level page allocator can use this to decide whether
+    /// a reclamation pass is worthwhile. Nothing is freed by this call.
+    ///
+    /// The leaves are collected under the lock, so the returned iterator does
+    /// not block other threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// // Pre-building the leftmost path leaves an empty leaf behind
+    /// let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+    /// assert_eq!(ida.free_pages().collect::<Vec<_>>(), vec![0]);
+    ///
+    /// ida.compact();
+    /// assert_eq!(ida.free_pages().count(), 0);
+    /// ```
+    pub fn free_pages(&self) -> impl Iterator<Item = usize> {
+        let inner = self.inner.lock();
+        let mut pages = Vec::new();
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, bitmap| {
+                if bitmap == 0 {
+                    pages.push(base);
+                }
+            });
+        pages.into_iter()
+    }
+
+    /// Returns the base ID of every leaf whose 64 IDs are all allocated, in
+    /// ascending order.
+    ///
+    /// Saturated leaves show where allocation is concentrated, which is
+    /// useful for a capacity heatmap or for deciding where to spread new
+    /// allocations. The leaves are collected under the lock, so the
+    /// returned iterator does not block other threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..100 {
+    ///     ida.alloc();
+    /// }
+    /// assert_eq!(ida.full_leaves().collect::<Vec<_>>(), vec![0]);
+    /// ```
+    pub fn full_leaves(&self) -> impl Iterator<Item = usize> {
+        let inner = self.inner.lock();
+        let mut leaves = Vec::new();
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, bitmap| {
+                if bitmap == u64::MAX {
+                    leaves.push(base);
+                }
+            });
+        leaves.into_iter()
+    }
+
+    /// Returns the base ID of the partially filled leaf with the most IDs
+    /// allocated.
+    ///
+    /// Only leaves with at least one allocated and at least one free ID are
+    /// considered; ties go to the lowest leaf. Packing new allocations into
+    /// this leaf, for example by passing its base to
+    /// [`set_search_start`](Self::set_search_start), keeps related IDs
+    /// together.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for id in [1, 64, 65, 66, 128] {
+    ///     ida.alloc_at(id).unwrap();
+    /// }
+    /// assert_eq!(ida.densest_leaf(), Some(64));
+    /// assert_eq!(ida.emptiest_partial_leaf(), Some(0));
+    /// ```
+    pub fn densest_leaf(&self) -> Option<usize> {
+        self.partial_leaf_by(|best, count| count > best)
+    }
+
+    /// Returns the base ID of the partially filled leaf with the fewest IDs
+    /// allocated.
+    ///
+    /// Only leaves with at least one allocated and at least one free ID are
+    /// considered; ties go to the lowest leaf. Directing new allocations
+    /// here spreads IDs across leaves.
+    pub fn emptiest_partial_leaf(&self) -> Option<usize> {
+        self.partial_leaf_by(|best, count| count < best)
+    }
+
+    /// Returns the base of the first partially filled leaf whose popcount
+    /// beats every earlier one according to `better(best, count)`.
+    fn partial_leaf_by(&self, better: impl Fn(u32, u32) -> bool) -> Option<usize> {
+        let inner = self.inner.lock();
+        let mut best: Option<(usize, u32)> = None;
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, bitmap| {
+                if bitmap == 0 || bitmap == u64::MAX {
+                    return;
+                }
+                let count = bitmap.count_ones();
+                if best.is_none_or(|(_, best)| better(best, count)) {
+                    best = Some((base, count));
+                }
+            });
+        best.map(|(base, _)| base)
+    }
+
+    /// Returns the allocated IDs as `(base, bits)` chunks of
+    /// `2^chunk_bits` IDs each, in ascending order of `base`.
+    ///
+    /// Each `base` is a multiple of the chunk size and only chunks with at
+    /// least one allocated ID are yielded. For chunks of up to 64 IDs
+    /// (`chunk_bits <= 6`), bit `i` of `bits` is set if `base + i` is
+    /// allocated; `chunk_bits == 6` yields exactly the tree's leaf bitmaps.
+    /// Larger chunks no longer fit one bit per ID, so each bit summarises a
+    /// block of `2^(chunk_bits - 6)` IDs instead and is set if any of them is
+    /// allocated.
+    ///
+    /// The chunks are collected under the lock, so the returned iterator does
+    /// not block other threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_bits` is larger than the number of bits in a `usize`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(1).unwrap();
+    /// ida.alloc_at(9).unwrap();
+    /// ida.alloc_at(200).unwrap();
+    ///
+    /// let chunks: Vec<_> = ida.iter_chunks(3).collect();
+    /// assert_eq!(chunks, vec![(0, 0b10), (8, 0b10), (200, 0b1)]);
+    ///
+    /// // With 256-ID chunks, each bit covers 4 IDs.
+    /// let chunks: Vec<_> = ida.iter_chunks(8).collect();
+    /// assert_eq!(chunks, vec![(0, 0b101 | 1 << 50)]);
+    /// ```
+    #[track_caller]
+    pub fn iter_chunks(&self, chunk_bits: usize) -> impl Iterator<Item = (usize, u64)> {
+        assert!(
+            chunk_bits <= IDA_ID_BITS,
+            "chunk_bits must not exceed the width of usize"
+        );
+        let chunk_base = |id: usize| match chunk_bits {
+            IDA_ID_BITS => 0,
+            bits => id & !((1 << bits) - 1),
+        };
+
+        let inner = self.inner.lock();
+        let mut chunks: Vec<(usize, u64)> = Vec::new();
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |leaf_base, bitmap| {
+                if bitmap == 0 {
+                    return;
+                }
+                if chunk_bits <= IDA_SHIFT {
+                    // Split the leaf into several chunks.
+                    let width = 1 << chunk_bits;
+                    let mask = u64::MAX >> (IDA_BITMAP_BITS - width);
+                    for offset in (0..IDA_BITMAP_BITS).step_by(width) {
+                        let bits = (bitmap >> offset) & mask;
+                        if bits != 0 {
+                            chunks.push((leaf_base + offset, bits));
+                        }
+                    }
+                    return;
+                }
+
+                // Fold the leaf into a chunk spanning several leaves, one bit
+                // per block of `2^block_bits` IDs.
+                let block_bits = chunk_bits - IDA_SHIFT;
+                let base = chunk_base(leaf_base);
+                let mut summary = 0;
+                if block_bits >= IDA_SHIFT {
+                    summary |= 1 << ((leaf_base - base) >> block_bits);
+                } else {
+                    let width = 1 << block_bits;
+                    let mask = u64::MAX >> (IDA_BITMAP_BITS - width);
+                    for offset in (0..IDA_BITMAP_BITS).step_by(width) {
+                        if (bitmap >> offset) & mask != 0 {
+                            summary |= 1 << ((leaf_base + offset - base) >> block_bits);
+                        }
+                    }
+                }
+                match chunks.last_mut() {
+                    Some((last, bits)) if *last == base => *bits |= summary,
+                    _ => chunks.push((base, summary)),
+                }
+            });
+        chunks.into_iter()
+    }
+
+    /// Removes every node in the tree that no longer holds any allocated IDs.
+    ///
+    /// [`free`](Self::free) already prunes the nodes along the path of the
+    /// freed ID, so this is mainly useful after operations that leave empty
+    /// nodes behind, such as [`IdaBuilder::prealloc_depth`]. The allocation
+    /// state is unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+    /// assert!(ida.node_count() > 1);
+    ///
+    /// ida.compact();
+    /// assert_eq!(ida.node_count(), 1);
+    /// ```
+    pub fn compact(&self) {
+        self.inner.lock().root.prune();
+    }
+
+    /// Returns all allocated IDs in ascending order.
+    ///
+    /// The IDs are copied under the lock, which is released before this
+    /// method returns. The result is a consistent point-in-time view, and
+    /// iterating over it never blocks other threads, so it is safe to
+    /// allocate or free IDs while walking it. The cost is an allocation
+    /// proportional to the number of allocated IDs.
+    ///
+    /// Use [`iter_locked`](Self::iter_locked) to walk the live tree without
+    /// copying.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// // Freeing while iterating is fine, the snapshot is unaffected
+    /// for id in ida.snapshot() {
+    ///     ida.free(id);
+    /// }
+    /// assert!(ida.is_empty());
+    /// ```
+    pub fn snapshot(&self) -> Vec<usize> {
+        let inner = self.inner.lock();
+        let mut ids = Vec::with_capacity(inner.len);
+        inner
+            .root
+            .for_each(IDA_MAX_LEVELS - 1, 0, &mut |id| ids.push(id));
+        ids
+    }
+
+    /// Returns a [`snapshot`](Self::snapshot) of the allocated IDs as a
+    /// shared, immutable slice.
+    ///
+    /// Cloning the returned `Arc` is cheap, so one snapshot can be handed to
+    /// any number of reader threads without walking the tree again or
+    /// copying the IDs per reader. Every clone sees the same point-in-time
+    /// view, however the allocator changes afterwards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    /// use std::sync::Arc;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    /// ida.alloc();
+    ///
+    /// let shared = ida.snapshot_shared();
+    /// let reader = Arc::clone(&shared);
+    /// ida.free(0);
+    ///
+    /// assert_eq!(&reader[..], &[0, 1]);
+    /// ```
+    pub fn snapshot_shared(&self) -> Arc<[usize]> {
+        Arc::from(self.snapshot())
+    }
+
+    /// Returns several statistics about the allocator, all taken from the
+    /// same point in time.
+    ///
+    /// Calling [`len`](Self::len), [`node_count`](Self::node_count) and so
+    /// on one after another can give mutually inconsistent answers if other
+    /// threads are allocating in between. This computes everything under a
+    /// single lock acquisition, in one walk over the leaves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..10 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(4);
+    ///
+    /// let stats = ida.snapshot_stats();
+    /// assert_eq!(stats.len, 9);
+    /// assert_eq!(stats.highest_allocated, Some(9));
+    /// assert_eq!(stats.lowest_free, Some(4));
+    /// assert_eq!(stats.allocated_runs, 2);
+    /// ```
+    pub fn snapshot_stats(&self) -> IdaSnapshot {
+        let inner = self.inner.lock();
+        let mut highest_allocated = None;
+        let mut allocated_runs = 0;
+        let mut run_end: Option<usize> = None;
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, bitmap| {
+                if bitmap == 0 {
+                    return;
+                }
+                // A run starts at each set bit whose predecessor is clear,
+                // except that bit 0 continues a run ending the previous leaf.
+                let starts = bitmap & !(bitmap << 1);
+                allocated_runs += starts.count_ones() as usize;
+                if starts & 1 == 1 && run_end.is_some_and(|end| end + 1 == base) {
+                    allocated_runs -= 1;
+                }
+                let last = base + (IDA_BITMAP_BITS - 1 - bitmap.leading_zeros() as usize);
+                highest_allocated = Some(last);
+                run_end = Some(last);
+            });
+
+        IdaSnapshot {
+            len: inner.len,
+            highest_allocated,
+            lowest_free: inner.find_free(0, inner.max),
+            allocated_runs,
+            node_count: inner.root.node_count(),
+        }
+    }
+
+    /// Returns a health summary of the allocator, all taken from the same
+    /// point in time.
+    ///
+    /// This is meant for diagnostics endpoints and periodic dumps. Like
+    /// [`snapshot_stats`](Self::snapshot_stats), everything is computed
+    /// under a single lock acquisition, with one walk over the leaves
+    /// finding the peak and every gap, so the fields agree with each other
+    /// even while other threads are allocating. Gaps are the free runs
+    /// between the lowest ID the allocator hands out and the highest
+    /// allocated ID; the free space above the peak is not a gap.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for id in [0, 1, 5, 6, 7, 9] {
+    ///     ida.alloc_at(id).unwrap();
+    /// }
+    ///
+    /// let report = ida.health_report();
+    /// assert_eq!(report.len, 6);
+    /// assert_eq!(report.peak, Some(9));
+    /// assert_eq!(report.longest_free_run, 3);
+    /// assert_eq!(report.largest_gaps, vec![(2, 3), (8, 1)]);
+    /// assert_eq!(report.fragmentation, 0.4);
+    /// ```
+    pub fn health_report(&self) -> HealthReport {
+        let inner = self.inner.lock();
+        let mut peak = None;
+        let mut largest_gaps: Vec<(usize, usize)> = Vec::new();
+        // The first ID after the last allocated run seen so far.
+        let mut next = inner.min;
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, mut bits| {
+                while bits != 0 {
+                    let first = bits.trailing_zeros() as usize;
+                    let run = (!(bits >> first)).trailing_zeros() as usize;
+                    let start = base + first;
+                    if start > next {
+                        let len = start - next;
+                        let at = largest_gaps.partition_point(|&(_, longer)| longer >= len);
+                        if at < HEALTH_REPORT_GAPS {
+                            largest_gaps.insert(at, (next, len));
+                            largest_gaps.truncate(HEALTH_REPORT_GAPS);
+                        }
+                    }
+                    peak = Some(start + (run - 1));
+                    next = (start + (run - 1)).wrapping_add(1);
+                    bits &= !bit_range_mask(first, first + run - 1);
+                }
+            });
+
+        let node_count = inner.root.node_count();
+        HealthReport {
+            len: inner.len,
+            peak,
+            node_count,
+            memory_usage: node_count * IDA_NODE_BYTES,
+            longest_free_run: largest_gaps.first().map_or(0, |&(_, len)| len),
+            fragmentation: inner.fragmentation_at(peak, inner.len),
+            largest_gaps,
+        }
+    }
+
+    /// Returns the allocated IDs coalesced into inclusive `(start, end)`
+    /// ranges, in ascending order.
+    ///
+    /// Consecutive IDs are merged into a single range even when they span
+    /// leaf or subtree boundaries, which makes this a compact way to report
+    /// or compare allocation state.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for id in [0, 1, 2, 5, 6, 9] {
+    ///     ida.alloc_at(id).unwrap();
+    /// }
+    /// assert_eq!(ida.as_ranges(), vec![(0, 2), (5, 6), (9, 9)]);
+    /// ```
+    pub fn as_ranges(&self) -> Vec<(usize, usize)> {
+        let inner = self.inner.lock();
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, mut bits| {
+                while bits != 0 {
+                    let first = bits.trailing_zeros() as usize;
+                    let run = (!(bits >> first)).trailing_zeros() as usize;
+                    let (start, end) = (base + first, base + first + (run - 1));
+                    match ranges.last_mut() {
+                        Some(last) if last.1 + 1 == start => last.1 = end,
+                        _ => ranges.push((start, end)),
+                    }
+                    bits &= !bit_range_mask(first, first + run - 1);
+                }
+            });
+        ranges
+    }
+
+    /// Returns the free gaps below the highest allocated ID as
+    /// `(start, len)` pairs, in ascending order.
+    ///
+    /// Each gap is a maximal run of free IDs, merged across leaf and subtree
+    /// boundaries. The free space above the highest allocated ID is not
+    /// included, so within the used region this is the complement of
+    /// [`as_ranges`](Self::as_ranges). The gaps are collected under the lock,
+    /// so the returned iterator does not block other threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for id in [2, 3, 7, 100] {
+    ///     ida.alloc_at(id).unwrap();
+    /// }
+    /// let gaps: Vec<_> = ida.iter_gaps().collect();
+    /// assert_eq!(gaps, vec![(0, 2), (4, 3), (8, 92)]);
+    /// ```
+    pub fn iter_gaps(&self) -> impl Iterator<Item = (usize, usize)> {
+        let inner = self.inner.lock();
+        let mut gaps = Vec::new();
+        // The first ID after the last allocated run seen so far.
+        let mut next = 0;
+        inner
+            .root
+            .for_each_leaf(IDA_MAX_LEVELS - 1, 0, &mut |base, mut bits| {
+                while bits != 0 {
+                    let first = bits.trailing_zeros() as usize;
+                    let run = (!(bits >> first)).trailing_zeros() as usize;
+                    let start = base + first;
+                    if start > next {
+                        gaps.push((next, start - next));
+                    }
+                    // Wraps to zero only after `usize::MAX`, which is the
+                    // last ID the walk can visit.
+                    next = (start + (run - 1)).wrapping_add(1);
+                    bits &= !bit_range_mask(first, first + run - 1);
+                }
+            });
+        gaps.into_iter()
+    }
+
+    /// Creates an allocator from a list of allocated IDs, such as one
+    /// produced by [`snapshot`](Self::snapshot), checking it along the way.
+    ///
+    /// This is meant for data read back from storage that may be corrupt.
+    /// The IDs must be in strictly ascending order, which catches
+    /// duplicated, reordered or truncated-and-spliced data before anything
+    /// is built, rather than producing an allocator that misbehaves later.
+    ///
+    /// # Errors
+    ///
+    /// - [`LoadError::NotAscending`] - If the IDs are not strictly ascending
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{Ida, LoadError};
+    ///
+    /// let ida = Ida::load_checked(&[0, 1, 5]).unwrap();
+    /// assert_eq!(ida.alloc(), Some(2));
+    ///
+    /// assert_eq!(
+    ///     Ida::load_checked(&[0, 5, 5]).unwrap_err(),
+    ///     LoadError::NotAscending(2)
+    /// );
+    /// ```
+    pub fn load_checked(ids: &[usize]) -> Result<Self, LoadError> {
+        if let Some(k) = ids.windows(2).position(|pair| pair[0] >= pair[1]) {
+            return Err(LoadError::NotAscending(k + 1));
+        }
+        let ida = Self::new();
+        {
+            let mut inner = ida.inner.lock();
+            for &id in ids {
+                inner.root.set(id, IDA_MAX_LEVELS - 1);
+            }
+            inner.len = ids.len();
+        }
+        Ok(ida)
+    }
+
+    /// Creates an allocator with every ID in the given inclusive
+    /// `(start, end)` ranges already allocated.
+    ///
+    /// Each range is reserved a leaf at a time rather than one ID at a time.
+    /// This is the inverse of [`as_ranges`](Self::as_ranges), and is handy
+    /// for rebuilding state that was stored or configured as ranges. The
+    /// ranges may be given in any order.
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::AlreadyAllocated`] - If two ranges overlap
+    /// - [`AllocError::OutOfRange`] - If a range's start is after its end
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{AllocError, Ida};
+    ///
+    /// let ida = Ida::from_ranges(&[(0, 2), (10, 11)]).unwrap();
+    /// assert_eq!(ida.len(), 5);
+    /// assert_eq!(ida.alloc(), Some(3));
+    ///
+    /// assert_eq!(
+    ///     Ida::from_ranges(&[(0, 5), (5, 6)]).unwrap_err(),
+    ///     AllocError::AlreadyAllocated
+    /// );
+    /// ```
+    pub fn from_ranges(ranges: &[(usize, usize)]) -> Result<Self, AllocError> {
+        let ida = Self::new();
+        {
+            let mut inner = ida.inner.lock();
+            for &(start, end) in ranges {
+                if start > end {
+                    return Err(AllocError::OutOfRange);
+                }
+                if inner
+                    .root
+                    .find_allocated(IDA_MAX_LEVELS - 1, 0, start, end)
+                    .is_some()
+                {
+                    return Err(AllocError::AlreadyAllocated);
+                }
+                inner.len += inner.root.set_range(IDA_MAX_LEVELS - 1, 0, start, end);
+            }
+        }
+        Ok(ida)
+    }
+
+    /// Encodes the allocated IDs into a compact, dependency-free binary
+    /// format.
+    ///
+    /// The allocated IDs are coalesced into runs as by
+    /// [`as_ranges`](Self::as_ranges) and written as the number of runs
+    /// followed by a `(start, len)` pair per run, in ascending order. Every
+    /// number is an unsigned LEB128 varint, so the format is little-endian
+    /// and independent of the platform's word size for IDs that fit in it.
+    /// Only the allocated IDs are stored; the policy, bound and other
+    /// settings are not.
+    ///
+    /// Use [`from_bytes`](Self::from_bytes) to decode it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for id in [0, 1, 2, 300] {
+    ///     ida.alloc_at(id).unwrap();
+    /// }
+    ///
+    /// let bytes = ida.to_bytes();
+    /// assert_eq!(bytes, [2, 0, 3, 0xac, 0x02, 1]);
+    /// assert_eq!(Ida::from_bytes(&bytes, 4).unwrap().snapshot(), ida.snapshot());
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let ranges = self.as_ranges();
+        let mut out = Vec::new();
+        write_varint(&mut out, ranges.len());
+        for (start, end) in ranges {
+            write_varint(&mut out, start);
+            write_varint(&mut out, end - start + 1);
+        }
+        out
+    }
+
+    /// Creates an allocator from the output of [`to_bytes`](Self::to_bytes),
+    /// holding at most `limit` IDs.
+    ///
+    /// The buffer must contain exactly one encoding whose runs are
+    /// non-empty and in ascending order without overlapping. The whole
+    /// buffer is decoded and checked before the tree is built, so a corrupt
+    /// buffer is rejected without allocating for its runs. A few bytes can
+    /// describe an enormous run, so `limit` should be the most IDs the
+    /// caller ever expects to store; the tree built for `limit` IDs can take
+    /// far more memory than the buffer.
+    ///
+    /// # Errors
+    ///
+    /// - [`DecodeError::Truncated`] - If the buffer ends early
+    /// - [`DecodeError::Overflow`] - If a number or run does not fit in a
+    ///   `usize`
+    /// - [`DecodeError::Malformed`] - If a run is empty, overlaps the one
+    ///   before it or is out of order
+    /// - [`DecodeError::TrailingBytes`] - If bytes follow the last run
+    /// - [`DecodeError::TooManyIds`] - If the runs hold more than `limit` IDs
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{DecodeError, Ida};
+    ///
+    /// let ida = Ida::from_bytes(&[1, 5, 2], 100).unwrap();
+    /// assert_eq!(ida.snapshot(), vec![5, 6]);
+    ///
+    /// assert_eq!(Ida::from_bytes(&[1, 5], 100).unwrap_err(), DecodeError::Truncated);
+    /// assert_eq!(Ida::from_bytes(&[1, 5, 2], 1).unwrap_err(), DecodeError::TooManyIds);
+    /// ```
+    pub fn from_bytes(bytes: &[u8], limit: usize) -> Result<Self, DecodeError> {
+        let mut bytes = bytes;
+        let count = read_varint(&mut bytes)?;
+        // The count is untrusted, so the runs are not reserved up front.
+        let mut runs = Vec::new();
+        let mut total: usize = 0;
+        let mut prev_end = None;
+        for _ in 0..count {
+            let start = read_varint(&mut bytes)?;
+            let len = read_varint(&mut bytes)?;
+            if len == 0 || prev_end.is_some_and(|prev_end| start <= prev_end) {
+                return Err(DecodeError::Malformed);
+            }
+            let end = start.checked_add(len - 1).ok_or(DecodeError::Overflow)?;
+            total = total
+                .checked_add(len)
+                .filter(|&total| total <= limit)
+                .ok_or(DecodeError::TooManyIds)?;
+            runs.push((start, end));
+            prev_end = Some(end);
+        }
+        if !bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        let ida = Self::new();
+        {
+            let mut inner = ida.inner.lock();
+            for (start, end) in runs {
+                inner.len += inner.root.set_range(IDA_MAX_LEVELS - 1, 0, start, end);
+            }
+        }
+        Ok(ida)
+    }
+
+    /// Rebuilds an allocator by applying a journal of operations in order.
+    ///
+    /// Each [`Op::Alloc`] allocates exactly the ID it names, as
+    /// [`alloc_at`](Self::alloc_at) would, and each [`Op::Free`] frees it.
+    /// Because every ID is recorded explicitly, the result does not depend
+    /// on the policy or search hint that chose the IDs originally, so a
+    /// journal of a live allocator always replays to the same state.
+    /// Allocating an ID that is already allocated, or freeing one that is
+    /// free, is a no-op.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::{Ida, Op};
+    ///
+    /// let ops = [Op::Alloc(0), Op::Alloc(1), Op::Free(0), Op::Alloc(7)];
+    /// let ida = Ida::replay(ops.into_iter());
+    /// assert_eq!(ida.snapshot(), vec![1, 7]);
+    /// ```
+    pub fn replay(ops: impl Iterator<Item = Op>) -> Self {
+        let ida = Self::new();
+        {
+            let mut inner = ida.inner.lock();
+            for op in ops {
+                match op {
+                    Op::Alloc(id) => {
+                        if inner.root.set(id, IDA_MAX_LEVELS - 1) {
+                            inner.len += 1;
+                        }
+                    }
+                    Op::Free(id) => {
+                        inner.free(id);
+                    }
+                }
+            }
+        }
+        ida
+    }
+
+    /// Replaces the entire allocation state with exactly the IDs in `ids`.
+    ///
+    /// Every ID not in `ids` is freed and every ID in it is allocated, all
+    /// under a single lock acquisition, so other threads see either the old
+    /// set or the new one and nothing in between. IDs above the allocator's
+    /// bound are ignored and duplicates are allowed. Freed IDs are not added
+    /// to the [`AllocPolicy::FifoReuse`] queue, which is cleared.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// ida.reset_to([5, 6, 7]);
+    /// assert_eq!(ida.snapshot(), vec![5, 6, 7]);
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    pub fn reset_to(&self, ids: impl IntoIterator<Item = usize>) {
+        // Collect first so that the iterator never runs under the lock.
+        let ids: Vec<usize> = ids.into_iter().collect();
+        let mut len = 0;
+        let mut inner = self.inner.lock();
+        let mut root = IdaNode::root_in(inner.root.alloc.clone());
+        for id in ids {
+            if id <= inner.max && !inner.is_excluded(id) && root.set(id, IDA_MAX_LEVELS - 1) {
+                len += 1;
+            }
+        }
+        inner.root = root;
+        inner.len = len;
+        inner.free_list.clear();
+        inner.tags.clear();
+        inner.pending.clear();
+        self.notify_watchers(inner);
+    }
+
+    /// Frees every allocated ID at once, returning how many there were.
+    ///
+    /// The whole tree is dropped in one go rather than freeing IDs one by
+    /// one, and the count comes from the allocator's length counter. As
+    /// with [`reset_to`](Self::reset_to), the freed IDs are not added to the
+    /// [`AllocPolicy::FifoReuse`] queue, which is cleared, and IDs pending a
+    /// commit from [`alloc_prepare`](Self::alloc_prepare) are freed too.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// assert_eq!(ida.free_all(), 3);
+    /// assert!(ida.is_empty());
+    /// assert_eq!(ida.free_all(), 0);
+    /// ```
+    pub fn free_all(&self) -> usize {
+        let mut inner = self.inner.lock();
+        let freed = inner.len;
+        inner.root = IdaNode::root_in(inner.root.alloc.clone());
+        inner.len = 0;
+        inner.free_list.clear();
+        inner.tags.clear();
+        inner.pending.clear();
+        inner.dirty_leaves.clear();
+        inner.drained |= freed > 0;
+        self.notify_watchers(inner);
+        freed
+    }
+
+    /// Creates an independent copy of this allocator that shares the tree
+    /// with it until either side changes it.
+    ///
+    /// Only the root node is copied, so forking costs the same regardless of
+    /// how many IDs are allocated. Afterwards each allocator copies a node
+    /// the first time it modifies it while the node is still shared, so
+    /// subtrees neither side touches are never duplicated. This makes it
+    /// cheap to try out a plan of allocations and throw it away.
+    ///
+    /// The fork keeps the policy, bound, search hint, reuse queue and tags.
+    /// Utilization callbacks are not carried over, and IDs pending in a
+    /// [`PreparedId`] are committed in the fork.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// let plan = ida.fork_cow();
+    /// assert_eq!(plan.alloc(), Some(3));
+    /// plan.free(0);
+    ///
+    /// // The original is unaffected
+    /// assert_eq!(ida.snapshot(), vec![0, 1, 2]);
+    /// assert_eq!(plan.snapshot(), vec![1, 2, 3]);
+    /// ```
+    pub fn fork_cow(&self) -> Ida {
+        let inner = self.inner.lock();
+        let fork = IdaInner {
+            root: inner.root.clone(),
+            len: inner.len,
+            policy: inner.policy,
+            free_list: inner.free_list.clone(),
+            search_start: inner.search_start,
+            max: inner.max,
+            next_version: inner.next_version,
+            watchers: Vec::new(),
+            tags: inner.tags.clone(),
+            pending: BTreeSet::new(),
+            blacklist: inner.blacklist.clone(),
+            warm_block: inner.warm_block,
+            lazy: inner.lazy,
+            dirty_leaves: inner.dirty_leaves.clone(),
+            recycle_delay: inner.recycle_delay,
+            alloc_count: inner.alloc_count,
+            cooling: inner.cooling.clone(),
+            cooling_queue: inner.cooling_queue.clone(),
+            empty_callbacks: Vec::new(),
+            drained: false,
+            class_windows: inner.class_windows.clone(),
+            touched_blocks: inner.touched_blocks.clone(),
+            stride: inner.stride,
+            min: inner.min,
+        };
+        Ida {
+            inner: Mutex::new(fork),
+        }
+    }
+
+    /// Returns an iterator over the allocated IDs in ascending order that
+    /// holds the lock for its whole lifetime.
+    ///
+    /// Holding the lock guarantees a consistent view without copying the
+    /// IDs, but every other operation on this allocator, from any thread,
+    /// blocks until the iterator is dropped. Calling another method on the
+    /// same allocator while the iterator is alive deadlocks, so keep its
+    /// scope short.
+    ///
+    /// Use [`snapshot`](Self::snapshot) if the IDs need to be processed
+    /// while the allocator is in use.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..3 {
+    ///     ida.alloc();
+    /// }
+    ///
+    /// let ids: Vec<usize> = ida.iter_locked().collect();
+    /// assert_eq!(ids, vec![0, 1, 2]);
+    /// ```
+    pub fn iter_locked(&self) -> LockedIter<'_> {
+        LockedIter {
+            inner: self.inner.lock(),
+            next: Some(0),
+        }
+    }
+
+    /// Returns the allocation state of `range` as packed 64-bit words.
+    ///
+    /// Word `k` covers IDs `range.start + k * 64 .. range.start + (k + 1) * 64`,
+    /// with bit `b` of the word set if ID `range.start + k * 64 + b` is
+    /// allocated. Absent subtrees produce zero words, and bits past the end
+    /// of the range are always clear. `range.start` does not need to be
+    /// aligned to a leaf.
+    ///
+    /// This is a fixed-layout format suited to wire protocols, and can be
+    /// loaded back with [`apply_bitset`](Self::apply_bitset).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..4 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(1);
+    ///
+    /// assert_eq!(ida.bitset_view(0..128), vec![0b1101, 0]);
+    /// assert_eq!(ida.bitset_view(2..4), vec![0b11]);
+    /// ```
+    pub fn bitset_view(&self, range: Range<usize>) -> Vec<u64> {
+        self.inner.lock().bitset_view(range)
+    }
+
+    /// Returns the leaf covering `id` as its base ID and raw bitmap.
+    ///
+    /// The base is `id` rounded down to a multiple of 64, and bit `b` of the
+    /// bitmap is set if ID `base + b` is allocated. A leaf that is not in
+    /// the tree reads as zero. Unlike [`bitset_view`](Self::bitset_view),
+    /// this reads a single word straight from the tree and never allocates,
+    /// which suits tooling that renders the tree one leaf at a time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(65).unwrap();
+    /// ida.alloc_at(70).unwrap();
+    ///
+    /// assert_eq!(ida.leaf_word(100), (64, (1 << 1) | (1 << 6)));
+    /// assert_eq!(ida.leaf_word(1000), (960, 0));
+    /// ```
+    pub fn leaf_word(&self, id: usize) -> (usize, u64) {
+        let base = id & !(IDA_BITMAP_BITS - 1);
+        let inner = self.inner.lock();
+        (base, inner.root.leaf_bitmap(id, IDA_MAX_LEVELS - 1))
+    }
+
+    /// Returns one byte per ID in `0..len`: `1` if the ID is allocated and
+    /// `0` if it is free.
+    ///
+    /// This dense layout suits C interfaces that expect a flag array, at the
+    /// cost of a byte per ID however sparse the allocations are. Prefer
+    /// [`bitset_view`](Self::bitset_view), which packs 64 IDs per word,
+    /// where the consumer allows it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(1).unwrap();
+    /// ida.alloc_at(3).unwrap();
+    ///
+    /// assert_eq!(ida.to_flags(5), vec![0, 1, 0, 1, 0]);
+    /// ```
+    pub fn to_flags(&self, len: usize) -> Vec<u8> {
+        let words = self.bitset_view(0..len);
+        (0..len)
+            .map(|id| (words[id / IDA_BITMAP_BITS] >> (id % IDA_BITMAP_BITS) & 1) as u8)
+            .collect()
+    }
+
+    /// Loads packed allocation words, as produced by
+    /// [`bitset_view`](Self::bitset_view), into the allocator.
+    ///
+    /// Word `k` describes IDs `start + k * 64 .. start + (k + 1) * 64`. If
+    /// `overwrite` is `false` the words are merged: every set bit is
+    /// allocated and clear bits leave the existing state alone. If
+    /// `overwrite` is `true` the covered range is replaced: set bits are
+    /// allocated and clear bits are freed.
+    ///
+    /// Bits for IDs beyond the allocator's bound, or beyond `usize::MAX`, are
+    /// ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc_at(64).unwrap();
+    ///
+    /// ida.apply_bitset(0, &[0b101, 0], false);
+    /// assert_eq!(ida.snapshot(), vec![0, 2, 64]);
+    ///
+    /// ida.apply_bitset(0, &[0b10, 0], true);
+    /// assert_eq!(ida.snapshot(), vec![1]);
+    /// ```
+    pub fn apply_bitset(&self, start: usize, words: &[u64], overwrite: bool) {
+        let mut inner = self.inner.lock();
+        inner.apply_bitset(start, words, overwrite);
+        self.notify_watchers(inner);
+    }
+
+    /// Moves the allocations in `old_start..old_start + len` to
+    /// `new_start..new_start + len`, preserving which offsets were allocated.
+    ///
+    /// The move happens under a single lock acquisition. The ranges may
+    /// overlap, since the source is moved out of the target's way. Tags and
+    /// pending commits move with their IDs, and the vacated IDs are not
+    /// queued for reuse. If the move fails, the allocator is left exactly as
+    /// it was.
+    ///
+    /// # Errors
+    ///
+    /// - [`RelocateError::Conflict`] - If an ID in the target range is
+    ///   allocated and is not part of the block being moved
+    /// - [`RelocateError::OutOfRange`] - If either range runs past the
+    ///   allocator's bound
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..4 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(1);
+    ///
+    /// ida.relocate_range(0, 100, 4).unwrap();
+    /// assert_eq!(ida.snapshot(), vec![100, 102, 103]);
+    /// ```
+    pub fn relocate_range(
+        &self,
+        old_start: usize,
+        new_start: usize,
+        len: usize,
+    ) -> Result<(), RelocateError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock();
+        let (Some(old_end), Some(new_end)) = (
+            old_start.checked_add(len - 1),
+            new_start.checked_add(len - 1),
+        ) else {
+            return Err(RelocateError::OutOfRange);
+        };
+        if old_end > inner.max || new_end > inner.max || new_start < inner.min {
+            return Err(RelocateError::OutOfRange);
+        }
+
+        // Only the part of the target outside the source can conflict, as
+        // the source is moved out of the way.
+        let below = (new_start < old_start).then(|| (new_start, new_end.min(old_start - 1)));
+        let above = (new_end > old_end).then(|| (new_start.max(old_end + 1), new_end));
+        let conflict = below
+            .into_iter()
+            .chain(above)
+            .find_map(|(lo, hi)| inner.root.find_allocated(IDA_MAX_LEVELS - 1, 0, lo, hi));
+        if let Some(id) = conflict {
+            return Err(RelocateError::Conflict(id));
+        }
+
+        // The IDs change places rather than being freed, so they are not
+        // queued for reuse or cooled, and their tags and pending commits go
+        // with them.
+        let words = inner.bitset_view(old_start..old_end + 1);
+        let mut moved = 0;
+        inner
+            .root
+            .free_range(IDA_MAX_LEVELS - 1, 0, old_start, old_end, &mut |_, bits| {
+                moved += bits.count_ones() as usize
+            });
+        inner.len -= moved;
+        inner.apply_bitset(new_start, &words, false);
+        let tagged: Vec<usize> = inner
+            .tags
+            .range(old_start..=old_end)
+            .map(|(&id, _)| id)
+            .collect();
+        let tags: Vec<(usize, u16)> = tagged
+            .into_iter()
+            .filter_map(|id| Some((id - old_start + new_start, inner.tags.remove(&id)?)))
+            .collect();
+        inner.tags.extend(tags);
+        let pending: Vec<usize> = inner.pending.range(old_start..=old_end).copied().collect();
+        for &id in &pending {
+            inner.pending.remove(&id);
+        }
+        inner
+            .pending
+            .extend(pending.into_iter().map(|id| id - old_start + new_start));
+        self.notify_watchers(inner);
+        Ok(())
+    }
+
+    /// Allocates every ID that is allocated in `other`, making this
+    /// allocator hold the union of both sets.
+    ///
+    /// The trees are merged a leaf bitmap at a time, and subtrees only
+    /// `other` has are shared copy-on-write rather than copied, so this is
+    /// far cheaper than reserving the IDs one by one. IDs allocated in both
+    /// are fine and stay allocated. IDs of `other` that lie outside this
+    /// allocator's span or on its blacklist are left out. `other` is not
+    /// modified.
+    ///
+    /// Both allocators are locked for the duration of the call, in a
+    /// consistent order so that concurrent unions cannot deadlock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let a = Ida::from_ranges(&[(0, 9)]).unwrap();
+    /// let b = Ida::from_ranges(&[(5, 14), (100, 100)]).unwrap();
+    ///
+    /// a.union_assign(&b);
+    /// assert_eq!(a.as_ranges(), vec![(0, 14), (100, 100)]);
+    /// assert_eq!(a.len(), 16);
+    /// ```
+    pub fn union_assign(&self, other: &Ida) {
+        if core::ptr::eq(self, other) {
+            return;
+        }
+
+        // Always lock the allocator at the lower address first.
+        let (mut inner, other_inner) = if (self as *const Ida) < (other as *const Ida) {
+            let inner = self.inner.lock();
+            (inner, other.inner.lock())
+        } else {
+            let other_inner = other.inner.lock();
+            (self.inner.lock(), other_inner)
+        };
+        let mut added = inner.root.union_from(&other_inner.root, IDA_MAX_LEVELS - 1);
+        drop(other_inner);
+
+        // Drop whatever the union brought in that this allocator may never
+        // hand out. None of it was allocated before, so nothing is queued.
+        if inner.max != usize::MAX {
+            let max = inner.max;
+            inner.root.free_range(
+                IDA_MAX_LEVELS - 1,
+                0,
+                max + 1,
+                usize::MAX,
+                &mut |_, bits| {
+                    added -= bits.count_ones() as usize;
+                },
+            );
+        }
+        if inner.min > 0 {
+            let min = inner.min;
+            inner
+                .root
+                .free_range(IDA_MAX_LEVELS - 1, 0, 0, min - 1, &mut |_, bits| {
+                    added -= bits.count_ones() as usize;
+                });
+        }
+        let blacklist = mem::take(&mut inner.blacklist);
+        for &id in &blacklist {
+            if inner.root.free(id, IDA_MAX_LEVELS - 1, true) {
+                added -= 1;
+            }
+        }
+        inner.blacklist = blacklist;
+        inner.len += added;
+        self.notify_watchers(inner);
+    }
+
+    /// Compares two allocators, returning `(added, removed)`.
+    ///
+    /// `added` holds the IDs allocated in `b` but not in `a`, and `removed`
+    /// holds the IDs allocated in `a` but not in `b`, both in ascending
+    /// order. Taking `a` as an earlier snapshot and `b` as a later one, this
+    /// is the change between them.
+    ///
+    /// The trees are walked in parallel, skipping subtrees that are absent
+    /// from both or full in both, which is much cheaper than comparing two
+    /// full ID lists. Both allocators are locked for the duration of the
+    /// call, in a consistent order so that concurrent diffs cannot deadlock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let before = Ida::new();
+    /// let after = Ida::new();
+    /// for id in [1, 2, 3] {
+    ///     before.alloc_at(id).unwrap();
+    /// }
+    /// for id in [2, 3, 4] {
+    ///     after.alloc_at(id).unwrap();
+    /// }
+    ///
+    /// assert_eq!(Ida::diff(&before, &after), (vec![4], vec![1]));
+    /// ```
+    pub fn diff(a: &Ida, b: &Ida) -> (Vec<usize>, Vec<usize>) {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        if core::ptr::eq(a, b) {
+            return (added, removed);
+        }
+
+        // Always lock the allocator at the lower address first.
+        let (a_inner, b_inner) = if (a as *const Ida) < (b as *const Ida) {
+            let a_inner = a.inner.lock();
+            (a_inner, b.inner.lock())
+        } else {
+            let b_inner = b.inner.lock();
+            (a.inner.lock(), b_inner)
+        };
+        IdaNode::diff_into(
+            Some(&a_inner.root),
+            Some(&b_inner.root),
+            IDA_MAX_LEVELS - 1,
+            0,
+            &mut added,
+            &mut removed,
+        );
+        (added, removed)
+    }
+
+    /// Reclaims as much bookkeeping memory as possible without changing the
+    /// allocation state, returning an estimate of the bytes released.
+    ///
+    /// This prunes empty tree nodes as [`compact`](Self::compact) does, and
+    /// also releases spare capacity in the queue of freed IDs kept by
+    /// [`AllocPolicy::FifoReuse`] and in the queue of IDs waiting out the
+    /// [`recycle_delay`](IdaBuilder::recycle_delay), either of which can stay
+    /// large after a burst of frees. Long-lived allocators that ballooned
+    /// during a spike can call this when under memory pressure.
+    ///
+    /// Tags and the leaves queued for [`maintain`](Self::maintain) are left
+    /// alone: both record state that is still live.
+    ///
+    /// The returned figure uses the same estimate as
+    /// [`memory_usage`](Self::memory_usage) for tree nodes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+    /// let before = ida.memory_usage();
+    ///
+    /// assert!(ida.reclaim_memory() > 0);
+    /// assert!(ida.memory_usage() < before);
+    /// ```
+    pub fn reclaim_memory(&self) -> usize {
+        let mut inner = self.inner.lock();
+
+        let nodes_before = inner.root.node_count();
+        inner.root.prune();
+        let nodes_freed = nodes_before - inner.root.node_count();
+
+        let queue_before = inner.free_list.capacity();
+        inner.free_list.shrink_to_fit();
+        let slots_freed = queue_before - inner.free_list.capacity();
+
+        // Entries for IDs that were freed again later are already dead.
+        let IdaInner {
+            cooling,
+            cooling_queue,
+            ..
+        } = &mut *inner;
+        let cooling_before = cooling_queue.capacity();
+        cooling_queue.retain(|(id, freed_at)| cooling.get(id) == Some(freed_at));
+        cooling_queue.shrink_to_fit();
+        let cooling_freed = cooling_before - cooling_queue.capacity();
+
+        nodes_freed * IDA_NODE_BYTES
+            + slots_freed * size_of::<usize>()
+            + cooling_freed * size_of::<(usize, u64)>()
+    }
+
+    /// Counts the allocated IDs by walking the tree.
+    ///
+    /// Unlike [`len`](Self::len), which reads a maintained counter, this
+    /// recomputes the total from scratch by summing the popcount of every
+    /// leaf bitmap. It is O(n) in the number of tree nodes and is mainly
+    /// useful for cross-checking the counter against the actual tree state.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// for _ in 0..100 {
+    ///     ida.alloc();
+    /// }
+    /// ida.free(42);
+    ///
+    /// assert_eq!(ida.count_by_walk(), 99);
+    /// assert_eq!(ida.count_by_walk(), ida.len());
+    /// ```
+    pub fn count_by_walk(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.root.count_ones(IDA_MAX_LEVELS - 1)
+    }
+}
+
+#[cfg(feature = "roaring")]
+impl Ida {
+    /// Exports all allocated IDs into a [`roaring::RoaringBitmap`].
+    ///
+    /// Roaring bitmaps hold `u32` values, so this fails if any allocated ID
+    /// does not fit in a `u32`.
+    ///
+    /// This method is only available with the `roaring` feature enabled.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(id)` with the first allocated ID that exceeds `u32::MAX`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::new();
+    /// ida.alloc();
+    /// ida.alloc();
+    ///
+    /// let bitmap = ida.to_roaring().unwrap();
+    /// assert_eq!(bitmap.len(), 2);
+    /// assert!(bitmap.contains(1));
+    /// ```
+    pub fn to_roaring(&self) -> Result<roaring::RoaringBitmap, usize> {
+        let inner = self.inner.lock();
+        let mut bitmap = roaring::RoaringBitmap::new();
+        let mut too_large = None;
+        inner.root.for_each(IDA_MAX_LEVELS - 1, 0, &mut |id| {
+            if too_large.is_some() {
+                return;
+            }
+            match u32::try_from(id) {
+                Ok(value) => {
+                    bitmap.insert(value);
+                }
+                Err(_) => too_large = Some(id),
+            }
+        });
+        match too_large {
+            Some(id) => Err(id),
+            None => Ok(bitmap),
+        }
+    }
+
+    /// Creates a new allocator with every ID in `bitmap` marked as allocated.
+    ///
+    /// This method is only available with the `roaring` feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    /// use roaring::RoaringBitmap;
+    ///
+    /// let bitmap: RoaringBitmap = [0, 2].into_iter().collect();
+    /// let ida = Ida::from_roaring(&bitmap);
+    ///
+    /// assert!(ida.is_allocated(2));
+    /// assert_eq!(ida.alloc(), Some(1));
+    /// ```
+    pub fn from_roaring(bitmap: &roaring::RoaringBitmap) -> Self {
+        let ida = Self::new();
+        {
+            let mut inner = ida.inner.lock();
+            for value in bitmap {
+                inner.root.set(value as usize, IDA_MAX_LEVELS - 1);
+            }
+            inner.len = bitmap.len() as usize;
+        }
+        ida
+    }
+}
+
+impl Default for Ida {
+    /// Creates a new ID allocator using the default configuration.
+    ///
+    /// This is equivalent to calling [`Ida::new()`](Self::new).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::Ida;
+    ///
+    /// let ida = Ida::default();
+    /// assert_eq!(ida.alloc(), Some(0));
+    /// ```
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    impl Ida {
+        /// Returns whether both allocators have node-for-node identical
+        /// trees, including empty nodes and internal full-bits.
+        ///
+        /// This is stricter than comparing allocated IDs and catches nodes
+        /// left behind by missed pruning.
+        fn deep_equal_tree(&self, other: &Ida) -> bool {
+            if core::ptr::eq(self, other) {
+                return true;
+            }
+            let (a, b) = if (self as *const Ida) < (other as *const Ida) {
+                let a = self.inner.lock();
+                (a, other.inner.lock())
+            } else {
+                let b = other.inner.lock();
+                (self.inner.lock(), b)
+            };
+            a.root == b.root
+        }
+    }
+
+    #[test]
+    fn test_alloc_and_free_simple() {
+        let ida = Ida::default();
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(2));
+
+        ida.free(1); // Free an ID in the middle.
+        assert_eq!(ida.alloc(), Some(1)); // Should be reused.
+
+        ida.free(0);
+        ida.free(2);
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(2));
+    }
+
+    #[test]
+    fn test_first_level_boundary() {
+        let ida = Ida::default();
+        // Allocate the first 64 IDs to fill the first leaf.
+        for i in 0..64 {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+
+        // The next allocation should cross the boundary into a new leaf.
+        assert_eq!(ida.alloc(), Some(64));
+        assert_eq!(ida.alloc(), Some(65));
+
+        // Free the boundary-crossing ID and ensure it's reused.
+        ida.free(64);
+        assert_eq!(ida.alloc(), Some(64));
+    }
+
+    #[test]
+    fn test_second_level_boundary() {
+        let ida = Ida::default();
+        let count = IDA_BITMAP_BITS * IDA_BITMAP_BITS; // 64 * 64 = 4096
+
+        // Allocate enough IDs to fill an entire first-level child node.
+        for i in 0..count {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+
+        // The next allocation should cross a major boundary.
+        assert_eq!(ida.alloc(), Some(count));
+
+        // Free it and ensure it's reused.
+        ida.free(count);
+        assert_eq!(ida.alloc(), Some(count));
+    }
+
+    #[test]
+    fn test_free_unallocated() {
+        let ida = Ida::default();
+        ida.free(100); // Free an ID that was never allocated.
+        assert_eq!(ida.alloc(), Some(0)); // The first ID should still be 0.
+    }
+
+    #[test]
+    fn test_stress_and_random_free() {
+        let ida = Ida::default();
+        let mut ids = alloc::vec::Vec::new();
+        let count = 10_000;
+
+        // 1. Allocate a large number of IDs.
+        for _ in 0..count {
+            if let Some(id) = ida.alloc() {
+                ids.push(id);
+            } else {
+                panic!("Allocator ran out of space unexpectedly.");
+            }
+        }
+
+        // 2. Free a random subset of those IDs.
+        let mut freed_ids = alloc::vec::Vec::new();
+        for (i, &id) in ids.iter().enumerate() {
+            if i % 3 == 0 || i % 7 == 0 {
+                // Arbitrary condition for freeing
+                ida.free(id);
+                freed_ids.push(id);
+            }
+        }
+
+        // Sort the freed IDs to make reallocation predictable.
+        freed_ids.sort();
+
+        // 3. Allocate new IDs and check if they are the same as the freed ones.
+        for &expected_id in &freed_ids {
+            assert_eq!(ida.alloc(), Some(expected_id));
+        }
+
+        // 4. Free all remaining IDs.
+        for (i, &id) in ids.iter().enumerate() {
+            if !(i % 3 == 0 || i % 7 == 0) {
+                ida.free(id);
+            }
+        }
+        for id in freed_ids {
+            ida.free(id);
+        }
+
+        // 5. Check that the allocator is empty and starts from 0 again.
+        assert_eq!(ida.alloc(), Some(0));
+    }
+
+    #[test]
+    fn test_is_allocated() {
+        let ida = Ida::default();
+        assert!(!ida.is_allocated(0));
+        assert!(!ida.is_allocated(100));
+
+        let id1 = ida.alloc().unwrap();
+        let id2 = ida.alloc().unwrap();
+
+        assert_eq!(id1, 0);
+        assert_eq!(id2, 1);
+
+        assert!(ida.is_allocated(0));
+        assert!(ida.is_allocated(1));
+        assert!(!ida.is_allocated(2));
+        assert!(!ida.is_allocated(100));
+
+        ida.free(1);
+        assert!(ida.is_allocated(0));
+        assert!(!ida.is_allocated(1));
+        assert!(!ida.is_allocated(2));
+    }
+
+    #[test]
+    fn test_debug_output() {
+        let ida = Ida::default();
+        ida.alloc();
+        ida.alloc();
+        // The exact output is not asserted as it's complex and implementation-dependent,
+        // but this test ensures the Debug trait is implemented and doesn't panic.
+        let _ = format!("{ida:?}");
+    }
+
+    #[test]
+    fn test_len_matches_count_by_walk() {
+        let ida = Ida::default();
+        let mut live = Vec::new();
+        // A small xorshift generator keeps the sequence deterministic.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..5_000 {
+            let r = next();
+            if live.is_empty() || r % 3 != 0 {
+                live.push(ida.alloc().unwrap());
+            } else {
+                let idx = (r as usize / 3) % live.len();
+                let id = live.swap_remove(idx);
+                ida.free(id);
+                // A double free must not drift the counter.
+                if r % 5 == 0 {
+                    ida.free(id);
+                }
+            }
+            assert_eq!(ida.len(), ida.count_by_walk());
+            assert_eq!(ida.len(), live.len());
+        }
+
+        for id in live {
+            ida.free(id);
+        }
+        assert_eq!(ida.len(), 0);
+        assert_eq!(ida.count_by_walk(), 0);
+    }
+
+    #[test]
+    fn test_fifo_reuse_policy() {
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(2));
+
+        ida.free(0);
+        ida.free(1);
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(1));
+
+        // Reverse free order is preserved rather than picking the lowest.
+        ida.free(2);
+        ida.free(0);
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(0));
+
+        // With nothing pending, allocation falls back to the tree.
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.len(), 4);
+    }
+
+    #[test]
+    fn test_fifo_reuse_ignores_double_free() {
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        ida.alloc();
+        ida.alloc();
+
+        ida.free(1);
+        ida.free(1);
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_iter_free_in() {
+        let ida = Ida::default();
+        for _ in 0..200 {
+            ida.alloc();
+        }
+        for id in [3, 63, 64, 65, 130, 199] {
+            ida.free(id);
+        }
+        // Allocate a sparse ID far away, leaving an absent subtree in between.
+        ida.alloc_at(5_000).unwrap();
+
+        for range in [0..300, 60..70, 190..4_200, 4_990..5_010, 10..10] {
+            let expected: Vec<usize> = range.clone().filter(|&id| !ida.is_allocated(id)).collect();
+            let actual: Vec<usize> = ida.iter_free_in(range).collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_alloc_spread() {
+        let ida = Ida::default();
+        // Leaf 0 is full, leaf 1 is nearly full, leaf 2 holds a single ID.
+        for _ in 0..(2 * IDA_BITMAP_BITS - 1) {
+            ida.alloc();
+        }
+        ida.alloc_at(130).unwrap();
+
+        let ids = ida.alloc_spread(4).unwrap();
+        assert_eq!(ids.len(), 4);
+
+        let mut leaves: Vec<usize> = ids.iter().map(|id| id / IDA_BITMAP_BITS).collect();
+        leaves.dedup();
+        assert_eq!(leaves.len(), 4, "IDs share a leaf: {ids:?}");
+        assert!(!leaves.contains(&0));
+
+        // Empty leaves come before the partially allocated leaf 2.
+        assert_eq!(ids, vec![192, 256, 320, 384]);
+        for &id in &ids {
+            assert!(ida.is_allocated(id));
+        }
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_search_start_hint() {
+        let ida = Ida::default();
+        for _ in 0..10 {
+            ida.alloc();
+        }
+        ida.free(3);
+
+        ida.set_search_start(100);
+        assert_eq!(ida.alloc(), Some(100));
+        assert_eq!(ida.alloc(), Some(101));
+
+        // IDs freed above the hint are found before ones below it.
+        ida.free(100);
+        assert_eq!(ida.alloc(), Some(100));
+
+        ida.hint_invalidate();
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), Some(10));
+    }
+
+    #[test]
+    fn test_search_start_falls_back_below_hint() {
+        let ida = Ida::default();
+        for _ in 0..4 {
+            ida.alloc();
+        }
+        ida.free(2);
+
+        // Exhaust everything at or above the hint.
+        ida.set_search_start(usize::MAX - 1);
+        assert_eq!(ida.alloc(), Some(usize::MAX - 1));
+        assert_eq!(ida.alloc(), Some(usize::MAX));
+
+        // Nothing is free above the hint, so the lower freed ID is returned.
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(4));
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_alloc_parity_interleaved() {
+        let ida = Ida::default();
+        for i in 0..100 {
+            assert_eq!(ida.alloc_parity(Parity::Even), Some(2 * i));
+            assert_eq!(ida.alloc_parity(Parity::Odd), Some(2 * i + 1));
+        }
+
+        ida.free(42);
+        ida.free(77);
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(77));
+        assert_eq!(ida.alloc_parity(Parity::Even), Some(42));
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(201));
+        assert_eq!(ida.alloc_parity(Parity::Even), Some(200));
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_alloc_parity_skips_one_sided_leaves() {
+        let ida = Ida::default();
+        // Fill every even ID in the first two leaves, leaving the odd ones free.
+        for _ in 0..IDA_BITMAP_BITS {
+            ida.alloc_parity(Parity::Even);
+        }
+        assert_eq!(ida.alloc_parity(Parity::Even), Some(2 * IDA_BITMAP_BITS));
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(1));
+    }
+
+    #[test]
+    fn test_prealloc_depth() {
+        let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+        assert_eq!(ida.node_count(), IDA_MAX_LEVELS);
+        assert!(ida.memory_usage() > Ida::new().memory_usage());
+
+        // The first allocation needs no new nodes.
+        let before = ida.memory_usage();
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.memory_usage(), before);
+
+        // Unused pre-built nodes are pruned once the path is freed.
+        ida.free(0);
+        assert_eq!(ida.node_count(), 1);
+    }
+
+    #[test]
+    fn test_prealloc_partial_depth() {
+        let ida = Ida::builder().prealloc_depth(3).build();
+        assert_eq!(ida.node_count(), 4);
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.node_count(), IDA_MAX_LEVELS);
+    }
+
+    #[test]
+    fn test_total_capacity() {
+        assert_eq!(
+            Ida::builder()
+                .max(u8::MAX as usize)
+                .build()
+                .total_capacity(),
+            256
+        );
+        assert_eq!(Ida::builder().max(1_000).build().total_capacity(), 1_001);
+        assert_eq!(Ida::builder().max(0).build().total_capacity(), 1);
+        assert_eq!(Ida::default().total_capacity(), 1u128 << usize::BITS);
+    }
+
+    #[test]
+    fn test_with_max_bounds_allocation() {
+        let ida = Ida::builder().max(100).build();
+        for i in 0..=100 {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+        assert_eq!(ida.alloc(), None);
+        assert_eq!(ida.alloc_parity(Parity::Odd), None);
+        assert_eq!(ida.alloc_spread(1), None);
+        assert_eq!(ida.iter_free_in(0..1_000).next(), None);
+
+        ida.free(99);
+        assert_eq!(ida.alloc_parity(Parity::Even), None);
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(99));
+        assert_eq!(ida.len(), 101);
+    }
+
+    #[test]
+    fn test_free_range_counted() {
+        let ida = Ida::default();
+        for _ in 0..5_000 {
+            ida.alloc();
+        }
+        // Punch holes inside the range that is about to be freed.
+        let holes = [10, 63, 64, 4_095, 4_096, 4_500];
+        for id in holes {
+            ida.free(id);
+        }
+
+        let freed = ida.free_range_counted(0, 4_600);
+        assert_eq!(freed, 4_600 - holes.len());
+        assert_eq!(ida.len(), 400);
+        assert_eq!(ida.len(), ida.count_by_walk());
+        assert!(!ida.is_allocated(4_599));
+        assert!(ida.is_allocated(4_600));
+
+        // The freed range is reusable from the bottom again.
+        assert_eq!(ida.alloc(), Some(0));
+
+        // Freeing an already-free range reports nothing.
+        assert_eq!(ida.free_range_counted(100, 200), 0);
+        assert_eq!(ida.free_range_counted(0, 0), 0);
+    }
+
+    #[test]
+    fn test_free_range_counted_prunes_nodes() {
+        let ida = Ida::default();
+        for _ in 0..10_000 {
+            ida.alloc();
+        }
+        assert_eq!(ida.free_range_counted(0, 10_000), 10_000);
+        assert_eq!(ida.node_count(), 1);
+        assert_eq!(ida.alloc(), Some(0));
+    }
+
+    #[test]
+    fn test_is_full() {
+        for max in [0, 63, 64, 200, 4_096] {
+            let ida = Ida::builder().max(max).build();
+            for _ in 0..max {
+                ida.alloc();
+                assert!(!ida.is_full());
+            }
+            ida.alloc();
+            assert!(ida.is_full(), "max = {max}");
+
+            ida.free(max / 2);
+            assert!(!ida.is_full());
+            ida.alloc();
+            assert!(ida.is_full());
+        }
+
+        let ida = Ida::default();
+        ida.alloc();
+        assert!(!ida.is_full());
+
+        // An ID waiting out the recycle delay cannot be allocated either.
+        let ida = Ida::builder().recycle_delay(10).max(3).build();
+        for _ in 0..4 {
+            ida.alloc();
+        }
+        ida.free(2);
+        assert_eq!(ida.alloc(), None);
+        assert!(ida.is_full());
+    }
+
+    #[test]
+    fn test_alloc_at() {
+        let ida = Ida::builder().max(100).build();
+        assert_eq!(ida.alloc_at(64), Ok(()));
+        assert_eq!(ida.alloc_at(64), Err(AllocError::AlreadyAllocated));
+        assert_eq!(ida.alloc_at(101), Err(AllocError::OutOfRange));
+        assert!(ida.is_allocated(64));
+        assert_eq!(ida.len(), 1);
+
+        ida.free(64);
+        assert_eq!(ida.alloc_at(64), Ok(()));
+    }
+
+    #[test]
+    fn test_alloc_where_rejects_multiples_of_ten() {
+        let ida = Ida::default();
+        let mut ids = Vec::new();
+        for _ in 0..30 {
+            ids.push(ida.alloc_where(|id| id % 10 != 0).unwrap());
+        }
+
+        let expected: Vec<usize> = (0..34).filter(|id| id % 10 != 0).collect();
+        assert_eq!(ids, expected[..30]);
+        // Rejected IDs were skipped, not allocated.
+        for id in [0, 10, 20, 30] {
+            assert!(!ida.is_allocated(id));
+        }
+        assert_eq!(ida.len(), 30);
+
+        ida.free(15);
+        assert_eq!(ida.alloc_where(|id| id % 10 != 0), Some(15));
+
+        let bounded = Ida::builder().max(20).build();
+        assert_eq!(bounded.alloc_where(|id| id > 20), None);
+        assert_eq!(bounded.alloc_where(|id| id == 20), Some(20));
+    }
+
+    #[test]
+    fn test_alloc_where_retries_after_race() {
+        let ida = Ida::default();
+        // The callback itself claims the candidate, simulating another
+        // thread winning the race between the check and the commit.
+        let mut stolen = false;
+        let id = ida.alloc_where(|id| {
+            if !stolen {
+                stolen = true;
+                ida.alloc_at(id).unwrap();
+            }
+            true
+        });
+        assert_eq!(id, Some(1));
+        assert!(ida.is_allocated(0));
+        assert_eq!(ida.len(), 2);
+    }
+
+    #[test]
+    fn test_extract_range_copies() {
+        let ida = Ida::default();
+        for _ in 0..5_000 {
+            ida.alloc();
+        }
+        ida.free(4_100);
+
+        let part = ida.extract_range(4_000..4_200, false);
+        assert_eq!(part.len(), 199);
+        assert!(part.is_allocated(4_000));
+        assert!(part.is_allocated(4_199));
+        assert!(!part.is_allocated(4_100));
+        assert!(!part.is_allocated(3_999));
+        assert!(!part.is_allocated(4_200));
+
+        let rebased = ida.extract_range(4_000..4_200, true);
+        assert_eq!(rebased.len(), 199);
+        assert!(rebased.is_allocated(0));
+        assert!(!rebased.is_allocated(100));
+        assert!(rebased.is_allocated(199));
+        assert_eq!(rebased.alloc(), Some(100));
+
+        // The source is left untouched.
+        assert_eq!(ida.len(), 4_999);
+        assert!(ida.is_allocated(4_000));
+    }
+
+    #[test]
+    fn test_split_off_range_moves() {
+        let ida = Ida::default();
+        for _ in 0..200 {
+            ida.alloc();
+        }
+
+        let part = ida.split_off_range(60..130, true);
+        assert_eq!(part.len(), 70);
+        assert_eq!(part.count_by_walk(), 70);
+        assert!(part.is_allocated(0) && part.is_allocated(69));
+        assert!(!part.is_allocated(70));
+
+        assert_eq!(ida.len(), 130);
+        assert_eq!(ida.count_by_walk(), 130);
+        assert!(ida.is_allocated(59) && ida.is_allocated(130));
+        assert_eq!(ida.alloc(), Some(60));
+
+        assert!(ida.split_off_range(10..10, false).is_empty());
+    }
+
+    #[test]
+    fn test_describe() {
+        let ida = Ida::default();
+        ida.alloc_at(4_097).unwrap();
+
+        let path = ida.describe(4_097);
+        assert_eq!(path.steps.len(), IDA_MAX_LEVELS);
+        assert!(path.allocated);
+        for (step, level) in path.steps.iter().zip((0..IDA_MAX_LEVELS).rev()) {
+            assert_eq!(step.level, level);
+            assert!(step.present);
+            let expected = match level {
+                2 => 1,
+                0 => 1,
+                _ => 0,
+            };
+            assert_eq!(step.index, expected, "level {level}");
+        }
+
+        // A neighbouring leaf that was never touched is absent.
+        let path = ida.describe(4_160);
+        assert!(!path.allocated);
+        let leaf = path.steps.last().unwrap();
+        assert_eq!((leaf.level, leaf.index, leaf.present), (0, 0, false));
+        assert!(path.steps[IDA_MAX_LEVELS - 2].present);
+    }
+
+    #[test]
+    fn test_exhaustion_of_small_space() {
+        let ida = Ida::builder().max(u8::MAX as usize).build();
+        for i in 0..=u8::MAX as usize {
+            assert_eq!(ida.alloc(), Some(i));
+        }
+        assert_eq!(ida.alloc(), None);
+        assert_eq!(ida.try_alloc(), Err(AllocError::Exhausted));
+        assert_eq!(ida.len(), 256);
+
+        ida.free(200);
+        assert_eq!(ida.try_alloc(), Ok(200));
+        assert_eq!(ida.alloc(), None);
+    }
+
+    #[test]
+    fn test_exhaustion_of_full_id_space() {
+        let ida = Ida::default();
+        // Mark everything below the last leaf as full, leaving only the two
+        // highest representable IDs free. Allocating all 2^64 IDs for real
+        // isn't practical, so the tree is built by hand.
+        {
+            let mut inner = ida.inner.lock();
+            let mut node = &mut inner.root;
+            for level in (1..IDA_MAX_LEVELS).rev() {
+                let last = (usize::MAX >> (level * IDA_SHIFT)) & (IDA_BITMAP_BITS - 1);
+                if last > 0 {
+                    node.bitmap |= bit_range_mask(0, last - 1);
+                }
+                node = Arc::make_mut(
+                    node.children
+                        .entry(last)
+                        .or_insert_with(|| node.alloc.new_child()),
+                );
+            }
+            node.bitmap = bit_range_mask(0, IDA_BITMAP_BITS - 3);
+        }
+
+        assert_eq!(ida.alloc(), Some(usize::MAX - 1));
+        assert_eq!(ida.alloc(), Some(usize::MAX));
+        // The root must not wrap around into children beyond the ID space.
+        assert_eq!(ida.alloc(), None);
+        assert_eq!(ida.try_alloc(), Err(AllocError::Exhausted));
+
+        ida.free(usize::MAX);
+        assert_eq!(ida.alloc(), Some(usize::MAX));
+    }
+
+    #[test]
+    fn test_free_pages_until_compact() {
+        // The pre-built path leaves an empty leaf at 0, next to a leaf at 64
+        // that holds IDs.
+        let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+        ida.alloc_at(IDA_BITMAP_BITS + 1).unwrap();
+        ida.alloc_at(5 * IDA_BITMAP_BITS).unwrap();
+        assert_eq!(ida.free_pages().collect::<Vec<_>>(), vec![0]);
+
+        // Eager pruning on `free` removes leaves emptied that way.
+        ida.free(5 * IDA_BITMAP_BITS);
+        assert_eq!(ida.free_pages().collect::<Vec<_>>(), vec![0]);
+
+        let before = ida.node_count();
+        ida.compact();
+        assert_eq!(ida.free_pages().count(), 0);
+        assert_eq!(ida.node_count(), before - 1);
+
+        // Compaction leaves the allocation state untouched.
+        assert_eq!(ida.len(), 1);
+        assert_eq!(ida.count_by_walk(), 1);
+        assert!(ida.is_allocated(IDA_BITMAP_BITS + 1));
+        assert_eq!(ida.alloc(), Some(0));
+    }
+
+    #[test]
+    fn test_alloc_in_range() {
+        let ida = Ida::builder().max(4_200).build();
+        for i in 60..130 {
+            assert_eq!(ida.alloc_in_range(60..130), Some(i));
+        }
+        assert_eq!(ida.alloc_in_range(60..130), None);
+        assert_eq!(ida.alloc_in_range(0..1), Some(0));
+
+        ida.free(100);
+        assert_eq!(ida.alloc_in_range(61..4_000), Some(100));
+        assert_eq!(ida.alloc_in_range(4_100..5_000), Some(4_100));
+        assert_eq!(ida.alloc_in_range(4_201..5_000), None);
+        assert_eq!(ida.alloc_in_range(10..10), None);
+        assert_eq!(ida.alloc_in_range(4_199..=4_199), Some(4_199));
+        assert_eq!(ida.alloc_in_range(4_150..), Some(4_150));
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_snapshot_allows_mutation() {
+        let ida = Ida::default();
+        for _ in 0..200 {
+            ida.alloc();
+        }
+        ida.alloc_at(usize::MAX).unwrap();
+
+        let snapshot = ida.snapshot();
+        assert_eq!(snapshot.len(), 201);
+        assert_eq!(snapshot.last(), Some(&usize::MAX));
+
+        // Mutating during iteration neither deadlocks nor changes the view.
+        for &id in &snapshot {
+            ida.free(id);
+            ida.alloc_in_range(1_000..);
+        }
+        assert_eq!(snapshot.len(), 201);
+        assert_eq!(ida.snapshot(), (1_000..1_201).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_iter_locked_is_consistent() {
+        let ida = Arc::new(Ida::default());
+        for _ in 0..500 {
+            ida.alloc();
+        }
+        ida.free(250);
+        ida.alloc_at(usize::MAX).unwrap();
+        let expected = ida.snapshot();
+
+        let iter = ida.iter_locked();
+        assert!(ida.inner.is_locked());
+
+        // A writer started during iteration must wait for it to finish.
+        let writer = {
+            let ida = Arc::clone(&ida);
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    ida.alloc();
+                }
+            })
+        };
+        thread::sleep(std::time::Duration::from_millis(20));
+
+        let seen: Vec<usize> = iter.collect();
+        assert_eq!(seen, expected);
+        assert!(!ida.inner.is_locked());
+
+        writer.join().unwrap();
+        assert_eq!(ida.len(), expected.len() + 100);
+    }
+
+    #[test]
+    fn test_bitset_view_matches_is_allocated() {
+        let ida = Ida::default();
+        for _ in 0..300 {
+            ida.alloc();
+        }
+        for id in (0..300).filter(|id| id % 7 == 0 || id % 11 == 3) {
+            ida.free(id);
+        }
+        ida.alloc_at(5_000).unwrap();
+        ida.alloc_at(5_063).unwrap();
+
+        for range in [0..320, 13..250, 60..70, 4_990..5_100, 299..5_064, 7..8] {
+            let words = ida.bitset_view(range.clone());
+            assert_eq!(words.len(), range.len().div_ceil(64));
+            for (offset, id) in range.clone().enumerate() {
+                let bit = (words[offset / 64] >> (offset % 64)) & 1 == 1;
+                assert_eq!(bit, ida.is_allocated(id), "id {id} in {range:?}");
+            }
+            // Padding bits past the end of the range stay clear.
+            if range.len() % 64 != 0 {
+                assert_eq!(words.last().unwrap() >> (range.len() % 64), 0);
+            }
+        }
+        assert!(ida.bitset_view(10..10).is_empty());
+    }
+
+    #[test]
+    fn test_apply_bitset_round_trip() {
+        let ida = Ida::default();
+        for _ in 0..5_000 {
+            ida.alloc();
+        }
+        for id in (0..5_000).filter(|id| id % 3 == 0 || id % 64 == 5) {
+            ida.free(id);
+        }
+
+        for range in [0..5_000, 37..4_200, 4_090..4_100] {
+            let copy = Ida::default();
+            copy.apply_bitset(range.start, &ida.bitset_view(range.clone()), false);
+            let expected: Vec<usize> = range.clone().filter(|&id| ida.is_allocated(id)).collect();
+            assert_eq!(copy.snapshot(), expected);
+            assert_eq!(copy.len(), copy.count_by_walk());
+            assert_eq!(copy.bitset_view(range.clone()), ida.bitset_view(range));
+        }
+    }
+
+    #[test]
+    fn test_apply_bitset_merge_and_overwrite() {
+        let ida = Ida::builder().max(200).build();
+        for _ in 0..10 {
+            ida.alloc();
+        }
+
+        // Merging leaves existing allocations alone.
+        ida.apply_bitset(100, &[0b11, 0], false);
+        assert_eq!(ida.len(), 12);
+        assert!(ida.is_allocated(5) && ida.is_allocated(101));
+
+        // Overwriting clears everything the words cover, and nothing else.
+        ida.apply_bitset(0, &[1 << 3], true);
+        assert_eq!(ida.snapshot(), vec![3, 100, 101]);
+        ida.apply_bitset(0, &[1 << 3, 0], true);
+        assert_eq!(ida.snapshot(), vec![3]);
+
+        // Bits past the bound are dropped.
+        ida.apply_bitset(192, &[u64::MAX], false);
+        assert_eq!(ida.len(), 1 + 9);
+        assert!(ida.is_allocated(200));
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_alloc_bounded_effort() {
+        let max = IDA_BITMAP_BITS.pow(3) - 1;
+        let ida = Ida::builder().max(max).build();
+        for _ in 0..=max {
+            ida.alloc();
+        }
+        // Leave a few free IDs scattered through the top of the space.
+        for id in [max - 1, max - 4_096, max - 9_000] {
+            ida.free(id);
+        }
+
+        // The levels above the bounded region alone take more than this.
+        assert_eq!(
+            ida.alloc_bounded_effort(IDA_MAX_LEVELS - 2),
+            Err(AllocError::EffortExceeded)
+        );
+        assert_eq!(ida.len(), max - 2);
+
+        assert_eq!(
+            ida.alloc_bounded_effort(IDA_MAX_LEVELS * 2),
+            Ok(max - 9_000)
+        );
+        assert_eq!(ida.alloc_bounded_effort(usize::MAX), Ok(max - 4_096));
+        assert_eq!(ida.alloc_bounded_effort(usize::MAX), Ok(max - 1));
+        assert_eq!(
+            ida.alloc_bounded_effort(usize::MAX),
+            Err(AllocError::Exhausted)
+        );
+        assert_eq!(ida.len(), ida.count_by_walk());
+    }
+
+    #[test]
+    fn test_relocate_range() {
+        let ida = Ida::default();
+        for _ in 0..200 {
+            ida.alloc();
+        }
+        for id in (50..150).filter(|id| id % 4 == 0) {
+            ida.free(id);
+        }
+        let block: Vec<usize> = (50..150).filter(|&id| ida.is_allocated(id)).collect();
+
+        ida.relocate_range(50, 10_000, 100).unwrap();
+        let moved: Vec<usize> = block.iter().map(|id| id - 50 + 10_000).collect();
+        assert_eq!(ida.iter_free_in(10_000..10_100).count(), 100 - block.len());
+        assert_eq!(ida.snapshot()[ida.len() - block.len()..], moved[..]);
+        assert_eq!(ida.iter_free_in(50..150).count(), 100);
+        assert_eq!(ida.len(), 200 - 25);
+        assert_eq!(ida.len(), ida.count_by_walk());
+
+        // Overlapping ranges are fine, since the source is cleared first.
+        ida.relocate_range(10_000, 10_010, 100).unwrap();
+        let shifted: Vec<usize> = moved.iter().map(|id| id + 10).collect();
+        assert_eq!(ida.snapshot()[ida.len() - block.len()..], shifted[..]);
+    }
+
+    #[test]
+    fn test_relocate_range_conflict_rolls_back() {
+        let ida = Ida::builder().max(1_000).build();
+        for _ in 0..100 {
+            ida.alloc();
+        }
+        ida.alloc_at(530).unwrap();
+        let before = ida.snapshot();
+
+        assert_eq!(
+            ida.relocate_range(0, 500, 100),
+            Err(RelocateError::Conflict(530))
+        );
+        assert_eq!(ida.snapshot(), before);
+        assert_eq!(ida.len(), before.len());
+
+        assert_eq!(
+            ida.relocate_range(0, 950, 100),
+            Err(RelocateError::OutOfRange)
+        );
+        assert_eq!(ida.snapshot(), before);
+
+        // Tags and pending commits survive a failed move and follow a
+        // successful one.
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        assert_eq!(ida.alloc_tagged(7), Some(0));
+        let prepared = ida.alloc_prepare().unwrap();
+        assert_eq!(prepared.id(), 1);
+        ida.alloc_at(101).unwrap();
+        assert_eq!(
+            ida.relocate_range(0, 100, 4),
+            Err(RelocateError::Conflict(101))
+        );
+        assert_eq!(ida.tag_of(0), Some(7));
+        assert!(!ida.is_committed(1));
+        prepared.rollback();
+        assert!(!ida.is_allocated(1));
+        assert_eq!(ida.len(), 2);
+
+        ida.alloc_prepare().unwrap().commit();
+        let prepared = ida.alloc_prepare().unwrap();
+        assert_eq!(prepared.id(), 2);
+        ida.relocate_range(0, 200, 4).unwrap();
+        assert_eq!(ida.snapshot(), vec![101, 200, 201, 202]);
+        assert_eq!(ida.tag_of(200), Some(7));
+        assert_eq!(ida.tag_of(0), None);
+        assert!(ida.is_committed(201) && !ida.is_committed(202));
+        // The vacated IDs were moved, not freed, so none is queued.
+        assert_eq!(ida.alloc(), Some(0));
+        drop(prepared);
+    }
+
+    #[test]
+    fn test_as_ranges() {
+        let ida = Ida::default();
+        for id in [0, 1, 2, 5, 6, 9] {
+            ida.alloc_at(id).unwrap();
+        }
+        assert_eq!(ida.as_ranges(), vec![(0, 2), (5, 6), (9, 9)]);
+
+        // Runs crossing leaf and subtree boundaries coalesce.
+        for id in [63, 64, 4_094, 4_095, 4_096, 4_097] {
+            ida.alloc_at(id).unwrap();
+        }
+        for id in 128..256 {
+            ida.alloc_at(id).unwrap();
+        }
+        ida.alloc_at(usize::MAX).unwrap();
+        assert_eq!(
+            ida.as_ranges(),
+            vec![
+                (0, 2),
+                (5, 6),
+                (9, 9),
+                (63, 64),
+                (128, 255),
+                (4_094, 4_097),
+                (usize::MAX, usize::MAX)
+            ]
+        );
+        assert!(Ida::default().as_ranges().is_empty());
+    }
+
+    #[test]
+    fn test_diff_overlapping() {
+        let a = Ida::default();
+        let b = Ida::default();
+        for _ in 0..5_000 {
+            a.alloc();
+            b.alloc();
+        }
+        for id in [0, 100, 4_095] {
+            b.free(id);
+        }
+        for id in [7_000, 1 << 40] {
+            b.alloc_at(id).unwrap();
+        }
+        a.free(200);
+
+        let (added, removed) = Ida::diff(&a, &b);
+        assert_eq!(added, vec![200, 7_000, 1 << 40]);
+        assert_eq!(removed, vec![0, 100, 4_095]);
+
+        let (added, removed) = Ida::diff(&b, &a);
+        assert_eq!(added, vec![0, 100, 4_095]);
+        assert_eq!(removed, vec![200, 7_000, 1 << 40]);
+
+        assert_eq!(Ida::diff(&a, &a), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_diff_disjoint() {
+        let a = Ida::default();
+        let b = Ida::default();
+        for id in 0..70 {
+            a.alloc_at(id).unwrap();
+        }
+        for id in 100..110 {
+            b.alloc_at(id).unwrap();
+        }
+
+        let (added, removed) = Ida::diff(&a, &b);
+        assert_eq!(added, (100..110).collect::<Vec<_>>());
+        assert_eq!(removed, (0..70).collect::<Vec<_>>());
+        assert_eq!(
+            Ida::diff(&Ida::default(), &Ida::default()),
+            (vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn test_alloc_versioned_detects_reuse() {
+        let ida = Ida::default();
+        let (a, va) = ida.alloc_versioned().unwrap();
+        let (b, vb) = ida.alloc_versioned().unwrap();
+        assert_eq!((a, b), (0, 1));
+        assert!(vb > va);
+
+        ida.free(a);
+        // Plain allocations don't consume sequence numbers.
+        ida.alloc_at(10).unwrap();
+        let (reused, vr) = ida.alloc_versioned().unwrap();
+        assert_eq!(reused, a);
+        assert_ne!(vr, va);
+        assert!(vr > vb);
+    }
+
+    #[test]
+    fn test_alloc_versioned_multi_threaded() {
+        let ida = Arc::new(Ida::default());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let ida = Arc::clone(&ida);
+                thread::spawn(move || {
+                    let mut versions = Vec::new();
+                    for _ in 0..500 {
+                        let (id, version) = ida.alloc_versioned().unwrap();
+                        ida.free(id);
+                        versions.push(version);
+                    }
+                    versions
+                })
+            })
+            .collect();
+
+        let mut all: Vec<u64> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        all.sort_unstable();
+        assert_eq!(all, (0..2_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reclaim_memory_after_spike() {
+        let ida = Ida::builder().prealloc_depth(usize::MAX).build();
+        for _ in 0..100_000 {
+            ida.alloc_in_range(1..);
+        }
+        let peak = ida.memory_usage();
+
+        ida.free_range_counted(1, 99_900);
+        ida.reclaim_memory();
+        assert!(ida.memory_usage() * 10 < peak);
+
+        // Nothing is left to reclaim, and the allocation state is intact.
+        assert_eq!(ida.reclaim_memory(), 0);
+        assert_eq!(ida.len(), 100);
+        assert_eq!(ida.as_ranges(), vec![(99_901, 100_000)]);
+    }
+
+    #[test]
+    fn test_reclaim_memory_shrinks_reuse_queue() {
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        for _ in 0..100_000 {
+            ida.alloc();
+        }
+        // Cycling every ID through the queue leaves it empty but large.
+        ida.free_range_counted(0, 100_000);
+        for _ in 0..100_000 {
+            ida.alloc();
+        }
+
+        let before = ida.memory_usage();
+        assert!(ida.reclaim_memory() >= 100_000 * size_of::<usize>());
+        assert_eq!(ida.memory_usage(), before);
+        assert_eq!(ida.len(), 100_000);
+        assert_eq!(ida.alloc(), Some(100_000));
+    }
+
+    #[test]
+    fn test_reclaim_memory_shrinks_cooling_queue() {
+        let ida = Ida::builder().recycle_delay(1).build();
+        for _ in 0..10_000 {
+            ida.alloc();
+        }
+        // The delay runs out on the next allocation, emptying the queue
+        // but leaving it large.
+        ida.free_range_counted(0, 10_000);
+        for _ in 0..10_000 {
+            ida.alloc();
+        }
+
+        assert!(ida.reclaim_memory() >= 10_000 * size_of::<(usize, u64)>());
+        assert_eq!(ida.reclaim_memory(), 0);
+        assert_eq!(ida.len(), 10_000);
+    }
+
+    #[test]
+    fn test_contains_all_and_none() {
+        let ida = Ida::default();
+        for _ in 0..100 {
+            ida.alloc();
+        }
+        ida.free(50);
+        ida.alloc_at(1 << 30).unwrap();
+
+        assert!(ida.contains_all(&[0, 99, 49, 1 << 30]));
+        assert!(!ida.contains_all(&[0, 99, 50, 1 << 30]));
+        assert!(ida.contains_none(&[50, 100, 5_000, 1 << 31]));
+        assert!(!ida.contains_none(&[50, 100, 1 << 30]));
+
+        // A mixed set satisfies neither.
+        assert!(!ida.contains_all(&[0, 100]));
+        assert!(!ida.contains_none(&[0, 100]));
+
+        assert!(ida.contains_all(&[]));
+        assert!(ida.contains_none(&[]));
+    }
+
+    #[test]
+    fn test_on_utilization_fires_once_per_crossing() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let ida = Arc::new(Ida::builder().max(9).build());
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let observer = ida.clone();
+        ida.on_utilization(0.8, move |utilization| {
+            assert!(utilization >= 0.8);
+            // The lock is released before callbacks run.
+            assert!(observer.len() >= 8);
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        for _ in 0..7 {
+            ida.alloc();
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+        ida.alloc();
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        // Staying above the threshold does not fire again.
+        ida.alloc();
+        ida.free(8);
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        // Dropping below and recrossing fires once more.
+        ida.free(7);
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        ida.alloc_at(7).unwrap();
+        assert_eq!(fired.load(Ordering::SeqCst), 2);
+
+        ida.free_range_counted(0, 10);
+        ida.alloc_in_range(0..8);
+        assert_eq!(fired.load(Ordering::SeqCst), 2);
+
+        // Bulk operations report crossings too.
+        ida.free_range_counted(0, 10);
+        ida.union_assign(&Ida::from_ranges(&[(0, 8)]).unwrap());
+        assert_eq!(fired.load(Ordering::SeqCst), 3);
+
+        let spread = Ida::builder().max(4 * IDA_BITMAP_BITS - 1).build();
+        for _ in 0..205 {
+            spread.alloc();
+        }
+        spread.free(0);
+        let counter = fired.clone();
+        spread.on_utilization(0.8, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        spread.alloc_spread(2).unwrap();
+        assert_eq!(fired.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_iter_chunks_covers_allocated_set() {
+        let ida = Ida::new();
+        let ids = [0, 3, 63, 64, 100, 4_095, 4_096, 70_000, 1 << 40, usize::MAX];
+        for &id in &ids {
+            ida.alloc_at(id).unwrap();
+        }
+
+        for chunk_bits in 0..=IDA_SHIFT {
+            let mut union = Vec::new();
+            let mut last_base = None;
+            for (base, mut bits) in ida.iter_chunks(chunk_bits) {
+                assert_eq!(base % (1 << chunk_bits), 0);
+                assert!(bits != 0);
+                assert!(chunk_bits == IDA_SHIFT || bits >> (1 << chunk_bits) == 0);
+                assert!(last_base < Some(base));
+                last_base = Some(base);
+                while bits != 0 {
+                    union.push(base + bits.trailing_zeros() as usize);
+                    bits &= bits - 1;
+                }
+            }
+            assert_eq!(union, ids, "chunk_bits = {}", chunk_bits);
+        }
+
+        // Leaf-sized chunks are exactly the leaf bitmaps.
+        let leaves: Vec<_> = ida.iter_chunks(IDA_SHIFT).collect();
+        assert_eq!(leaves[0], (0, 1 | 1 << 3 | 1 << 63));
+
+        // Larger chunks summarise blocks of IDs.
+        for chunk_bits in [7, 12, 20, IDA_ID_BITS] {
+            let block_bits = chunk_bits - IDA_SHIFT;
+            let mut blocks = Vec::new();
+            for (base, mut bits) in ida.iter_chunks(chunk_bits) {
+                while bits != 0 {
+                    blocks.push(base + ((bits.trailing_zeros() as usize) << block_bits));
+                    bits &= bits - 1;
+                }
+            }
+            let mut expected: Vec<_> = ids
+                .iter()
+                .map(|&id| id & !((1 << block_bits) - 1))
+                .collect();
+            expected.dedup();
+            assert_eq!(blocks, expected, "chunk_bits = {}", chunk_bits);
+        }
+    }
+
+    #[test]
+    fn test_from_ranges_round_trip() {
+        let ida = Ida::new();
+        for id in 0..300 {
+            ida.alloc_at(id).unwrap();
+        }
+        for id in [5, 63, 64, 200] {
+            ida.free(id);
+        }
+        ida.alloc_at(1 << 40).unwrap();
+        ida.alloc_at(usize::MAX).unwrap();
+
+        let ranges = ida.as_ranges();
+        let rebuilt = Ida::from_ranges(&ranges).unwrap();
+        assert_eq!(rebuilt.snapshot(), ida.snapshot());
+        assert_eq!(rebuilt.len(), ida.len());
+        assert_eq!(rebuilt.as_ranges(), ranges);
+
+        let full = Ida::from_ranges(&[(0, 4_095)]).unwrap();
+        assert_eq!(full.alloc(), Some(4_096));
+        full.free(1_000);
+        assert_eq!(full.alloc(), Some(1_000));
+
+        assert_eq!(
+            Ida::from_ranges(&[(100, 200), (0, 100)]).unwrap_err(),
+            AllocError::AlreadyAllocated
+        );
+        assert_eq!(
+            Ida::from_ranges(&[(2, 1)]).unwrap_err(),
+            AllocError::OutOfRange
+        );
+        assert!(Ida::from_ranges(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_alloc_multiple_of() {
+        for m in [3, 10] {
+            let ida = Ida::new();
+            let got: Vec<_> = (0..5).map(|_| ida.alloc_multiple_of(m).unwrap()).collect();
+            let expected: Vec<_> = (0..5).map(|k| k * m).collect();
+            assert_eq!(got, expected, "m = {}", m);
+        }
+
+        // Fill a dense prefix, then free one qualifying and one
+        // non-qualifying ID.
+        let ida = Ida::new();
+        for _ in 0..1_000 {
+            ida.alloc();
+        }
+        ida.free(301);
+        ida.free(300);
+        ida.free(500);
+        assert_eq!(ida.alloc_multiple_of(3), Some(300));
+        assert_eq!(ida.alloc_multiple_of(3), Some(1_002));
+        assert_eq!(ida.alloc_multiple_of(10), Some(500));
+        assert_eq!(ida.alloc_multiple_of(10), Some(1_000));
+        assert!(ida.is_allocated(300) && !ida.is_allocated(301));
+
+        // Bounded allocators stop at their limit.
+        let bounded = Ida::builder().max(25).build();
+        assert_eq!(bounded.alloc_multiple_of(10), Some(0));
+        assert_eq!(bounded.alloc_multiple_of(10), Some(10));
+        assert_eq!(bounded.alloc_multiple_of(10), Some(20));
+        assert_eq!(bounded.alloc_multiple_of(10), None);
+        assert_eq!(bounded.alloc_multiple_of(0), None);
+        assert_eq!(bounded.alloc_multiple_of(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_snapshot_stats_matches_individual_queries() {
+        let ida = Ida::new();
+        let empty = ida.snapshot_stats();
+        assert_eq!(empty.len, 0);
+        assert_eq!(empty.highest_allocated, None);
+        assert_eq!(empty.lowest_free, Some(0));
+        assert_eq!(empty.allocated_runs, 0);
+
+        for _ in 0..200 {
+            ida.alloc();
+        }
+        for id in [0, 50, 51, 127] {
+            ida.free(id);
+        }
+        ida.alloc_at(1 << 30).unwrap();
+        ida.alloc_at((1 << 30) + 1).unwrap();
+
+        let stats = ida.snapshot_stats();
+        let ids = ida.snapshot();
+        assert_eq!(stats.len, ida.len());
+        assert_eq!(stats.highest_allocated, ids.last().copied());
+        assert_eq!(stats.lowest_free, ida.iter_free_in(0..usize::MAX).next());
+        assert_eq!(stats.allocated_runs, ida.as_ranges().len());
+        assert_eq!(stats.node_count, ida.node_count());
+        // Runs spanning the leaf boundary at 64 and 128 are counted once.
+        assert_eq!(stats.allocated_runs, 4);
+
+        let full = Ida::builder().max(63).build();
+        while full.alloc().is_some() {}
+        let stats = full.snapshot_stats();
+        assert_eq!(stats.lowest_free, None);
+        assert_eq!(stats.highest_allocated, Some(63));
+        assert_eq!(stats.allocated_runs, 1);
+    }
+
+    #[test]
+    fn test_alloc_contiguous_high_stacks_blocks() {
+        let ida = Ida::new();
+        assert_eq!(ida.alloc_contiguous_high(0), None);
+
+        let first = ida.alloc_contiguous_high(100).unwrap();
+        let second = ida.alloc_contiguous_high(30).unwrap();
+        assert_eq!(first, 0);
+        assert_eq!(second, 100);
+        assert_eq!(ida.len(), 130);
+        assert_eq!(ida.as_ranges(), vec![(0, 129)]);
+
+        // Holes below the top are left alone.
+        ida.free_range_counted(10, 20);
+        assert_eq!(ida.alloc_contiguous_high(5), Some(130));
+
+        // Freeing in reverse order lowers the high-water mark again.
+        ida.free_range_counted(130, 5);
+        ida.free_range_counted(second, 30);
+        assert_eq!(ida.alloc_contiguous_high(64), Some(100));
+
+        let bounded = Ida::builder().max(9).build();
+        assert_eq!(bounded.alloc_contiguous_high(8), Some(0));
+        assert_eq!(bounded.alloc_contiguous_high(3), None);
+        assert_eq!(bounded.alloc_contiguous_high(2), Some(8));
+
+        let top = Ida::new();
+        top.alloc_at(usize::MAX - 1).unwrap();
+        assert_eq!(top.alloc_contiguous_high(2), None);
+        assert_eq!(top.alloc_contiguous_high(1), Some(usize::MAX));
+        assert_eq!(top.alloc_contiguous_high(1), None);
+    }
+
+    #[test]
+    fn test_reset_to_replaces_allocation_set() {
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        for _ in 0..3 {
+            ida.alloc();
+        }
+        ida.free(1);
+        ida.alloc_at(1).unwrap();
+
+        ida.reset_to([5, 6, 7, 6]);
+        assert_eq!(ida.snapshot(), vec![5, 6, 7]);
+        assert_eq!(ida.len(), 3);
+        for id in 0..5 {
+            assert!(!ida.is_allocated(id));
+        }
+        // The stale queue entry for 1 is gone, so allocation starts at 0.
+        assert_eq!(ida.alloc(), Some(0));
+
+        ida.reset_to(core::iter::empty());
+        assert!(ida.is_empty());
+        assert_eq!(ida.node_count(), 1);
+
+        let bounded = Ida::builder().max(10).build();
+        bounded.reset_to([3, 10, 11, usize::MAX]);
+        assert_eq!(bounded.snapshot(), vec![3, 10]);
+    }
+
+    #[test]
+    fn test_alloc_random_unique_and_spread() {
+        let ida = Ida::builder().max(4_095).build();
+        let mut rng = 42;
+        let ids: Vec<_> = (0..4_096)
+            .map(|_| ida.alloc_random(&mut rng).unwrap())
+            .collect();
+
+        // Every ID is handed out exactly once, even as the pool fills up.
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..4_096).collect::<Vec<_>>());
+        assert!(ida.is_full());
+        assert_eq!(ida.alloc_random(&mut rng), None);
+
+        // The early picks are not handed out in increasing order.
+        assert!(ids[..16].windows(2).any(|w| w[0] > w[1]));
+
+        // The same seed reproduces the same sequence.
+        let (a, b) = (
+            Ida::builder().max(1_000).build(),
+            Ida::builder().max(1_000).build(),
+        );
+        let (mut ra, mut rb) = (7, 7);
+        for _ in 0..50 {
+            assert_eq!(a.alloc_random(&mut ra), b.alloc_random(&mut rb));
+        }
+
+        let unbounded = Ida::new();
+        let mut rng = 1;
+        let x = unbounded.alloc_random(&mut rng).unwrap();
+        let y = unbounded.alloc_random(&mut rng).unwrap();
+        assert_ne!(x, y);
+        assert!(unbounded.is_allocated(x) && unbounded.is_allocated(y));
+    }
+
+    #[test]
+    fn test_capacity_hint_does_not_change_allocation() {
+        let hinted = Ida::builder().capacity_hint(10_000).build();
+        let plain = Ida::new();
+        // One node per level down to the leaves, plus the 157 leaves and
+        // three level-1 nodes needed for 10,000 IDs.
+        assert_eq!(hinted.node_count(), IDA_MAX_LEVELS - 2 + 3 + 157);
+
+        for _ in 0..12_000 {
+            assert_eq!(hinted.alloc(), plain.alloc());
+        }
+        for id in (0..12_000).step_by(7) {
+            hinted.free(id);
+            plain.free(id);
+        }
+        assert_eq!(hinted.snapshot(), plain.snapshot());
+        assert_eq!(hinted.alloc(), Some(0));
+
+        assert_eq!(Ida::builder().capacity_hint(0).build().node_count(), 1);
+        assert_eq!(
+            Ida::builder().capacity_hint(1).build().node_count(),
+            IDA_MAX_LEVELS
+        );
+    }
+
+    #[test]
+    fn test_alloc_tagged_tracks_tags() {
+        let ida = Ida::new();
+        let net: Vec<_> = (0..5).map(|_| ida.alloc_tagged(1).unwrap()).collect();
+        let fs: Vec<_> = (0..3).map(|_| ida.alloc_tagged(2).unwrap()).collect();
+        let untagged = ida.alloc().unwrap();
+
+        assert_eq!(net, vec![0, 1, 2, 3, 4]);
+        assert_eq!(fs, vec![5, 6, 7]);
+        assert_eq!(ida.tag_of(3), Some(1));
+        assert_eq!(ida.tag_of(6), Some(2));
+        assert_eq!(ida.tag_of(untagged), None);
+        assert_eq!(ida.tag_of(1_000), None);
+        assert_eq!(ida.count_by_tag(1), 5);
+        assert_eq!(ida.count_by_tag(2), 3);
+        assert_eq!(ida.count_by_tag(3), 0);
+
+        ida.free(net[1]);
+        ida.free_range_counted(4, 2);
+        assert_eq!(ida.tag_of(1), None);
+        assert_eq!(ida.tag_of(4), None);
+        assert_eq!(ida.tag_of(5), None);
+        assert_eq!(ida.count_by_tag(1), 3);
+        assert_eq!(ida.count_by_tag(2), 2);
+
+        // A reused ID takes its new tag, or none.
+        assert_eq!(ida.alloc_tagged(9), Some(1));
+        assert_eq!(ida.tag_of(1), Some(9));
+        assert_eq!(ida.alloc(), Some(4));
+        assert_eq!(ida.tag_of(4), None);
+
+        ida.reset_to([0, 1]);
+        assert_eq!(ida.tag_of(0), None);
+        assert_eq!(ida.count_by_tag(1), 0);
+    }
+
+    #[test]
+    fn test_free_report_flags_last_free() {
+        let ida = Ida::new();
+        let ids: Vec<_> = (0..100).map(|_| ida.alloc().unwrap()).collect();
+
+        for &id in &ids[..99] {
+            let outcome = ida.free_report(id);
+            assert!(outcome.was_allocated);
+            assert!(!outcome.now_empty, "id {}", id);
+        }
+        assert_eq!(
+            ida.free_report(ids[99]),
+            FreeOutcome {
+                was_allocated: true,
+                now_empty: true,
+            }
+        );
+        assert_eq!(
+            ida.free_report(ids[99]),
+            FreeOutcome {
+                was_allocated: false,
+                now_empty: true,
+            }
+        );
+
+        // Only one of many concurrent freers sees the allocator emptied.
+        let ida = Arc::new(Ida::new());
+        for _ in 0..64 {
+            ida.alloc();
+        }
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let ida = ida.clone();
+                thread::spawn(move || {
+                    (0..8)
+                        .filter(|k| ida.free_report(t * 8 + k).now_empty)
+                        .count()
+                })
+            })
+            .collect();
+        let emptied: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(emptied, 1);
+    }
+
+    #[test]
+    fn test_set_max_grows_and_shrinks() {
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).max(9).build();
+        for _ in 0..10 {
+            ida.alloc();
+        }
+        assert_eq!(ida.alloc(), None);
+
+        ida.set_max(99).unwrap();
+        let more: Vec<_> = (0..90).map(|_| ida.alloc().unwrap()).collect();
+        assert_eq!(more, (10..100).collect::<Vec<_>>());
+        assert_eq!(ida.alloc(), None);
+
+        // Shrinking is rejected while a high ID is allocated.
+        ida.free_range_counted(50, 50);
+        ida.alloc_at(70).unwrap();
+        assert_eq!(ida.set_max(49), Err(ResizeError::Occupied(70)));
+        assert_eq!(ida.total_capacity(), 100);
+
+        // Once it is freed, the stale queue entries above the bound are
+        // dropped along with it.
+        ida.free(70);
+        ida.set_max(49).unwrap();
+        ida.free(3);
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), None);
+
+        ida.set_max(usize::MAX).unwrap();
+        assert_eq!(ida.alloc(), Some(50));
+    }
+
+    #[test]
+    fn test_alloc_prepare_commit_and_rollback() {
+        let ida = Ida::new();
+        ida.alloc();
+
+        let first = ida.alloc_prepare().unwrap();
+        let second = ida.alloc_prepare().unwrap();
+        assert_eq!((first.id(), second.id()), (1, 2));
+        assert_eq!(ida.len(), 3);
+        assert!(ida.is_committed(0));
+        assert!(!ida.is_committed(1) && !ida.is_committed(2));
+
+        assert_eq!(first.commit(), 1);
+        assert!(ida.is_committed(1));
+        second.rollback();
+        assert!(!ida.is_allocated(2));
+        assert!(!ida.is_committed(2));
+        assert_eq!(ida.len(), 2);
+
+        // The rolled-back ID is reused.
+        assert_eq!(ida.alloc(), Some(2));
+        assert!(ida.is_committed(2));
+
+        // Dropping an uncommitted token rolls back too.
+        {
+            let _abandoned = ida.alloc_prepare().unwrap();
+            assert!(ida.is_allocated(3));
+        }
+        assert!(!ida.is_allocated(3));
+
+        // A pending ID freed and reallocated elsewhere is not freed again by
+        // the stale token.
+        let stale = ida.alloc_prepare().unwrap();
+        ida.free(3);
+        assert_eq!(ida.alloc(), Some(3));
+        drop(stale);
+        assert!(ida.is_committed(3));
+
+        assert!(Ida::builder().max(0).build().alloc_prepare().is_some());
+        let full = Ida::builder().max(0).build();
+        full.alloc();
+        assert!(full.alloc_prepare().is_none());
+    }
+
+    #[test]
+    fn test_densest_and_emptiest_partial_leaf() {
+        let ida = Ida::new();
+        assert_eq!(ida.densest_leaf(), None);
+        assert_eq!(ida.emptiest_partial_leaf(), None);
+
+        // Leaf 0: full. Leaf 64: 10 IDs. Leaf 128: 40 IDs. Leaf 192: 3 IDs.
+        // Leaf 1 << 20: 40 IDs. Leaf 1 << 30: 3 IDs.
+        for id in 0..64 {
+            ida.alloc_at(id).unwrap();
+        }
+        for (base, count) in [(64, 10), (128, 40), (192, 3), (1 << 20, 40), (1 << 30, 3)] {
+            for k in 0..count {
+                ida.alloc_at(base + k).unwrap();
+            }
+        }
+
+        assert_eq!(ida.densest_leaf(), Some(128));
+        assert_eq!(ida.emptiest_partial_leaf(), Some(192));
+
+        ida.free(130);
+        assert_eq!(ida.densest_leaf(), Some(1 << 20));
+        ida.free(192);
+        assert_eq!(ida.emptiest_partial_leaf(), Some(192));
+        ida.free_range_counted(192, 64);
+        assert_eq!(ida.emptiest_partial_leaf(), Some(1 << 30));
+
+        // Full leaves never count.
+        let full = Ida::new();
+        for _ in 0..128 {
+            full.alloc();
+        }
+        assert_eq!(full.densest_leaf(), None);
+        full.free(100);
+        assert_eq!(full.densest_leaf(), Some(64));
+        assert_eq!(full.emptiest_partial_leaf(), Some(64));
+    }
+
+    #[test]
+    fn test_fork_cow_isolates_branches() {
+        let ida = Ida::new();
+        for _ in 0..1_000 {
+            ida.alloc();
+        }
+        ida.alloc_at(1 << 40).unwrap();
+        let before = ida.snapshot();
+
+        let fork = ida.fork_cow();
+        assert_eq!(fork.snapshot(), before);
+        assert_eq!(fork.len(), ida.len());
+
+        // Mutate each branch independently.
+        fork.free(10);
+        fork.alloc_at(5_000).unwrap();
+        ida.free(20);
+        ida.free_range_counted(900, 100);
+
+        assert!(!fork.is_allocated(10) && fork.is_allocated(20));
+        assert!(fork.is_allocated(950) && fork.is_allocated(5_000));
+        assert!(ida.is_allocated(10) && !ida.is_allocated(20));
+        assert!(!ida.is_allocated(950) && !ida.is_allocated(5_000));
+        assert_eq!(fork.len(), 1_001);
+        assert_eq!(ida.len(), 900);
+        assert_eq!(fork.alloc(), Some(10));
+        assert_eq!(ida.alloc(), Some(20));
+    }
+
+    #[test]
+    fn test_fork_cow_shares_untouched_subtrees() {
+        let ida = Ida::new();
+        for _ in 0..100 {
+            ida.alloc();
+        }
+        ida.alloc_at(1 << 62).unwrap();
+
+        let fork = ida.fork_cow();
+        {
+            let (a, b) = (ida.inner.lock(), fork.inner.lock());
+            for (child_a, child_b) in a.root.children.values().zip(b.root.children.values()) {
+                assert!(Arc::ptr_eq(child_a, child_b));
+            }
+        }
+
+        // Touching the low IDs copies only the path to them; the subtree
+        // holding `1 << 62` stays shared.
+        fork.free(5);
+        let (a, b) = (ida.inner.lock(), fork.inner.lock());
+        assert!(!Arc::ptr_eq(&a.root.children[&0], &b.root.children[&0]));
+        assert!(Arc::ptr_eq(&a.root.children[&4], &b.root.children[&4]));
+        assert_eq!(Arc::strong_count(&a.root.children[&4]), 2);
+    }
+
+    #[test]
+    fn test_blacklist_is_never_allocated() {
+        let blacklist = [0, 1, 5, 63, 64, 200, 4_096];
+        let ida = Ida::builder()
+            .blacklist(&[200, 0, 5, 1, 64, 63, 4_096, 5])
+            .build();
+
+        let ids: Vec<_> = (0..5_000).map(|_| ida.alloc().unwrap()).collect();
+        for id in blacklist {
+            assert!(!ids.contains(&id), "blacklisted {} was allocated", id);
+            assert!(!ida.is_allocated(id));
+            assert_eq!(ida.alloc_at(id), Err(AllocError::OutOfRange));
+        }
+        assert_eq!(ids[..3], [2, 3, 4]);
+        assert_eq!(ida.len(), 5_000);
+        assert!(ida.snapshot().iter().all(|id| !blacklist.contains(id)));
+
+        // Freeing around a blacklisted ID never exposes it, even when it is
+        // the lowest free value.
+        ida.free(2);
+        ida.free(6);
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc_in_range(..), Some(6));
+        assert_eq!(ida.alloc_parity(Parity::Even), Some(5_008));
+        assert_eq!(ida.alloc_multiple_of(100), Some(5_100));
+        assert_eq!(ida.alloc_bounded_effort(usize::MAX), Ok(5_007));
+        assert!(ida.iter_free_in(0..100).next().is_none());
+
+        // Bulk paths leave blacklisted IDs unset.
+        ida.reset_to([0, 1, 2, 3]);
+        assert_eq!(ida.snapshot(), vec![2, 3]);
+        ida.apply_bitset(0, &[u64::MAX], false);
+        assert!(!ida.is_allocated(5) && !ida.is_allocated(63));
+        assert_eq!(ida.len(), 60);
+
+        let stack = Ida::builder().blacklist(&[3, 4]).build();
+        assert_eq!(stack.alloc_contiguous_high(3), Some(0));
+        assert_eq!(stack.alloc_contiguous_high(2), Some(5));
+
+        let mut rng = 3;
+        let bounded = Ida::builder().blacklist(&[1]).max(2).build();
+        let mut got: Vec<_> = (0..2)
+            .map(|_| bounded.alloc_random(&mut rng).unwrap())
+            .collect();
+        got.sort_unstable();
+        assert_eq!(got, vec![0, 2]);
+        assert_eq!(bounded.alloc_random(&mut rng), None);
+        assert_eq!(bounded.alloc(), None);
+        assert!(bounded.alloc_spread(1).is_none());
+        assert!(bounded.is_full());
+    }
+
+    #[test]
+    fn test_iter_gaps_complements_ranges() {
+        let ida = Ida::new();
+        assert_eq!(ida.iter_gaps().count(), 0);
+
+        for _ in 0..300 {
+            ida.alloc();
+        }
+        // Holes inside a leaf, across the leaf boundary at 64 and across the
+        // level-1 boundary at 4,096.
+        ida.free_range_counted(10, 3);
+        ida.free_range_counted(60, 10);
+        ida.free(299);
+        ida.alloc_at(4_000).unwrap();
+        ida.free_range_counted(4_000, 1);
+        ida.alloc_at(4_090).unwrap();
+        ida.alloc_at(4_200).unwrap();
+
+        let gaps: Vec<_> = ida.iter_gaps().collect();
+        assert_eq!(
+            gaps,
+            vec![
+                (10, 3),
+                (60, 10),
+                (299, 4_090 - 299),
+                (4_091, 4_200 - 4_091)
+            ]
+        );
+
+        // Gaps and allocated ranges tile `0..=highest` exactly.
+        let mut tiles: Vec<_> = ida
+            .as_ranges()
+            .into_iter()
+            .map(|(start, end)| (start, end - start + 1))
+            .chain(gaps)
+            .collect();
+        tiles.sort_unstable();
+        let mut next = 0;
+        for (start, len) in tiles {
+            assert_eq!(start, next);
+            next = start + len;
+        }
+        assert_eq!(next, 4_201);
+
+        let top = Ida::new();
+        top.alloc_at(usize::MAX).unwrap();
+        let gaps: Vec<_> = top.iter_gaps().collect();
+        assert_eq!(gaps, vec![(0, usize::MAX)]);
+    }
+
+    #[test]
+    fn test_alloc_buddy_orders() {
+        let ida = Ida::new();
+        ida.alloc_at(0).unwrap();
+        ida.alloc_at(9).unwrap();
+
+        // Order 0 behaves like plain allocation.
+        assert_eq!(ida.alloc_buddy(0), Some(1));
+        // Order 3 skips the block holding 9.
+        assert_eq!(ida.alloc_buddy(3), Some(16));
+        assert_eq!(ida.alloc_buddy(3), Some(24));
+        // Order 6 needs a whole empty leaf.
+        assert_eq!(ida.alloc_buddy(6), Some(64));
+        assert_eq!(ida.alloc_buddy(6), Some(128));
+        assert_eq!(ida.alloc_buddy(12), Some(4_096));
+        assert_eq!(ida.len(), 3 + 16 + 128 + 4_096);
+
+        for (base, order) in [(16, 3), (64, 6), (4_096, 12)] {
+            ida.free_buddy(base, order);
+            for id in base..base + (1 << order) {
+                assert!(!ida.is_allocated(id));
+            }
+        }
+        assert_eq!(ida.alloc_buddy(3), Some(16));
+        assert_eq!(ida.alloc_buddy(6), Some(64));
+        assert_eq!(ida.alloc_buddy(12), Some(4_096));
+        assert_eq!(ida.alloc_buddy(0), Some(2));
+
+        let bounded = Ida::builder().max(15).build();
+        assert_eq!(bounded.alloc_buddy(3), Some(0));
+        assert_eq!(bounded.alloc_buddy(3), Some(8));
+        assert_eq!(bounded.alloc_buddy(0), None);
+        assert_eq!(Ida::builder().max(14).build().alloc_buddy(4), None);
+        assert_eq!(Ida::new().alloc_buddy(IDA_ID_BITS), None);
+
+        let blacklisted = Ida::builder().blacklist(&[3]).build();
+        assert_eq!(blacklisted.alloc_buddy(2), Some(4));
+        assert_eq!(blacklisted.alloc_buddy(1), Some(0));
+    }
+
+    #[test]
+    fn test_free_restores_fresh_tree_structure() {
+        let fresh = Ida::new();
+
+        let ida = Ida::new();
+        let id = ida.alloc().unwrap();
+        assert!(!ida.deep_equal_tree(&fresh));
+        ida.free(id);
+        assert!(ida.deep_equal_tree(&fresh));
+
+        // Many IDs spanning several levels, freed out of order.
+        for _ in 0..10_000 {
+            ida.alloc();
+        }
+        ida.alloc_at(1 << 50).unwrap();
+        ida.alloc_at(usize::MAX).unwrap();
+        for id in (0..10_000).rev().step_by(2).chain((0..10_000).step_by(2)) {
+            ida.free(id);
+        }
+        ida.free(1 << 50);
+        ida.free(usize::MAX);
+        assert!(ida.is_empty());
+        assert!(ida.deep_equal_tree(&fresh));
+
+        // Range frees prune just as thoroughly.
+        for _ in 0..5_000 {
+            ida.alloc();
+        }
+        ida.free_range_counted(0, 5_000);
+        assert!(ida.deep_equal_tree(&fresh));
+
+        // Pre-built empty nodes make the structure differ until compacted.
+        let prebuilt = Ida::builder().prealloc_depth(usize::MAX).build();
+        assert!(!prebuilt.deep_equal_tree(&fresh));
+        prebuilt.compact();
+        assert!(prebuilt.deep_equal_tree(&fresh));
+        assert!(fresh.deep_equal_tree(&fresh));
+    }
+
+    /// Asserts that every internal node below the root has its full-bit set
+    /// exactly for the children that are present and full.
+    fn assert_full_bits_consistent(node: &IdaNode, level: usize) {
+        if level == 0 {
+            return;
+        }
+        let children = if level == IDA_MAX_LEVELS - 1 {
+            IDA_ROOT_CHILDREN
+        } else {
+            IDA_BITMAP_BITS
+        };
+        for i in 0..children {
+            let full = node.children.get(&i).is_some_and(|c| c.bitmap == u64::MAX);
+            assert_eq!(
+                node.bitmap >> i & 1 == 1,
+                full,
+                "full-bit {} at level {}",
+                i,
+                level
+            );
+        }
+        for child in node.children.values() {
+            assert_full_bits_consistent(child, level - 1);
+        }
+    }
+
+    #[test]
+    fn test_contiguous_runs_across_level_boundaries() {
+        // Boundaries between leaves, level-1 nodes and level-2 nodes.
+        for boundary in [64, 4_096, 262_144] {
+            for (start, len) in [(boundary - 6, 12), (boundary - 1, 2), (boundary - 63, 128)] {
+                let end = start + len - 1;
+
+                let ida = Ida::from_ranges(&[(start, end)]).unwrap();
+                assert_eq!(ida.len(), len);
+                assert!(!ida.is_allocated(start - 1) && !ida.is_allocated(end + 1));
+                assert_eq!(ida.as_ranges(), vec![(start, end)]);
+                assert_full_bits_consistent(&ida.inner.lock().root, IDA_MAX_LEVELS - 1);
+
+                // The same run stacked above an existing allocation.
+                let stacked = Ida::new();
+                stacked.alloc_at(start - 1).unwrap();
+                assert_eq!(stacked.alloc_contiguous_high(len), Some(start));
+                assert_eq!(stacked.as_ranges(), vec![(start - 1, end)]);
+                assert_full_bits_consistent(&stacked.inner.lock().root, IDA_MAX_LEVELS - 1);
+
+                stacked.free_range_counted(start, len);
+                assert_eq!(stacked.snapshot(), vec![start - 1]);
+                assert_full_bits_consistent(&stacked.inner.lock().root, IDA_MAX_LEVELS - 1);
+            }
+
+            // Filling everything below the boundary marks every level full,
+            // so allocation lands exactly on the boundary.
+            let ida = Ida::from_ranges(&[(0, boundary - 1)]).unwrap();
+            assert_full_bits_consistent(&ida.inner.lock().root, IDA_MAX_LEVELS - 1);
+            assert_eq!(ida.alloc(), Some(boundary));
+            assert_eq!(ida.alloc_buddy(6), Some(boundary + 64));
+
+            // A whole aligned block on the far side of the boundary.
+            let buddy = Ida::from_ranges(&[(0, boundary - 2)]).unwrap();
+            let order = boundary.trailing_zeros() as usize;
+            assert_eq!(buddy.alloc_buddy(order), Some(boundary));
+            assert_eq!(buddy.alloc(), Some(boundary - 1));
+            assert_full_bits_consistent(&buddy.inner.lock().root, IDA_MAX_LEVELS - 1);
+            assert_eq!(buddy.alloc(), Some(2 * boundary));
+        }
+    }
+
+    #[test]
+    fn test_reserve_scoped_releases_on_drop() {
+        let ida = Ida::new();
+        ida.alloc_at(50).unwrap();
+        let ids = [1, 64, 4_096, 1 << 40, 0];
+
+        {
+            let group = ida.reserve_scoped(&ids).unwrap();
+            assert_eq!(group.ids(), ids);
+            assert_eq!(ida.len(), 6);
+            for id in ids {
+                assert!(ida.is_allocated(id));
+            }
+        }
+        for id in ids {
+            assert!(!ida.is_allocated(id));
+        }
+        assert_eq!(ida.snapshot(), vec![50]);
+
+        // A conflict anywhere reserves nothing.
+        assert_eq!(ida.reserve_scoped(&[2, 3, 50, 4]).unwrap_err(), 50);
+        assert_eq!(ida.reserve_scoped(&[2, 3, 2]).unwrap_err(), 2);
+        assert_eq!(ida.snapshot(), vec![50]);
+
+        // IDs freed by hand and handed to someone else are left alone.
+        {
+            let _group = ida.reserve_scoped(&[2, 3]).unwrap();
+            ida.free(3);
+            assert_eq!(ida.alloc_at(3), Ok(()));
+        }
+        assert_eq!(ida.snapshot(), vec![3, 50]);
+        ida.free(3);
+
+        ida.reserve_scoped(&[2, 3]).unwrap().commit();
+        assert!(ida.is_committed(2));
+        assert_eq!(ida.snapshot(), vec![2, 3, 50]);
+        assert!(ida.reserve_scoped(&[]).is_ok());
+
+        let bounded = Ida::builder().max(10).build();
+        assert_eq!(bounded.reserve_scoped(&[5, 11]).unwrap_err(), 11);
+        assert!(bounded.is_empty());
+    }
+
+    #[test]
+    fn test_alloc_region_stays_in_bounds() {
+        const SIZE: usize = 100;
+        let ida = Ida::new();
+
+        let low: Vec<_> = (0..SIZE)
+            .map(|_| ida.alloc_region(1, SIZE).unwrap())
+            .collect();
+        let high: Vec<_> = (0..40)
+            .map(|_| ida.alloc_region(7, SIZE).unwrap())
+            .collect();
+        assert_eq!(low, (100..200).collect::<Vec<_>>());
+        assert_eq!(high, (700..740).collect::<Vec<_>>());
+        assert!(low.iter().all(|&id| Ida::region_of(id, SIZE) == 1));
+        assert!(high.iter().all(|&id| Ida::region_of(id, SIZE) == 7));
+
+        // A full region does not spill into its neighbour.
+        assert_eq!(ida.alloc_region(1, SIZE), None);
+        assert!(!ida.is_allocated(200));
+        ida.free(150);
+        assert_eq!(ida.alloc_region(1, SIZE), Some(150));
+
+        assert_eq!(ida.alloc_region(0, 0), None);
+        assert_eq!(ida.alloc_region(usize::MAX, 2), None);
+        let last = usize::MAX / 3;
+        assert_eq!(ida.alloc_region(last, 3), Some(last * 3));
+    }
+
+    #[test]
+    fn test_load_checked_accepts_valid_and_rejects_malformed() {
+        let ida = Ida::new();
+        for _ in 0..5_000 {
+            ida.alloc();
+        }
+        for id in (0..5_000).step_by(3) {
+            ida.free(id);
+        }
+        ida.alloc_at(usize::MAX).unwrap();
+
+        let blob = ida.snapshot();
+        let loaded = Ida::load_checked(&blob).unwrap();
+        assert_eq!(loaded.snapshot(), blob);
+        assert_eq!(loaded.len(), ida.len());
+        assert!(loaded.deep_equal_tree(&ida));
+        assert!(Ida::load_checked(&[]).unwrap().is_empty());
+
+        // Swap two entries, duplicate one, and splice in a stale prefix.
+        let mut swapped = blob.clone();
+        swapped.swap(10, 11);
+        assert_eq!(
+            Ida::load_checked(&swapped).unwrap_err(),
+            LoadError::NotAscending(11)
+        );
+        let mut duplicated = blob.clone();
+        duplicated.insert(4, duplicated[3]);
+        assert_eq!(
+            Ida::load_checked(&duplicated).unwrap_err(),
+            LoadError::NotAscending(4)
+        );
+        let spliced: Vec<_> = blob[..100].iter().chain(&blob[50..]).copied().collect();
+        assert_eq!(
+            Ida::load_checked(&spliced).unwrap_err(),
+            LoadError::NotAscending(100)
+        );
+        assert!(loaded.inner.lock().root.is_consistent(IDA_MAX_LEVELS - 1));
+    }
+
+    #[test]
+    fn test_full_leaves_reports_saturated_leaves() {
+        assert_eq!(Ida::new().full_leaves().count(), 0);
+
+        // Leaves 0 and 2 are full, leaf 1 is partial.
+        let ida =
+            Ida::from_ranges(&[(0, 63), (64, 100), (128, 191), (1 << 40, (1 << 40) + 63)]).unwrap();
+        assert_eq!(ida.full_leaves().collect::<Vec<_>>(), vec![0, 128, 1 << 40]);
+
+        ida.free(130);
+        ida.alloc_in_range(101..128);
+        assert_eq!(ida.full_leaves().collect::<Vec<_>>(), vec![0, 1 << 40]);
+    }
+
+    #[test]
+    fn test_alloc_metered_cost_grows_with_fragmentation() {
+        let max = (1 << (3 * IDA_SHIFT)) - 1;
+        let ida = Ida::builder().max(max).build();
+        ida.reset_to(0..=max);
+        ida.set_search_start(max);
+
+        // Each round frees one hole, which the hinted search cannot use and
+        // the fallback search then finds. The closer the hole sits to the
+        // hint, the more subtrees on the hinted path it leaves non-full.
+        let cost = |hole: usize| {
+            ida.free(hole);
+            let (id, metrics) = ida.alloc_metered().unwrap();
+            assert_eq!(id, hole);
+            metrics
+        };
+        let far = cost(0);
+        let nearer = cost(max - IDA_BITMAP_BITS);
+        let nearest = cost(max - 1);
+        assert!(far.nodes_visited < nearer.nodes_visited);
+        assert!(nearer.nodes_visited <= nearest.nodes_visited);
+        assert!(far.backtracks < nearer.backtracks);
+        assert!(nearer.backtracks < nearest.backtracks);
+
+        assert_eq!(ida.alloc_metered(), None);
+        ida.hint_invalidate();
+        ida.free(5);
+        let (id, unfragmented) = ida.alloc_metered().unwrap();
+        assert_eq!(id, 5);
+        assert_eq!(unfragmented.backtracks, 0);
+        assert!(unfragmented.nodes_visited < far.nodes_visited);
+    }
+
+    #[test]
+    fn test_on_empty_fires_on_transition() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        let ida = Ida::new();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        ida.on_empty(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Freeing into an allocator that is already empty is not a drain.
+        ida.free(3);
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+
+        let ids: Vec<usize> = (0..3).map(|_| ida.alloc().unwrap()).collect();
+        ida.free(ids[0]);
+        ida.free(ids[1]);
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+        ida.free(ids[2]);
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        ida.free(ids[2]);
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        // Range frees count too, and each drain fires again.
+        ida.alloc();
+        ida.alloc();
+        assert_eq!(ida.free_range_counted(0, 10), 2);
+        assert_eq!(fired.load(Ordering::SeqCst), 2);
+
+        // The callback may re-enter the allocator.
+        let reentrant = Arc::new(Ida::new());
+        let inner = reentrant.clone();
+        reentrant.on_empty(move || {
+            inner.alloc();
+        });
+        let id = reentrant.alloc().unwrap();
+        reentrant.free(id);
+        assert_eq!(reentrant.len(), 1);
+    }
+
+    #[test]
+    fn test_warm_subtree_policy_stays_in_block() {
+        let block = 1 << WARM_BLOCK_SHIFT;
+        let ida = Ida::builder().policy(AllocPolicy::WarmSubtree).build();
+        for expected in 0..block {
+            assert_eq!(ida.alloc(), Some(expected));
+        }
+        // The first block is full, so allocation spills into the next one.
+        assert_eq!(ida.alloc(), Some(block));
+
+        for id in [3, 100, 2_000] {
+            ida.free(id);
+        }
+        let next: Vec<usize> = (0..8).map(|_| ida.alloc().unwrap()).collect();
+        assert_eq!(next, (block + 1..block + 9).collect::<Vec<_>>());
+
+        // Once the warm block fills up, the lowest free ID is used again and
+        // its block becomes the warm one.
+        for _ in block + 9..2 * block {
+            ida.alloc();
+        }
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), Some(100));
+        assert_eq!(ida.alloc(), Some(2_000));
+        assert_eq!(ida.alloc(), Some(2 * block));
+    }
+
+    #[test]
+    fn test_snapshot_shared_across_threads() {
+        let ida = Ida::new();
+        for _ in 0..200 {
+            ida.alloc();
+        }
+        ida.free_range_counted(50, 25);
+        let shared = ida.snapshot_shared();
+        let expected = ida.snapshot();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || shared.iter().copied().collect::<Vec<_>>())
+            })
+            .collect();
+        // Changes made after the snapshot was taken are not observed.
+        ida.alloc();
+        ida.free(0);
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+        assert_eq!(&shared[..], &expected[..]);
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn test_alloc_exact_or_next() {
+        let ida = Ida::builder().max(9).build();
+        assert_eq!(ida.alloc_exact_or_next(3), Some((3, true)));
+        assert_eq!(ida.alloc_exact_or_next(0), Some((0, true)));
+
+        // A taken preference falls through to the next free ID.
+        ida.alloc_at(4).unwrap();
+        assert_eq!(ida.alloc_exact_or_next(3), Some((5, false)));
+
+        // With nothing free above it, the lowest free ID is used.
+        for id in 6..=9 {
+            ida.alloc_at(id).unwrap();
+        }
+        assert_eq!(ida.alloc_exact_or_next(9), Some((1, false)));
+        assert_eq!(ida.alloc_exact_or_next(100), Some((2, false)));
+        assert_eq!(ida.alloc_exact_or_next(2), None);
+
+        ida.free(7);
+        assert_eq!(ida.alloc_exact_or_next(7), Some((7, true)));
+    }
+
+    #[test]
+    fn test_bulk_is_allocated_matches_individual_checks() {
+        let ida = Ida::new();
+        for _ in 0..300 {
+            ida.alloc();
+        }
+        for id in (0..300).step_by(7) {
+            ida.free(id);
+        }
+        ida.alloc_at(1 << 30).unwrap();
+
+        let mut ids: Vec<usize> = (0..320).collect();
+        ids.extend([1 << 30, (1 << 30) + 1, 5, 64, 63, 5, usize::MAX]);
+        let expected: Vec<bool> = ids.iter().map(|&id| ida.is_allocated(id)).collect();
+        assert_eq!(ida.bulk_is_allocated(&ids), expected);
+        assert!(ida.bulk_is_allocated(&[]).is_empty());
+    }
+
+    /// Builds an allocator with holes, a freed range and far-off IDs for
+    /// the ordered iterator tests, along with the starting points to probe.
+    fn iter_fixture() -> (Ida, [usize; 12]) {
+        let ida = Ida::new();
+        for _ in 0..500 {
+            ida.alloc();
+        }
+        ida.free_range_counted(100, 200);
+        for id in (0..500).step_by(3) {
+            ida.free(id);
+        }
+        ida.alloc_at(1 << 40).unwrap();
+        ida.alloc_at(usize::MAX).unwrap();
+        let starts = [
+            0,
+            1,
+            63,
+            64,
+            99,
+            100,
+            299,
+            300,
+            499,
+            500,
+            1 << 40,
+            usize::MAX,
+        ];
+        (ida, starts)
+    }
+
+    #[test]
+    fn test_iter_from_matches_filtered_snapshot() {
+        let (ida, starts) = iter_fixture();
+        let all = ida.snapshot();
+        for k in starts {
+            let expected: Vec<usize> = all.iter().copied().filter(|&id| id >= k).collect();
+            assert_eq!(ida.iter_from(k).collect::<Vec<_>>(), expected, "k = {k}");
+        }
+    }
+
+    #[test]
+    fn test_free_iter_matches_per_id_free() {
+        let build = || {
+            let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+            for _ in 0..5_000 {
+                ida.alloc();
+            }
+            ida
+        };
+        let batch: Vec<usize> = (0..5_000)
+            .filter(|id| id % 3 == 0 || (1_000..4_200).contains(id))
+            .chain([7_000, 12])
+            .collect();
+
+        let bulk = build();
+        let single = build();
+        let freed = bulk.free_iter(batch.iter().copied());
+        for &id in &batch {
+            single.free(id);
+        }
+        assert_eq!(freed, 5_000 - single.len());
+        assert_eq!(bulk.snapshot(), single.snapshot());
+        assert_eq!(bulk.len(), single.len());
+        assert!(bulk.deep_equal_tree(&single));
+        assert!(bulk.inner.lock().root.is_consistent(IDA_MAX_LEVELS - 1));
+        // Freed IDs were queued in the order they were freed.
+        let bulk_next: Vec<usize> = (0..3).map(|_| bulk.alloc().unwrap()).collect();
+        let single_next: Vec<usize> = (0..3).map(|_| single.alloc().unwrap()).collect();
+        assert_eq!(bulk_next, single_next);
+
+        // Unsorted input and frees that empty the allocator work too.
+        let ida = Ida::new();
+        for _ in 0..130 {
+            ida.alloc();
+        }
+        assert_eq!(ida.free_iter((0..130).rev().chain([5, 200])), 130);
+        assert!(ida.is_empty());
+        assert_eq!(ida.node_count(), 1);
+    }
+
+    #[test]
+    fn test_alloc_soft_watermark() {
+        let ida = Ida::new();
+        for expected in 0..10 {
+            assert_eq!(ida.alloc_soft(10, 12), Ok((expected, false)));
+        }
+        // Past the soft line allocation continues, flagged.
+        assert_eq!(ida.alloc_soft(10, 12), Ok((10, true)));
+
+        // Dropping back under the soft line clears the flag again.
+        ida.free(4);
+        assert_eq!(ida.alloc_soft(10, 12), Ok((4, false)));
+
+        assert_eq!(ida.alloc_soft(10, 12), Ok((11, true)));
+        assert_eq!(ida.alloc_soft(10, 12), Err(AllocError::Exhausted));
+        assert_eq!(ida.len(), 12);
+        assert_eq!(ida.alloc_soft(0, 0), Err(AllocError::Exhausted));
+
+        // The allocator's own bound caps the hard limit.
+        let bounded = Ida::builder().max(1).build();
+        assert_eq!(bounded.alloc_soft(1, 100), Ok((0, false)));
+        assert_eq!(bounded.alloc_soft(1, 100), Ok((1, true)));
+        assert_eq!(bounded.alloc_soft(1, 100), Err(AllocError::Exhausted));
+    }
+
+    #[test]
+    fn test_bytes_round_trip_sparse() {
+        let ida = Ida::new();
+        for id in [
+            0,
+            1,
+            2,
+            63,
+            64,
+            65,
+            4_095,
+            4_096,
+            1 << 20,
+            1 << 40,
+            usize::MAX - 1,
+        ] {
+            ida.alloc_at(id).unwrap();
+        }
+        for id in 10_000..10_500 {
+            ida.alloc_at(id).unwrap();
+        }
+
+        let bytes = ida.to_bytes();
+        let decoded = Ida::from_bytes(&bytes, ida.len()).unwrap();
+        assert_eq!(decoded.snapshot(), ida.snapshot());
+        assert_eq!(decoded.len(), ida.len());
+        assert!(decoded.deep_equal_tree(&ida));
+        assert!(
+            Ida::from_bytes(&Ida::new().to_bytes(), 0)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_bytes_rejects_corrupt_buffers() {
+        let ida = Ida::from_ranges(&[(3, 9), (200, 200)]).unwrap();
+        let bytes = ida.to_bytes();
+        for cut in 0..bytes.len() {
+            assert_eq!(
+                Ida::from_bytes(&bytes[..cut], 100).unwrap_err(),
+                DecodeError::Truncated
+            );
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Ida::from_bytes(&trailing, 100).unwrap_err(),
+            DecodeError::TrailingBytes
+        );
+
+        // An empty run, and runs that overlap or go backwards.
+        assert_eq!(
+            Ida::from_bytes(&[1, 3, 0], 100).unwrap_err(),
+            DecodeError::Malformed
+        );
+        assert_eq!(
+            Ida::from_bytes(&[2, 3, 4, 5, 1], 100).unwrap_err(),
+            DecodeError::Malformed
+        );
+        assert_eq!(
+            Ida::from_bytes(&[2, 9, 1, 3, 1], 100).unwrap_err(),
+            DecodeError::Malformed
+        );
+
+        // A varint longer than a usize, and a run past `usize::MAX`.
+        let mut huge = vec![1];
+        huge.extend([0xff; 10]);
+        huge.push(0x01);
+        assert_eq!(
+            Ida::from_bytes(&huge, 100).unwrap_err(),
+            DecodeError::Overflow
+        );
+        let mut past_end = vec![1];
+        write_varint(&mut past_end, usize::MAX);
+        write_varint(&mut past_end, 2);
+        assert_eq!(
+            Ida::from_bytes(&past_end, 100).unwrap_err(),
+            DecodeError::Overflow
+        );
+
+        // A run of 2^30 IDs in seven bytes is refused before any of it is
+        // built, as is one whose damage only shows at the end.
+        let mut big = vec![1, 0, 128, 128, 128, 128, 4];
+        assert_eq!(
+            Ida::from_bytes(&big, 1 << 20).unwrap_err(),
+            DecodeError::TooManyIds
+        );
+        big.push(0);
+        assert_eq!(
+            Ida::from_bytes(&big, usize::MAX).unwrap_err(),
+            DecodeError::TrailingBytes
+        );
+        assert_eq!(
+            Ida::from_bytes(&bytes, 7).unwrap_err(),
+            DecodeError::TooManyIds
+        );
+        assert_eq!(Ida::from_bytes(&bytes, 8).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn test_lazy_free_defers_pruning() {
+        let ida = Ida::builder().lazy().build();
+        for _ in 0..10_000 {
+            ida.alloc();
+        }
+        let full_nodes = ida.node_count();
+        let full_memory = ida.memory_usage();
+
+        for id in 0..10_000 {
+            ida.free(id);
+        }
+        assert!(ida.is_empty());
+        assert_eq!(ida.node_count(), full_nodes);
+        assert!(ida.inner.lock().root.is_consistent(IDA_MAX_LEVELS - 1));
+
+        // Un-maintained nodes do not hide freed IDs.
+        for expected in 0..5_000 {
+            assert_eq!(ida.alloc(), Some(expected));
+        }
+        ida.free(1_234);
+        assert_eq!(ida.alloc(), Some(1_234));
+        for id in 0..5_000 {
+            ida.free(id);
+        }
+
+        ida.maintain();
+        assert_eq!(ida.node_count(), 1);
+        assert!(ida.memory_usage() < full_memory);
+        assert!(ida.inner.lock().dirty_leaves.is_empty());
+
+        // Maintaining keeps nodes that still hold IDs.
+        let kept = ida.alloc().unwrap();
+        ida.alloc_at(100_000).unwrap();
+        ida.free(100_000);
+        ida.maintain();
+        assert!(ida.is_allocated(kept));
+        assert_eq!(ida.node_count(), IDA_MAX_LEVELS);
+        assert_eq!(ida.alloc(), Some(kept + 1));
+    }
+
+    #[test]
+    fn test_union_assign_is_set_union() {
+        let a = Ida::new();
+        let b = Ida::new();
+        for id in (0..3_000).step_by(2).chain([1 << 30, 70_000]) {
+            a.alloc_at(id).unwrap();
+        }
+        for id in (0..5_000).step_by(3).chain([1 << 40, 70_000]) {
+            b.alloc_at(id).unwrap();
+        }
+        let mut expected = a.snapshot();
+        expected.extend(b.snapshot());
+        expected.sort_unstable();
+        expected.dedup();
+
+        let b_before = b.snapshot();
+        a.union_assign(&b);
+        assert_eq!(a.snapshot(), expected);
+        assert_eq!(a.len(), expected.len());
+        assert!(a.inner.lock().root.is_consistent(IDA_MAX_LEVELS - 1));
+        assert_eq!(b.snapshot(), b_before);
+
+        // Unioning again changes nothing, and the shared subtrees stay
+        // independent of `b`.
+        a.union_assign(&b);
+        a.union_assign(&a);
+        assert_eq!(a.len(), expected.len());
+        a.free(1 << 40);
+        assert!(b.is_allocated(1 << 40));
+
+        // Filling the gaps of a leaf through a union marks it full.
+        let evens = Ida::new();
+        let odds = Ida::new();
+        for id in 0..64 {
+            let target = if id % 2 == 0 { &evens } else { &odds };
+            target.alloc_at(id).unwrap();
+        }
+        evens.union_assign(&odds);
+        assert!(evens.inner.lock().root.is_consistent(IDA_MAX_LEVELS - 1));
+        assert_eq!(evens.alloc(), Some(64));
+
+        // IDs this allocator may not hand out are left out.
+        let bounded = Ida::builder().max(99).build();
+        bounded.union_assign(&Ida::from_ranges(&[(90, 200)]).unwrap());
+        assert_eq!(bounded.as_ranges(), vec![(90, 99)]);
+        assert_eq!(bounded.len(), 10);
+        let guarded = Ida::builder().blacklist(&[3]).build();
+        guarded.union_assign(&Ida::from_ranges(&[(0, 5)]).unwrap());
+        assert_eq!(guarded.snapshot(), vec![0, 1, 2, 4, 5]);
+        assert_eq!(guarded.len(), 5);
+    }
+
+    #[test]
+    fn test_replay_matches_live_operations() {
+        let live = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        let mut journal = Vec::new();
+        let mut rng = 0x5eed;
+        let mut held = Vec::new();
+        for round in 0..2_000 {
+            if round % 3 == 2 && !held.is_empty() {
+                let id = held.swap_remove(splitmix64(&mut rng) as usize % held.len());
+                live.free(id);
+                journal.push(Op::Free(id));
+            } else {
+                let id = live.alloc().unwrap();
+                held.push(id);
+                journal.push(Op::Alloc(id));
+            }
+        }
+        let replayed = Ida::replay(journal.iter().copied());
+        assert_eq!(replayed.snapshot(), live.snapshot());
+        assert_eq!(replayed.len(), live.len());
+
+        // A fixed sequence gives a predictable result, including no-ops.
+        let ops = [
+            Op::Alloc(3),
+            Op::Alloc(3),
+            Op::Alloc(64),
+            Op::Free(9),
+            Op::Free(3),
+            Op::Alloc(1 << 50),
+        ];
+        let ida = Ida::replay(ops.into_iter());
+        assert_eq!(ida.snapshot(), vec![64, 1 << 50]);
+        assert_eq!(ida.len(), 2);
+    }
+
+    #[test]
+    fn test_free_if_divisible_by_seven() {
+        let ida = Arc::new(Ida::new());
+        for _ in 0..100 {
+            ida.alloc();
+        }
+        let candidates: Vec<usize> = (0..150).collect();
+
+        // The predicate runs without the lock held, so it may query the
+        // allocator itself.
+        let observer = ida.clone();
+        let freed = ida.free_if(&candidates, |id| observer.is_allocated(id) && id % 7 == 0);
+        assert_eq!(freed, 15);
+        for id in 0..100 {
+            assert_eq!(ida.is_allocated(id), id % 7 != 0, "id {id}");
+        }
+        assert_eq!(ida.len(), 85);
+
+        // Non-candidates are never touched, even if they match.
+        assert_eq!(ida.free_if(&[1, 2, 3], |id| id % 7 == 0), 0);
+        assert_eq!(ida.free_if(&[1, 2, 3, 1], |_| true), 3);
+        assert_eq!(ida.len(), 82);
+    }
+
+    #[test]
+    fn test_alloc_spaced_guard_band() {
+        let ida = Ida::new();
+        assert_eq!(ida.alloc_spaced(1), Some(0));
+        assert_eq!(ida.alloc_spaced(1), Some(2));
+        assert_eq!(ida.alloc_spaced(1), Some(4));
+
+        // A freed ID whose neighbours are free is reused.
+        ida.free(2);
+        assert_eq!(ida.alloc_spaced(1), Some(2));
+
+        // Once a neighbour is taken, the freed ID is passed over.
+        ida.free(2);
+        ida.alloc_at(3).unwrap();
+        assert_eq!(ida.alloc_spaced(1), Some(6));
+        assert!(!ida.is_allocated(2));
+
+        // Guard IDs are not reserved against plain allocation.
+        assert_eq!(ida.alloc(), Some(1));
+
+        // With no spaced ID left, the lowest free ID is used.
+        let small = Ida::builder().max(4).build();
+        assert_eq!(small.alloc_spaced(1), Some(0));
+        assert_eq!(small.alloc_spaced(1), Some(2));
+        assert_eq!(small.alloc_spaced(1), Some(4));
+        assert_eq!(small.alloc_spaced(1), Some(1));
+        assert_eq!(small.alloc_spaced(1), Some(3));
+        assert_eq!(small.alloc_spaced(1), None);
+
+        // Huge bands never overflow.
+        let wide = Ida::new();
+        assert_eq!(wide.alloc_spaced(usize::MAX), Some(0));
+        assert_eq!(wide.alloc_spaced(usize::MAX), Some(1));
+    }
+
+    #[test]
+    fn test_to_flags_matches_is_allocated() {
+        let ida = Ida::new();
+        for id in [0, 5, 63, 64, 130, 4_095, 4_096, 9_000] {
+            ida.alloc_at(id).unwrap();
+        }
+        let flags = ida.to_flags(10_000);
+        assert_eq!(flags.len(), 10_000);
+        for (id, &flag) in flags.iter().enumerate() {
+            assert_eq!(flag, ida.is_allocated(id) as u8, "id {id}");
+        }
+
+        // Subtrees that were never allocated read as all zeros.
+        let sparse = Ida::new();
+        sparse.alloc_at(1 << 20).unwrap();
+        assert!(sparse.to_flags(50_000).iter().all(|&flag| flag == 0));
+        assert!(ida.to_flags(0).is_empty());
+        assert_eq!(ida.to_flags(6), [1, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_memory_drops_after_dense_node_goes_sparse() {
+        // Populate all 64 leaves under one first-level node, then free all
+        // but a couple of them. Children live in a `BTreeMap`, which keeps
+        // no spare capacity, so the estimate must fall right back.
+        let ida = Ida::new();
+        for leaf in 0..IDA_BITMAP_BITS {
+            ida.alloc_at(leaf * IDA_BITMAP_BITS).unwrap();
+        }
+        let dense = ida.memory_usage();
+        for leaf in 2..IDA_BITMAP_BITS {
+            ida.free(leaf * IDA_BITMAP_BITS);
+        }
+        ida.compact();
+        let sparse = ida.memory_usage();
+        assert!(sparse < dense);
+
+        let fresh = Ida::new();
+        fresh.alloc_at(0).unwrap();
+        fresh.alloc_at(IDA_BITMAP_BITS).unwrap();
+        assert_eq!(sparse, fresh.memory_usage());
+        assert!(ida.deep_equal_tree(&fresh));
+    }
+
+    #[test]
+    fn test_recycle_delay_holds_freed_ids() {
+        let ida = Ida::builder().recycle_delay(3).build();
+        assert_eq!(ida.alloc(), Some(0));
+        ida.free(0);
+        assert!(!ida.is_allocated(0));
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), Some(0));
+
+        // Each freed ID waits from its own free, and they come back in
+        // ascending order once ready.
+        ida.free(2);
+        ida.alloc();
+        ida.free(1);
+        ida.alloc();
+        ida.alloc();
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(1));
+
+        // Range frees are delayed too, and re-freeing restarts the delay.
+        ida.free_range_counted(0, 3);
+        ida.alloc_at(0).unwrap();
+        assert!(ida.alloc().unwrap() > 3);
+        ida.free(0);
+        assert!(ida.alloc().unwrap() > 3);
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(0));
+
+        // When nothing else is free, waiting IDs stay unavailable unless
+        // the caller opts in, which takes the longest-waiting one.
+        let small = Ida::builder().recycle_delay(100).max(1).build();
+        small.alloc();
+        small.alloc();
+        small.free(1);
+        small.free(0);
+        assert_eq!(small.alloc(), None);
+        assert_eq!(small.alloc_or_recycle(), Some(1));
+        assert_eq!(small.alloc_or_recycle(), Some(0));
+        assert_eq!(small.alloc_or_recycle(), None);
+
+        // Every allocating method advances the delay, not just `alloc`.
+        let ida = Ida::builder().recycle_delay(2).build();
+        for _ in 0..4 {
+            ida.alloc();
+        }
+        ida.free(1);
+        assert_eq!(ida.alloc_in_range(..), Some(4));
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(5));
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(1));
+        ida.free(2);
+        ida.alloc_at(100).unwrap();
+        assert_eq!(ida.alloc_contiguous_high(2), Some(101));
+        assert_eq!(ida.alloc_in_range(..), Some(2));
+    }
+
+    #[test]
+    fn test_iter_rev_from_matches_filtered_snapshot() {
+        let (ida, starts) = iter_fixture();
+        let all = ida.snapshot();
+        for start in starts {
+            let expected: Vec<usize> = all
+                .iter()
+                .copied()
+                .filter(|&id| id <= start)
+                .rev()
+                .collect();
+            assert_eq!(
+                ida.iter_rev_from(start).collect::<Vec<_>>(),
+                expected,
+                "start = {start}"
+            );
+        }
+        assert_eq!(Ida::new().iter_rev_from(usize::MAX).next(), None);
+
+        // Starting on the first ID of a leaf steps back into the previous
+        // leaf, skipping the hole at its top.
+        assert_eq!(
+            ida.iter_rev_from(64).take(3).collect::<Vec<_>>(),
+            [64, 62, 61]
+        );
+        // Nothing at or below the start: 0 was freed.
+        assert_eq!(ida.iter_rev_from(0).next(), None);
+        // The far-off IDs are reached from across absent subtrees.
+        assert_eq!(ida.iter_rev_from(usize::MAX - 1).next(), Some(1 << 40));
+    }
+
+    #[test]
+    fn test_lifo_reuse_policy() {
+        let ida = Ida::builder().policy(AllocPolicy::LifoReuse(4)).build();
+        for _ in 0..5 {
+            ida.alloc();
+        }
+        for id in [0, 1, 2] {
+            ida.free(id);
+        }
+        // The most recently freed ID comes back first, then the tree.
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(1));
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(5));
+
+        // Stacked IDs taken by other means are skipped, and range frees are
+        // stacked in ascending order.
+        ida.free_range_counted(1, 3);
+        ida.alloc_at(3).unwrap();
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(1));
+
+        // Frees beyond the window overflow to the tree search.
+        let ida = Ida::builder().policy(AllocPolicy::LifoReuse(2)).build();
+        for _ in 0..5 {
+            ida.alloc();
+        }
+        for id in [0, 1, 2, 3] {
+            ida.free(id);
+        }
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc(), Some(2));
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(1));
+
+        // A range free keeps only the window's worth of its highest IDs.
+        for _ in 0..10_000 {
+            ida.alloc();
+        }
+        assert_eq!(ida.free_range_counted(0, 10_000), 10_000);
+        assert_eq!(ida.inner.lock().free_list, [9_998, 9_999]);
+    }
+
+    #[test]
+    fn test_leaf_word_reflects_leaf_bits() {
+        let ida = Ida::new();
+        let base = 5 * IDA_BITMAP_BITS;
+        for bit in [0, 1, 17, 63] {
+            ida.alloc_at(base + bit).unwrap();
+        }
+        // Neighbouring leaves do not bleed into the word.
+        ida.alloc_at(base - 1).unwrap();
+        ida.alloc_at(base + 64).unwrap();
+
+        let expected = (1 << 0) | (1 << 1) | (1 << 17) | (1 << 63);
+        for id in [base, base + 17, base + 63] {
+            assert_eq!(ida.leaf_word(id), (base, expected));
+        }
+        ida.free(base + 17);
+        assert_eq!(ida.leaf_word(base + 30), (base, expected & !(1 << 17)));
+
+        // Absent leaves, including ones far out in the tree, read as zero.
+        assert_eq!(ida.leaf_word(1 << 40), (1 << 40, 0));
+        assert_eq!(ida.leaf_word(usize::MAX), (usize::MAX - 63, 0));
+    }
+
+    #[test]
+    fn test_health_report_matches_individual_queries() {
+        let ida = Ida::new();
+        let empty = ida.health_report();
+        assert_eq!(empty.peak, None);
+        assert_eq!(empty.longest_free_run, 0);
+        assert!(empty.largest_gaps.is_empty());
+
+        for _ in 0..3000 {
+            ida.alloc();
+        }
+        // Twelve gaps of growing length, some spanning leaf boundaries.
+        for k in 1..=12 {
+            for id in k * 200..k * 200 + k * 10 {
+                ida.free(id);
+            }
+        }
+        ida.alloc_at(1 << 20).unwrap();
+
+        let report = ida.health_report();
+        assert_eq!(report.len, ida.len());
+        assert_eq!(report.peak, ida.snapshot().last().copied());
+        assert_eq!(report.node_count, ida.node_count());
+        assert_eq!(report.memory_usage, ida.memory_usage());
+        assert_eq!(report.fragmentation, ida.fragmentation());
+
+        let mut gaps: Vec<_> = ida.iter_gaps().collect();
+        gaps.sort_by_key(|&(start, len)| (usize::MAX - len, start));
+        gaps.truncate(HEALTH_REPORT_GAPS);
+        assert_eq!(report.largest_gaps, gaps);
+        assert_eq!(report.largest_gaps[0], (3000, (1 << 20) - 3000));
+        assert_eq!(report.largest_gaps[1], (2400, 120));
+        assert_eq!(report.longest_free_run, (1 << 20) - 3000);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use alloc::format;
-    use alloc::vec::Vec;
-    use std::sync::{Arc, Mutex};
-    use std::thread;
+    #[test]
+    fn test_alloc_if_unfragmented_guard() {
+        let ida = Ida::new();
+        for _ in 0..1000 {
+            ida.alloc();
+        }
+        // Free every other ID below 800, leaving 400 holes.
+        for id in (0..800).step_by(2) {
+            ida.free(id);
+        }
+        assert_eq!(ida.fragmentation(), 0.4);
+
+        // Filling a hole only brings it down to 399 holes in 1000.
+        assert_eq!(
+            ida.alloc_if_unfragmented(0.3),
+            Err(FragmentationError::TooHigh(0.399))
+        );
+        assert_eq!(ida.len(), 600);
+        assert_eq!(ida.alloc_if_unfragmented(0.4), Ok(0));
+        assert_eq!(ida.alloc_if_unfragmented(0.399), Ok(2));
+
+        // Compacting the holes away satisfies even a strict guard.
+        let dense = Ida::new();
+        dense.reset_to(ida.snapshot().iter().enumerate().map(|(k, _)| k));
+        assert_eq!(dense.fragmentation(), 0.0);
+        assert_eq!(dense.alloc_if_unfragmented(0.0), Ok(602));
+
+        let full = Ida::builder().max(0).build();
+        full.alloc();
+        assert_eq!(
+            full.alloc_if_unfragmented(1.0),
+            Err(FragmentationError::Exhausted)
+        );
+        assert_eq!(Ida::new().fragmentation(), 0.0);
+
+        // The candidate follows the policy: FIFO reuse refills the oldest
+        // hole first, and the stats count the allocation.
+        let ida = Ida::builder().policy(AllocPolicy::FifoReuse).build();
+        for _ in 0..10 {
+            ida.alloc();
+        }
+        ida.free(7);
+        ida.free(3);
+        assert_eq!(ida.alloc_if_unfragmented(1.0), Ok(7));
+        assert_eq!(ida.alloc_if_unfragmented(1.0), Ok(3));
+        assert_eq!(ida.alloc_if_unfragmented(1.0), Ok(10));
+        assert_eq!(ida.inner.lock().alloc_count, 13);
+
+        // Under a stride the holes between strided IDs are passed over, so
+        // the guard judges the ID the stride picks, not the lowest hole.
+        let ida = Ida::builder().stride(4).build();
+        for _ in 0..4 {
+            ida.alloc();
+        }
+        assert_eq!(
+            ida.alloc_if_unfragmented(0.65),
+            Err(FragmentationError::TooHigh(12.0 / 17.0))
+        );
+        assert_eq!(ida.alloc_if_unfragmented(0.75), Ok(16));
+    }
 
     #[test]
-    fn test_alloc_and_free_simple() {
-        let ida = Ida::default();
-        assert_eq!(ida.alloc(), Some(0));
-        assert_eq!(ida.alloc(), Some(1));
-        assert_eq!(ida.alloc(), Some(2));
+    fn test_alloc_avoiding_skips_overlay() {
+        let ida = Ida::new();
+        for _ in 0..5 {
+            ida.alloc();
+        }
 
-        ida.free(1); // Free an ID in the middle.
-        assert_eq!(ida.alloc(), Some(1)); // Should be reused.
+        // The overlay claims the lowest free ID.
+        assert_eq!(ida.alloc_avoiding(&[1 << 5]), Some(6));
+        assert!(!ida.is_allocated(5));
+        assert_eq!(ida.len(), 6);
 
-        ida.free(0);
-        ida.free(2);
-        assert_eq!(ida.alloc(), Some(0));
-        assert_eq!(ida.alloc(), Some(2));
+        // Whole claimed leaves are passed over, and IDs past the end of the
+        // overlay are clear.
+        let overlay = [u64::MAX, u64::MAX, !(1 << 9)];
+        assert_eq!(ida.alloc_avoiding(&overlay), Some(128 + 9));
+        assert_eq!(ida.alloc_avoiding(&overlay), Some(192));
+        assert_eq!(ida.alloc_avoiding(&[]), Some(5));
+
+        let bounded = Ida::builder().max(127).build();
+        assert_eq!(bounded.alloc_avoiding(&[u64::MAX, u64::MAX]), None);
+        assert_eq!(bounded.alloc_avoiding(&[u64::MAX, !(1 << 63)]), Some(127));
+        assert_eq!(bounded.len(), 1);
     }
 
     #[test]
-    fn test_first_level_boundary() {
-        let ida = Ida::default();
-        // Allocate the first 64 IDs to fill the first leaf.
-        for i in 0..64 {
-            assert_eq!(ida.alloc(), Some(i));
+    fn test_drain_filter_frees_matching_ids() {
+        let ida = Ida::new();
+        for _ in 0..200 {
+            ida.alloc();
         }
+        ida.alloc_at(1 << 33 | 1).unwrap();
 
-        // The next allocation should cross the boundary into a new leaf.
-        assert_eq!(ida.alloc(), Some(64));
-        assert_eq!(ida.alloc(), Some(65));
+        // The predicate may call back into the allocator.
+        let drained = ida.drain_filter(|id| id % 2 == 1 && ida.is_allocated(id));
+        let mut odd: Vec<usize> = (1..200).step_by(2).collect();
+        odd.push(1 << 33 | 1);
+        assert_eq!(drained, odd);
+        assert!(drained.iter().all(|&id| !ida.is_allocated(id)));
+        assert_eq!(ida.snapshot(), (0..200).step_by(2).collect::<Vec<_>>());
 
-        // Free the boundary-crossing ID and ensure it's reused.
-        ida.free(64);
-        assert_eq!(ida.alloc(), Some(64));
+        assert_eq!(ida.drain_filter(|id| id % 2 == 1), Vec::<usize>::new());
+        assert_eq!(ida.alloc(), Some(1));
     }
 
     #[test]
-    fn test_second_level_boundary() {
-        let ida = Ida::default();
-        let count = IDA_BITMAP_BITS * IDA_BITMAP_BITS; // 64 * 64 = 4096
+    fn test_with_span_bounds_both_ends() {
+        let ida = Ida::builder().span(5000, 5100).build();
+        assert_eq!(ida.total_capacity(), 100);
+        assert_eq!(ida.alloc(), Some(5000));
+        let rest: Vec<usize> = core::iter::from_fn(|| ida.alloc()).collect();
+        assert_eq!(rest, (5001..5100).collect::<Vec<_>>());
+        assert_eq!(ida.len(), 100);
+        assert!(ida.is_full());
 
-        // Allocate enough IDs to fill an entire first-level child node.
-        for i in 0..count {
-            assert_eq!(ida.alloc(), Some(i));
+        // Out-of-span IDs are rejected, whichever way they are named.
+        assert_eq!(ida.alloc_at(4999), Err(AllocError::OutOfRange));
+        assert_eq!(ida.alloc_at(5100), Err(AllocError::OutOfRange));
+        assert!(!ida.is_allocated(0) && !ida.is_allocated(5100));
+        ida.free(4999);
+        assert_eq!(ida.len(), 100);
+
+        ida.free(5042);
+        assert!(!ida.is_full());
+        assert_eq!(ida.alloc(), Some(5042));
+
+        // Searches that start below the span are lifted into it.
+        let ida = Ida::builder().span(130, 300).build();
+        assert_eq!(ida.alloc_in_range(..200), Some(130));
+        assert_eq!(ida.alloc_buddy(4), Some(144));
+        assert_eq!(ida.alloc_blocks(10, 1), Some(vec![131]));
+        assert_eq!(ida.alloc_contiguous_high(500), None);
+        assert_eq!(ida.alloc_parity(Parity::Odd), Some(141));
+        assert_eq!(ida.alloc_bounded_effort(usize::MAX), Ok(142));
+        assert!(ida.snapshot().iter().all(|id| (130..300).contains(id)));
+
+        // IDs merged in from below the span are dropped, and the bound
+        // cannot be lowered past the span's start.
+        let ida = Ida::builder().span(130, 300).build();
+        ida.union_assign(&Ida::from_ranges(&[(0, 10), (140, 141)]).unwrap());
+        assert_eq!(ida.as_ranges(), vec![(140, 141)]);
+        assert_eq!(ida.len(), 2);
+        assert!(ida.fragmentation() >= 0.0);
+        assert_eq!(ida.set_max(10), Err(ResizeError::BelowMin(130)));
+        assert_eq!(ida.set_max(200), Ok(()));
+
+        // Spreading starts from the span rather than walking up to it.
+        let ida = Ida::builder().span(1 << 48, usize::MAX).build();
+        assert_eq!(
+            ida.alloc_spread(2),
+            Some(vec![1 << 48, (1 << 48) + IDA_BITMAP_BITS])
+        );
+    }
+
+    #[test]
+    fn test_is_range_allocated() {
+        let ida = Ida::new();
+        // A run crossing the boundary between two first-level subtrees.
+        let start = 4096 - 100;
+        for id in start..start + 300 {
+            ida.alloc_at(id).unwrap();
         }
 
-        // The next allocation should cross a major boundary.
-        assert_eq!(ida.alloc(), Some(count));
+        assert!(ida.is_range_allocated(start, 300));
+        assert!(ida.is_range_allocated(4090, 20));
+        assert!(!ida.is_range_allocated(start - 1, 2));
+        assert!(!ida.is_range_allocated(start + 299, 2));
+        assert!(!ida.is_range_allocated(0, 64));
+        assert!(!ida.is_range_allocated(1 << 40, 1));
+        assert!(ida.is_range_allocated(12_345, 0));
 
-        // Free it and ensure it's reused.
-        ida.free(count);
-        assert_eq!(ida.alloc(), Some(count));
+        // A single hole is found on either side of the boundary.
+        ida.free(4097);
+        assert!(!ida.is_range_allocated(start, 300));
+        assert!(ida.is_range_allocated(start, 101));
+        ida.free(4000);
+        assert!(!ida.is_range_allocated(3990, 20));
+
+        let top = Ida::new();
+        top.alloc_at(usize::MAX).unwrap();
+        assert!(top.is_range_allocated(usize::MAX, 1));
+        assert!(!top.is_range_allocated(usize::MAX, 2));
     }
 
     #[test]
-    fn test_free_unallocated() {
-        let ida = Ida::default();
-        ida.free(100); // Free an ID that was never allocated.
-        assert_eq!(ida.alloc(), Some(0)); // The first ID should still be 0.
+    fn test_alloc_blocks_are_contiguous_and_disjoint() {
+        let ida = Ida::new();
+        // Obstacles leave a few gaps too small for a block.
+        for id in [3, 30, 40, 41, 100] {
+            ida.alloc_at(id).unwrap();
+        }
+
+        let bases = ida.alloc_blocks(16, 4).unwrap();
+        assert_eq!(bases, [4, 42, 58, 74]);
+        let mut covered = Vec::new();
+        for &base in &bases {
+            covered.extend(base..base + 16);
+        }
+        covered.sort_unstable();
+        covered.dedup();
+        assert_eq!(covered.len(), 4 * 16);
+        assert!(covered.iter().all(|&id| ida.is_allocated(id)));
+        assert_eq!(ida.len(), 5 + 4 * 16);
+
+        // All or nothing: a request that cannot be met allocates nothing.
+        let bounded = Ida::builder().max(99).build();
+        bounded.alloc_at(50).unwrap();
+        assert_eq!(bounded.alloc_blocks(25, 4), None);
+        assert_eq!(bounded.len(), 1);
+        assert_eq!(bounded.alloc_blocks(25, 3), Some(vec![0, 25, 51]));
+        assert_eq!(bounded.alloc_blocks(0, 1), None);
+        assert_eq!(bounded.alloc_blocks(1, 0), Some(vec![]));
     }
 
     #[test]
-    fn test_stress_and_random_free() {
-        let ida = Ida::default();
-        let mut ids = alloc::vec::Vec::new();
-        let count = 10_000;
+    fn test_misuse_panics_report_caller_location() {
+        use std::cell::{Cell, RefCell};
+        use std::panic::{self, AssertUnwindSafe};
+        use std::string::String;
 
-        // 1. Allocate a large number of IDs.
-        for _ in 0..count {
-            if let Some(id) = ida.alloc() {
-                ids.push(id);
-            } else {
-                panic!("Allocator ran out of space unexpectedly.");
-            }
+        std::thread_local! {
+            static CAPTURING: Cell<bool> = const { Cell::new(false) };
+            static REPORTED: RefCell<Option<(String, u32)>> = const { RefCell::new(None) };
         }
 
-        // 2. Free a random subset of those IDs.
-        let mut freed_ids = alloc::vec::Vec::new();
-        for (i, &id) in ids.iter().enumerate() {
-            if i % 3 == 0 || i % 7 == 0 {
-                // Arbitrary condition for freeing
-                ida.free(id);
-                freed_ids.push(id);
+        // Panics on this thread while capturing are recorded; every other
+        // panic, including those of tests running alongside, goes to the
+        // previous hook as usual. The guard reinstates it even if an
+        // assertion below fails.
+        type Hook = Arc<dyn Fn(&panic::PanicHookInfo<'_>) + Send + Sync>;
+        struct RestoreHook(Hook);
+        impl Drop for RestoreHook {
+            fn drop(&mut self) {
+                let previous = self.0.clone();
+                panic::set_hook(Box::new(move |info| previous(info)));
             }
         }
+        let previous: Hook = Arc::from(panic::take_hook());
+        let _restore = RestoreHook(previous.clone());
+        panic::set_hook(Box::new(move |info| {
+            if CAPTURING.with(Cell::get) {
+                let location = info.location().map(|l| (String::from(l.file()), l.line()));
+                REPORTED.with(|reported| *reported.borrow_mut() = location);
+            } else {
+                previous(info);
+            }
+        }));
 
-        // Sort the freed IDs to make reallocation predictable.
-        freed_ids.sort();
+        // Each call must sit on the same line as the macro invocation.
+        macro_rules! assert_reported_here {
+            ($call:expr) => {{
+                let line = line!();
+                CAPTURING.with(|capturing| capturing.set(true));
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let _ = $call;
+                }));
+                CAPTURING.with(|capturing| capturing.set(false));
+                assert!(result.is_err(), "{} did not panic", stringify!($call));
+                let reported = REPORTED.with(|reported| reported.borrow_mut().take());
+                assert_eq!(reported, Some((String::from(file!()), line)));
+            }};
+        }
 
-        // 3. Allocate new IDs and check if they are the same as the freed ones.
-        for &expected_id in &freed_ids {
-            assert_eq!(ida.alloc(), Some(expected_id));
+        let ida = Ida::builder().max(10).build();
+        assert_reported_here!(Ida::builder().stride(0).build());
+        assert_reported_here!(Ida::builder().span(7, 7).build());
+        assert_reported_here!(Ida::builder().span(10, 20).max(5).build());
+        assert_reported_here!(Ida::region_of(5, 0));
+        assert_reported_here!(ida.alloc_aligned_in(3, ..));
+        assert_reported_here!(ida.subtree_usage(0, IDA_MAX_LEVELS));
+        assert_reported_here!(ida.iter_chunks(IDA_ID_BITS + 1));
+        assert_reported_here!(ShardedIda::new(0));
+    }
+
+    #[test]
+    fn test_alloc_aligned_in_window() {
+        let ida = Ida::new();
+        // Take some of the aligned slots in the window, and the unaligned
+        // ones in between.
+        for id in [128, 144, 145, 150, 176] {
+            ida.alloc_at(id).unwrap();
         }
 
-        // 4. Free all remaining IDs.
-        for (i, &id) in ids.iter().enumerate() {
-            if !(i % 3 == 0 || i % 7 == 0) {
-                ida.free(id);
-            }
+        let window = 130..200;
+        let picked: Vec<usize> =
+            core::iter::from_fn(|| ida.alloc_aligned_in(16, window.clone())).collect();
+        assert_eq!(picked, [160, 192]);
+        assert!(!ida.is_allocated(208) && !ida.is_allocated(112));
+
+        // Freeing an aligned slot in the window makes it available again;
+        // one outside the window does not.
+        ida.free(144);
+        ida.free(128);
+        assert_eq!(ida.alloc_aligned_in(16, window.clone()), Some(144));
+        assert_eq!(ida.alloc_aligned_in(16, window), None);
+        assert_eq!(ida.alloc_aligned_in(16, 0..=128), Some(0));
+        assert_eq!(ida.alloc_aligned_in(1, 145..=150), Some(146));
+        assert_eq!(ida.alloc_aligned_in(64, 1..64), None);
+        assert_eq!(ida.alloc_aligned_in(64, usize::MAX - 10..), None);
+    }
+
+    #[test]
+    fn test_free_all_returns_count() {
+        let ida = Ida::new();
+        let emptied = Arc::new(Mutex::new(0));
+        let counter = emptied.clone();
+        ida.on_empty(move || *counter.lock().unwrap() += 1);
+
+        for _ in 0..300 {
+            ida.alloc();
         }
-        for id in freed_ids {
+        ida.alloc_at(1 << 30).unwrap();
+        assert_eq!(ida.free_all(), 301);
+        assert!(ida.is_empty());
+        assert_eq!(ida.node_count(), 1);
+        assert_eq!(ida.snapshot(), Vec::<usize>::new());
+        assert_eq!(*emptied.lock().unwrap(), 1);
+
+        // Nothing left to free, so the callback does not fire again.
+        assert_eq!(ida.free_all(), 0);
+        assert_eq!(*emptied.lock().unwrap(), 1);
+        assert_eq!(ida.alloc(), Some(0));
+    }
+
+    #[test]
+    fn test_with_stride_order() {
+        const MAX: usize = 1000;
+        let ida = Ida::builder().stride(64 + 7).max(MAX).build();
+
+        let order: Vec<usize> = core::iter::from_fn(|| ida.alloc()).collect();
+        let mut expected: Vec<usize> = (0..=MAX).collect();
+        expected.sort_by_key(|&id| (id % 71, id));
+        assert_eq!(order, expected);
+        assert_eq!(ida.len(), MAX + 1);
+
+        // Freed IDs come back in the same order, regardless of free order.
+        for id in [900, 5, 71, 76] {
             ida.free(id);
         }
+        let again: Vec<usize> = core::iter::from_fn(|| ida.alloc()).collect();
+        assert_eq!(again, [71, 5, 76, 900]);
 
-        // 5. Check that the allocator is empty and starts from 0 again.
+        // Unbounded, each pass of 64 strides wraps to fill its gaps before
+        // the walk moves on.
+        let ida = Ida::builder().stride(4).build();
+        let order: Vec<usize> = (0..300).map(|_| ida.alloc().unwrap()).collect();
+        let mut expected: Vec<usize> = (0..256).collect();
+        expected.sort_by_key(|&id| (id % 4, id));
+        expected.extend((256..).step_by(4).take(44));
+        assert_eq!(order, expected);
+        let ida = Ida::builder().stride(4096).build();
+        assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc(), Some(4096));
+        assert_eq!(ida.alloc_multiple_of(4096), Some(8192));
+
+        // Metered allocations follow the stride and are profiled too.
+        let ida = Ida::builder().stride(4).build();
         assert_eq!(ida.alloc(), Some(0));
+        assert_eq!(ida.alloc_metered().map(|(id, _)| id), Some(4));
+        assert_eq!(ida.alloc_metered().map(|(id, _)| id), Some(8));
+        let ida = Ida::builder().stride(8192).build();
+        ida.alloc_metered();
+        ida.alloc_metered();
+        assert_eq!(ida.subtrees_touched(), 2);
     }
 
     #[test]
-    fn test_is_allocated() {
-        let ida = Ida::default();
-        assert!(!ida.is_allocated(0));
-        assert!(!ida.is_allocated(100));
+    fn test_alloc_chain_falls_through_full_ranges() {
+        let ida = Ida::new();
+        let chain = [(0, 64), (1000, 1010), (5000, 5003)];
+        for id in (0..64).chain(1000..1010) {
+            ida.alloc_at(id).unwrap();
+        }
 
-        let id1 = ida.alloc().unwrap();
-        let id2 = ida.alloc().unwrap();
+        assert_eq!(ida.alloc_chain(&chain), Some(5000));
+        assert_eq!(ida.alloc_chain(&chain), Some(5001));
+        // A hole in an earlier range takes precedence again.
+        ida.free(1004);
+        assert_eq!(ida.alloc_chain(&chain), Some(1004));
+        assert_eq!(ida.alloc_chain(&chain), Some(5002));
+        assert_eq!(ida.alloc_chain(&chain), None);
+        assert_eq!(ida.alloc_chain(&[]), None);
+        assert_eq!(ida.alloc_chain(&[(7, 7)]), None);
+        assert_eq!(ida.len(), 64 + 10 + 3);
+    }
 
-        assert_eq!(id1, 0);
-        assert_eq!(id2, 1);
+    #[test]
+    fn test_subtrees_touched_dense_vs_scattered() {
+        let dense = Ida::new();
+        for _ in 0..3 * 4096 {
+            dense.alloc();
+        }
+        assert_eq!(dense.subtrees_touched(), 3);
 
-        assert!(ida.is_allocated(0));
+        let scattered = Ida::new();
+        for block in 0..50 {
+            scattered.set_search_start(block * 7 * 4096);
+            scattered.alloc();
+        }
+        assert_eq!(scattered.len(), 50);
+        assert_eq!(scattered.subtrees_touched(), 50);
+
+        // Freeing keeps blocks counted, and revisiting one adds nothing.
+        for id in scattered.snapshot() {
+            scattered.free(id);
+        }
+        scattered.hint_invalidate();
+        assert_eq!(scattered.alloc(), Some(0));
+        assert_eq!(scattered.subtrees_touched(), 50);
+
+        scattered.reset_profiling();
+        scattered.alloc();
+        assert_eq!(scattered.subtrees_touched(), 1);
+
+        // The allocating variants count too, ranges by every block they
+        // span, while restoring state does not.
+        let ida = Ida::new();
+        ida.alloc_at(5 * 4096).unwrap();
+        ida.alloc_in_range(9 * 4096..);
+        ida.alloc_parity(Parity::Odd);
+        assert_eq!(ida.subtrees_touched(), 3);
+        ida.alloc_contiguous_high(2 * 4096);
+        assert_eq!(ida.subtrees_touched(), 5);
+        ida.apply_bitset(20 * 4096, &[1], false);
+        assert_eq!(ida.subtrees_touched(), 5);
+    }
+
+    #[test]
+    fn test_alloc_pinned_frees_on_drop() {
+        let ida = Ida::builder().max(2).build();
+        let a = ida.alloc_pinned().unwrap();
+        let b = ida.alloc_pinned().unwrap();
+        let c = ida.alloc_pinned().unwrap();
+        assert_eq!([*a, *b, *c], [0, 1, 2]);
+        assert!(ida.alloc_pinned().is_none());
+
+        drop(b);
+        assert!(!ida.is_allocated(1));
+        assert_eq!(ida.len(), 2);
+        let d = ida.alloc_pinned().unwrap();
+        assert_eq!(*d, 1);
+
+        // An ID freed by hand and reused elsewhere survives the token.
+        ida.free(*d);
+        ida.alloc_at(1).unwrap();
+        drop(d);
         assert!(ida.is_allocated(1));
+        ida.free(1);
+
+        drop((a, c));
+        assert!(ida.is_empty());
+    }
+
+    #[test]
+    fn test_ensure_reserved_is_idempotent() {
+        let ida = Ida::builder().max(15).build();
+        assert_eq!(ida.ensure_reserved(7), Ok(true));
+        assert_eq!(ida.ensure_reserved(7), Ok(false));
+        assert!(ida.is_allocated(7));
+        assert_eq!(ida.len(), 1);
+
+        // An ID allocated some other way counts as reserved too.
+        let id = ida.alloc().unwrap();
+        assert_eq!(ida.ensure_reserved(id), Ok(false));
+        assert_eq!(ida.len(), 2);
+
+        // IDs that can never be allocated are reported, not panicked on.
+        assert_eq!(ida.ensure_reserved(16), Err(AllocError::OutOfRange));
+        let blocked = Ida::builder().blacklist(&[4]).build();
+        assert_eq!(blocked.ensure_reserved(4), Err(AllocError::OutOfRange));
+        assert_eq!(ida.len(), 2);
+    }
+
+    #[test]
+    fn test_subtree_usage_of_first_level_subtree() {
+        let ida = Ida::new();
+        // Fill a third of the second first-level subtree, plus one ID in
+        // each of its neighbours.
+        let base = 4096;
+        for id in base..base + 4096 / 3 {
+            ida.alloc_at(id).unwrap();
+        }
+        ida.alloc_at(base - 1).unwrap();
+        ida.alloc_at(2 * base).unwrap();
+
+        for prefix in [base, base + 17, 2 * base - 1] {
+            assert_eq!(ida.subtree_usage(prefix, 1), (1365, 4096));
+        }
+        assert_eq!(ida.subtree_usage(0, 1), (1, 4096));
+        assert_eq!(ida.subtree_usage(base + 64 * 21, 0), (21, 64));
+        assert_eq!(ida.subtree_usage(base + 64 * 22, 0), (0, 64));
+        assert_eq!(ida.subtree_usage(0, 2), (1367, 1 << 18));
+        // Absent subtrees are empty, and the root covers everything.
+        assert_eq!(ida.subtree_usage(1 << 40, 3), (0, 1 << 24));
+        assert_eq!(
+            ida.subtree_usage(0, IDA_MAX_LEVELS - 1),
+            (ida.len(), usize::MAX)
+        );
+    }
+
+    #[test]
+    fn test_adopt_scattered_ids() {
+        let ida = Ida::builder().max(199).build();
+        let legacy = [3, 4, 17, 64, 130, 199];
+        for id in legacy {
+            assert_eq!(ida.adopt(id), Ok(()));
+        }
+        assert_eq!(ida.adopt(17), Err(17));
+        assert_eq!(ida.adopt(200), Err(200));
+
+        let fresh: Vec<usize> = core::iter::from_fn(|| ida.alloc()).collect();
+        assert_eq!(fresh.len(), 200 - legacy.len());
+        assert!(fresh.iter().all(|id| !legacy.contains(id)));
+
+        // Adopted IDs are freed like any other.
+        ida.free(64);
+        assert_eq!(ida.alloc(), Some(64));
+    }
+
+    #[test]
+    fn test_alloc_masked_even_positions() {
+        const EVEN: u64 = 0x5555_5555_5555_5555;
+        let ida = Ida::builder().max(4 * 64 - 1).build();
+        // IDs taken by others are skipped.
+        ida.alloc_at(1).unwrap();
+        ida.alloc_at(64).unwrap();
+
+        let mut ids = Vec::new();
+        while let Some(id) = ida.alloc_masked(EVEN) {
+            assert_eq!(id % 2, 0, "{id} is not an even position");
+            ids.push(id);
+        }
+        let expected: Vec<usize> = (0..4 * 64).step_by(2).filter(|&id| id != 64).collect();
+        assert_eq!(ids, expected);
+        // Every odd position is still free.
+        assert_eq!(ida.len(), 1 + 4 * 32);
+        assert_eq!(ida.alloc(), Some(3));
+        assert_eq!(ida.alloc_masked(0), None);
+
+        ida.free(130);
+        assert_eq!(ida.alloc_masked(EVEN), Some(130));
+    }
+
+    #[test]
+    fn test_debug_tree_shows_populated_nodes() {
+        let ida = Ida::builder().lazy().build();
+        for id in [1, 3 * 64 + 5, 2 * 4096] {
+            ida.alloc_at(id).unwrap();
+        }
+        // An emptied subtree is left out of the dump.
+        ida.alloc_at(5 * 4096).unwrap();
+        ida.free(5 * 4096);
+
+        let dump = ida.debug_tree();
+        let lines: Vec<&str> = dump.lines().collect();
+        let top = IDA_MAX_LEVELS - 1;
+        assert!(lines[0].starts_with(&format!("level {top} root bitmap 0x")));
+        // One line per node on the shared path, then the two level-1
+        // subtrees with their leaves.
+        assert_eq!(lines.len(), top - 1 + 5);
+        let tail = [
+            "level 1 index 0 bitmap 0x0000000000000000",
+            "  level 0 index 0 bitmap 0x0000000000000002",
+            "  level 0 index 3 bitmap 0x0000000000000020",
+            "level 1 index 2 bitmap 0x0000000000000000",
+            "  level 0 index 0 bitmap 0x0000000000000001",
+        ];
+        let indent = " ".repeat(2 * (top - 1));
+        for (line, expected) in lines[top - 1..].iter().zip(tail) {
+            assert_eq!(*line, format!("{indent}{expected}"));
+        }
+    }
+
+    #[test]
+    fn test_alloc_class_isolation_and_spill() {
+        let ida = Ida::builder().class_windows([0..8, 8..72, 72..200]).build();
+
+        // Under normal load every class stays in its own window, however
+        // the requests are interleaved.
+        let mut by_class: [Vec<usize>; 3] = Default::default();
+        for round in 0..20 {
+            for class in [Priority::Low, Priority::High, Priority::Normal] {
+                if round < 4 || class != Priority::High {
+                    by_class[class as usize].push(ida.alloc_class(class).unwrap());
+                }
+            }
+        }
+        assert_eq!(by_class[0], (0..4).collect::<Vec<_>>());
+        assert_eq!(by_class[1], (8..28).collect::<Vec<_>>());
+        assert_eq!(by_class[2], (72..92).collect::<Vec<_>>());
+
+        // Low priority is refused once its window is full, even though the
+        // windows above it still have room.
+        while ida.alloc_class(Priority::Low).is_some() {}
+        assert_eq!(ida.len(), 4 + 20 + 128);
+        assert!((72..200).all(|id| ida.is_allocated(id)));
+        assert!(!ida.is_allocated(4) && !ida.is_allocated(28));
+
+        // High priority fills its window, then spills into Normal's.
+        let spilled: Vec<usize> = (0..6)
+            .map(|_| ida.alloc_class(Priority::High).unwrap())
+            .collect();
+        assert_eq!(spilled, [4, 5, 6, 7, 28, 29]);
+
+        // Freeing a high-priority ID makes it the first choice again, and
+        // Normal never takes it.
+        ida.free(2);
+        while ida.alloc_class(Priority::Normal).is_some() {}
         assert!(!ida.is_allocated(2));
-        assert!(!ida.is_allocated(100));
+        assert_eq!(ida.alloc_class(Priority::Normal), None);
+        assert_eq!(ida.alloc_class(Priority::High), Some(2));
+        assert_eq!(ida.alloc_class(Priority::High), None);
 
-        ida.free(1);
-        assert!(ida.is_allocated(0));
+        // Without windows every class shares the whole space.
+        let plain = Ida::builder().max(3).build();
+        plain.alloc();
+        assert_eq!(plain.alloc_class(Priority::Low), Some(1));
+        assert_eq!(plain.alloc_class(Priority::High), Some(2));
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_with_allocator_routes_node_allocations() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use std::alloc::System;
+
+        #[derive(Default)]
+        struct Counting {
+            live: AtomicUsize,
+            total: AtomicUsize,
+        }
+
+        unsafe impl Allocator for &'static Counting {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+                self.live.fetch_add(1, Ordering::Relaxed);
+                self.total.fetch_add(1, Ordering::Relaxed);
+                System.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.live.fetch_sub(1, Ordering::Relaxed);
+                unsafe { System.deallocate(ptr, layout) }
+            }
+        }
+
+        let counting: &'static Counting = Box::leak(Box::default());
+        let ida = Ida::builder().allocator(counting).build();
+        assert_eq!(counting.total.load(Ordering::Relaxed), 0);
+
+        let ids = [0, 1, 64, 5_000, 1 << 40, usize::MAX];
+        for &id in &ids {
+            ida.alloc_at(id).unwrap();
+        }
+        // Every node below the root is its own allocation, on top of the
+        // child maps holding them.
+        let live = counting.live.load(Ordering::Relaxed);
+        assert!(live >= ida.node_count() - 1, "{live} allocations");
+
+        // Nodes built by a different path come from the same allocator.
+        let before = counting.total.load(Ordering::Relaxed);
+        ida.reset_to([7, 1 << 50]);
+        assert!(counting.total.load(Ordering::Relaxed) > before);
+        ida.alloc_at(1 << 20).unwrap();
+
+        for id in ida.snapshot() {
+            ida.free(id);
+        }
+        assert!(ida.is_empty());
+        assert_eq!(ida.node_count(), 1);
+        // The root's child map may keep its storage, but dropping the
+        // allocator returns everything.
+        drop(ida);
+        assert_eq!(counting.live.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "roaring")]
+    #[test]
+    fn test_roaring_round_trip() {
+        let ids: [u32; 6] = [0, 63, 64, 4_097, 5_000_000, u32::MAX];
+        let bitmap: roaring::RoaringBitmap = ids.iter().copied().collect();
+
+        let ida = Ida::from_roaring(&bitmap);
+        assert_eq!(ida.len(), ids.len());
+        for &id in &ids {
+            assert!(ida.is_allocated(id as usize));
+        }
         assert!(!ida.is_allocated(1));
-        assert!(!ida.is_allocated(2));
+
+        assert_eq!(ida.to_roaring(), Ok(bitmap));
     }
 
+    #[cfg(feature = "roaring")]
     #[test]
-    fn test_debug_output() {
-        let ida = Ida::default();
+    fn test_to_roaring_rejects_large_ids() {
+        let ida = Ida::new();
+        let big = u32::MAX as usize + 1;
         ida.alloc();
-        ida.alloc();
-        // The exact output is not asserted as it's complex and implementation-dependent,
-        // but this test ensures the Debug trait is implemented and doesn't panic.
-        let _ = format!("{ida:?}");
+        ida.alloc_at(big).unwrap();
+        assert_eq!(ida.to_roaring(), Err(big));
     }
 
     #[test]
@@ -0,0 +1,274 @@
+//! An allocator with a lock-free fast path in front of an [`Ida`].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{IDA_MAX_LEVELS, Ida};
+
+/// The number of pre-claimed IDs a [`MagazineIda`] keeps on hand.
+const MAGAZINE_SLOTS: usize = 16;
+
+/// An ID allocator that serves most allocations without taking a lock.
+///
+/// A small magazine of IDs is claimed from the underlying [`Ida`] in one
+/// locked batch. Each slot holds one claimed ID, and [`alloc`](Self::alloc)
+/// takes an ID out of a slot with a single atomic swap, so the common case
+/// never touches the spinlock. Only when the magazine is empty does an
+/// allocation fall back to the locked tree walk, refilling the magazine as
+/// it goes. [`free`](Self::free) always goes through the lock, returning the
+/// ID to the tree where a later refill can pick it up again.
+///
+/// Slots are emptied with a swap rather than by clearing bits in a shared
+/// word, so a slot can never be taken twice even if it is refilled with the
+/// same ID in between, which rules out ABA problems without a generation
+/// counter.
+///
+/// IDs sitting in the magazine are allocated in the underlying tree but have
+/// not been handed out yet; [`is_allocated`](Self::is_allocated) and
+/// [`len`](Self::len) account for them. Because the magazine is filled ahead
+/// of time, the lowest free ID is not guaranteed to be returned.
+///
+/// # Examples
+///
+/// ```
+/// use ida_rs::{Ida, MagazineIda};
+///
+/// let ida = MagazineIda::new(Ida::new());
+/// let a = ida.alloc().unwrap();
+/// let b = ida.alloc().unwrap();
+/// assert_ne!(a, b);
+/// assert_eq!(ida.len(), 2);
+///
+/// ida.free(a);
+/// assert!(!ida.is_allocated(a));
+/// ```
+#[derive(Debug)]
+pub struct MagazineIda {
+    ida: Ida,
+    // Each slot holds a claimed ID plus one, or zero when empty. Only a
+    // refill, which holds the tree lock, stores a non-zero value.
+    slots: [AtomicUsize; MAGAZINE_SLOTS],
+}
+
+impl MagazineIda {
+    /// Creates a new allocator in front of `ida`.
+    ///
+    /// `ida` may already hold allocations, and its policy and bound are
+    /// respected when the magazine is refilled.
+    pub fn new(ida: Ida) -> Self {
+        Self {
+            ida,
+            slots: [const { AtomicUsize::new(0) }; MAGAZINE_SLOTS],
+        }
+    }
+
+    /// Allocates an ID, taking it from the magazine when possible.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated ID
+    /// - `None` - If the magazine is empty and the underlying allocator is
+    ///   exhausted
+    pub fn alloc(&self) -> Option<usize> {
+        self.take().or_else(|| self.refill())
+    }
+
+    /// Takes an ID out of the magazine without locking.
+    fn take(&self) -> Option<usize> {
+        self.slots.iter().find_map(|slot| {
+            if slot.load(Ordering::Relaxed) == 0 {
+                return None;
+            }
+            match slot.swap(0, Ordering::Acquire) {
+                0 => None,
+                stored => Some(stored - 1),
+            }
+        })
+    }
+
+    /// Allocates an ID for the caller under the lock, topping up every empty
+    /// slot of the magazine while the lock is held.
+    fn refill(&self) -> Option<usize> {
+        let mut inner = self.ida.inner.lock();
+        let id = inner.alloc()?;
+        for slot in &self.slots {
+            if slot.load(Ordering::Relaxed) != 0 {
+                continue;
+            }
+            let Some(spare) = inner.alloc() else {
+                break;
+            };
+            // Takers only ever store zero, and other refills are shut out by
+            // the lock, so the slot is still empty.
+            slot.store(spare + 1, Ordering::Release);
+        }
+        self.ida.notify_watchers(inner);
+        Some(id)
+    }
+
+    /// Frees a previously allocated ID.
+    ///
+    /// The ID goes back to the underlying tree, from which the magazine is
+    /// refilled. Freeing an ID that is parked in the magazine takes it out
+    /// of its slot as well, so it cannot be handed out twice.
+    pub fn free(&self, id: usize) {
+        let mut inner = self.ida.inner.lock();
+        // Holding the lock keeps refills from parking the ID again, and the
+        // exchange keeps takers from claiming it while it is freed.
+        for slot in &self.slots {
+            if slot
+                .compare_exchange(id.wrapping_add(1), 0, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        inner.free(id);
+        self.ida.notify_watchers(inner);
+    }
+
+    /// Checks if `id` has been handed out and not yet freed.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        // Slots are only filled under the lock, so with it held an ID is
+        // either parked or handed out, never both.
+        let inner = self.ida.inner.lock();
+        inner.root.is_allocated(id, IDA_MAX_LEVELS - 1) && !self.in_magazine(id)
+    }
+
+    /// Returns the number of IDs handed out and not yet freed.
+    pub fn len(&self) -> usize {
+        let inner = self.ida.inner.lock();
+        let cached = self
+            .slots
+            .iter()
+            .filter(|slot| slot.load(Ordering::Acquire) != 0)
+            .count();
+        inner.len - cached
+    }
+
+    /// Returns `true` if no IDs are handed out.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn in_magazine(&self, id: usize) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.load(Ordering::Acquire) == id.wrapping_add(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_magazine_alloc_and_free() {
+        let ida = MagazineIda::new(Ida::new());
+        let first = ida.alloc().unwrap();
+        assert_eq!(first, 0);
+        // The refill claimed a magazine's worth of IDs behind the first.
+        assert_eq!(ida.ida.len(), 1 + MAGAZINE_SLOTS);
+        assert_eq!(ida.len(), 1);
+
+        let mut ids: Vec<usize> = (0..40).map(|_| ida.alloc().unwrap()).collect();
+        ids.push(first);
+        ids.sort_unstable();
+        assert_eq!(ids, (0..41).collect::<Vec<_>>());
+        assert_eq!(ida.len(), 41);
+        for id in 0..41 {
+            assert!(ida.is_allocated(id));
+        }
+
+        ida.free(7);
+        assert!(!ida.is_allocated(7));
+        assert_eq!(ida.len(), 40);
+        // Drain the magazine so the next refill reaches the freed ID.
+        while ida.take().is_some() {}
+        assert_eq!(ida.alloc(), Some(7));
+    }
+
+    #[test]
+    fn test_magazine_free_of_parked_id() {
+        let ida = MagazineIda::new(Ida::new());
+        assert_eq!(ida.alloc(), Some(0));
+        // 5 is parked in a slot, not handed out.
+        assert!(!ida.is_allocated(5));
+        ida.free(5);
+        assert!(!ida.in_magazine(5));
+
+        let mut ids: Vec<usize> = (0..40).map(|_| ida.alloc().unwrap()).collect();
+        ids.push(0);
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 41);
+        assert_eq!(ida.len(), 41);
+    }
+
+    #[test]
+    fn test_magazine_respects_bound() {
+        let ida = MagazineIda::new(Ida::builder().max(4).build());
+        let mut ids: Vec<usize> = (0..5).map(|_| ida.alloc().unwrap()).collect();
+        assert_eq!(ida.alloc(), None);
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+
+        ida.free(2);
+        assert_eq!(ida.alloc(), Some(2));
+        assert!(ida.alloc().is_none());
+    }
+
+    #[test]
+    fn test_magazine_no_duplicates_under_contention() {
+        const MAX: usize = 1_023;
+        let ida = Arc::new(MagazineIda::new(Ida::builder().max(MAX).build()));
+        let owned: Arc<Vec<AtomicBool>> =
+            Arc::new((0..=MAX).map(|_| AtomicBool::new(false)).collect());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let ida = ida.clone();
+                let owned = owned.clone();
+                thread::spawn(move || {
+                    let mut held = Vec::new();
+                    for round in 0..20_000 {
+                        if let Some(id) = ida.alloc() {
+                            // No other thread may hold this ID right now.
+                            assert!(!owned[id].swap(true, Ordering::SeqCst), "duplicate {id}");
+                            held.push(id);
+                        }
+                        if (round % 3 == 0 || held.len() > 64)
+                            && let Some(id) = held.pop()
+                        {
+                            owned[id].store(false, Ordering::SeqCst);
+                            ida.free(id);
+                        }
+                    }
+                    held
+                })
+            })
+            .collect();
+
+        let mut live: Vec<usize> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        assert_eq!(ida.len(), live.len());
+        for &id in &live {
+            assert!(ida.is_allocated(id));
+        }
+        live.sort_unstable();
+        live.dedup();
+        assert_eq!(live.len(), ida.len());
+
+        // Freeing everything leaves only the magazine's claims behind.
+        for id in live {
+            ida.free(id);
+        }
+        assert!(ida.is_empty());
+        while ida.take().is_some() {}
+    }
+}
@@ -0,0 +1,304 @@
+//! An allocator that spreads IDs across independently locked shards.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{IDA_MAX_LEVELS, Ida};
+
+/// An ID allocator split into independently locked shards.
+///
+/// Each shard is a separate [`Ida`], so threads allocating from different
+/// shards never contend on the same lock. Global IDs are interleaved across
+/// shards: shard `s` of `n` owns every ID `g` with `g % n == s`, and its
+/// local ID `l` maps to the global ID `l * n + s`. This keeps
+/// [`free`](Self::free) and [`is_allocated`](Self::is_allocated) a matter of
+/// arithmetic.
+///
+/// Unlike a single [`Ida`], the lowest free global ID is not guaranteed to be
+/// returned, since each shard allocates independently.
+///
+/// # Examples
+///
+/// ```
+/// use ida_rs::ShardedIda;
+///
+/// let ida = ShardedIda::new(4);
+/// let a = ida.alloc().unwrap();
+/// let b = ida.alloc().unwrap();
+/// assert_ne!(ida.shard_of(a), ida.shard_of(b));
+///
+/// ida.free(a);
+/// assert!(!ida.is_allocated(a));
+/// ```
+#[derive(Debug)]
+pub struct ShardedIda {
+    shards: Vec<Ida>,
+    next_shard: AtomicUsize,
+}
+
+impl ShardedIda {
+    /// Creates a new allocator with `shards` shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    #[track_caller]
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0, "ShardedIda needs at least one shard");
+        Self {
+            // Bound each shard so its highest local ID still maps to a
+            // global ID that fits in a `usize`.
+            shards: (0..shards)
+                .map(|shard| Ida::builder().max((usize::MAX - shard) / shards).build())
+                .collect(),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard that owns the global ID `id`.
+    pub fn shard_of(&self, id: usize) -> usize {
+        id % self.shards.len()
+    }
+
+    /// Returns the number of IDs allocated in each shard, indexed by shard.
+    ///
+    /// This shows how evenly allocations are spread. Each count is read
+    /// under that shard's lock alone, so while other threads are allocating
+    /// the counts need not add up to a single moment's total.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::ShardedIda;
+    ///
+    /// let ida = ShardedIda::new(3);
+    /// for _ in 0..4 {
+    ///     ida.alloc();
+    /// }
+    /// assert_eq!(ida.shard_lengths(), vec![2, 1, 1]);
+    /// ```
+    pub fn shard_lengths(&self) -> Vec<usize> {
+        self.shards.iter().map(Ida::len).collect()
+    }
+
+    /// Moves allocations between shards until their lengths differ by at
+    /// most one, returning how the moved global IDs were renumbered.
+    ///
+    /// Frees that happen to fall mostly on some shards leave the others
+    /// carrying more than their share. This computes an even target for
+    /// every shard and moves the highest IDs of the fuller shards onto the
+    /// lowest free IDs of the emptier ones. A global ID encodes its shard,
+    /// so a moved ID necessarily changes: each entry of the returned table
+    /// is an `(old, new)` pair of global IDs, in the order the moves were
+    /// made. IDs not in the table are unchanged.
+    ///
+    /// # Thread Safety
+    ///
+    /// Every shard is locked for the whole operation, so no allocation or
+    /// free can interleave with it. Holders of the moved IDs must still be
+    /// told about their new IDs, so this is meant to run while the users of
+    /// the allocator are quiesced.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::ShardedIda;
+    ///
+    /// let ida = ShardedIda::new(2);
+    /// for _ in 0..4 {
+    ///     ida.alloc_on(0);
+    /// }
+    ///
+    /// // Shard 0 gives up its two highest IDs
+    /// assert_eq!(ida.rebalance(), vec![(6, 1), (4, 3)]);
+    /// assert_eq!(ida.shard_lengths(), vec![2, 2]);
+    /// ```
+    pub fn rebalance(&self) -> Vec<(usize, usize)> {
+        let n = self.shards.len();
+        // Lock in index order, the only place more than one shard is
+        // locked at a time.
+        let mut guards: Vec<_> = self.shards.iter().map(|shard| shard.inner.lock()).collect();
+        let total: usize = guards.iter().map(|inner| inner.len).sum();
+        let target = |shard: usize| total / n + usize::from(shard < total % n);
+
+        let mut remap = Vec::new();
+        let mut receiver = 0;
+        for donor in 0..n {
+            while guards[donor].len > target(donor) {
+                while receiver < n && guards[receiver].len >= target(receiver) {
+                    receiver += 1;
+                }
+                if receiver == n {
+                    break;
+                }
+                let Some(new_local) = guards[receiver].alloc() else {
+                    // The receiver is exhausted; let the next one take over.
+                    receiver += 1;
+                    continue;
+                };
+                let old_local = guards[donor]
+                    .root
+                    .highest_allocated(IDA_MAX_LEVELS - 1, 0)
+                    .expect("an overfull shard holds an ID");
+                guards[donor].free(old_local);
+                remap.push((old_local * n + donor, new_local * n + receiver));
+            }
+        }
+
+        for (shard, inner) in self.shards.iter().zip(guards) {
+            shard.notify_watchers(inner);
+        }
+        remap
+    }
+
+    /// Allocates an ID, choosing shards in round-robin order.
+    ///
+    /// If the chosen shard is exhausted the following shards are tried in
+    /// turn, so this only fails once every shard is full.
+    pub fn alloc(&self) -> Option<usize> {
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        (0..self.shards.len())
+            .find_map(|offset| self.alloc_on((start + offset) % self.shards.len()))
+    }
+
+    /// Allocates an ID from a specific shard.
+    ///
+    /// This lets callers pin allocations to a shard of their choice, such as
+    /// one per CPU, for locality. The returned global ID always maps back to
+    /// `shard` through [`shard_of`](Self::shard_of).
+    ///
+    /// # Returns
+    ///
+    /// - `Some(id)` - The allocated global ID
+    /// - `None` - If `shard` is out of range or exhausted
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ida_rs::ShardedIda;
+    ///
+    /// let ida = ShardedIda::new(4);
+    /// assert_eq!(ida.alloc_on(2), Some(2));
+    /// assert_eq!(ida.alloc_on(2), Some(6));
+    /// assert_eq!(ida.alloc_on(4), None);
+    /// ```
+    pub fn alloc_on(&self, shard: usize) -> Option<usize> {
+        let local = self.shards.get(shard)?.alloc()?;
+        Some(local * self.shards.len() + shard)
+    }
+
+    /// Frees a previously allocated global ID.
+    pub fn free(&self, id: usize) {
+        let n = self.shards.len();
+        self.shards[id % n].free(id / n);
+    }
+
+    /// Checks if a given global ID is currently allocated.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        let n = self.shards.len();
+        self.shards[id % n].is_allocated(id / n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_alloc() {
+        let ida = ShardedIda::new(3);
+        let ids: Vec<usize> = (0..6).map(|_| ida.alloc().unwrap()).collect();
+        assert_eq!(ids, [0, 1, 2, 3, 4, 5]);
+
+        ida.free(4);
+        assert!(!ida.is_allocated(4));
+        assert!(ida.is_allocated(5));
+    }
+
+    #[test]
+    fn test_alloc_on_specific_shards() {
+        let ida = ShardedIda::new(4);
+        let mut ids = Vec::new();
+        for shard in [1, 1, 3, 0, 1] {
+            let id = ida.alloc_on(shard).unwrap();
+            assert_eq!(ida.shard_of(id), shard);
+            ids.push(id);
+        }
+        assert_eq!(ids, [1, 5, 3, 0, 9]);
+
+        // Round-robin allocation skips over IDs pinned to a shard.
+        for _ in 0..8 {
+            let id = ida.alloc().unwrap();
+            assert!(!ids.contains(&id));
+            ids.push(id);
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 13);
+
+        ida.free(5);
+        assert_eq!(ida.alloc_on(1), Some(5));
+    }
+
+    #[test]
+    fn test_rebalance_evens_out_shards() {
+        let ida = ShardedIda::new(4);
+        let ids: Vec<usize> = (0..400).map(|_| ida.alloc().unwrap()).collect();
+        // Free most of shards 1 and 3.
+        for &id in &ids {
+            if ida.shard_of(id) % 2 == 1 && id % 5 != 0 {
+                ida.free(id);
+            }
+        }
+        let before = ida.shard_lengths();
+        assert_eq!(before, [100, 20, 100, 20]);
+
+        let mut live: Vec<usize> = ids.into_iter().filter(|&id| ida.is_allocated(id)).collect();
+        let remap = ida.rebalance();
+        assert_eq!(ida.shard_lengths(), [60, 60, 60, 60]);
+        assert_eq!(remap.len(), 80);
+
+        // Every move goes from a live ID to a newly allocated one on a
+        // shard that was short, and nothing else changes.
+        for &(old, new) in &remap {
+            assert!(live.contains(&old));
+            assert!(!ida.is_allocated(old));
+            assert!(ida.is_allocated(new));
+            assert!(before[ida.shard_of(old)] > 60 && before[ida.shard_of(new)] < 60);
+        }
+        for id in &mut live {
+            if let Some(&(_, new)) = remap.iter().find(|&&(old, _)| old == *id) {
+                *id = new;
+            }
+        }
+        live.sort_unstable();
+        let mut now: Vec<usize> = (0..4 * 100).filter(|&id| ida.is_allocated(id)).collect();
+        now.sort_unstable();
+        assert_eq!(live, now);
+
+        // Already balanced shards are left alone.
+        assert!(ida.rebalance().is_empty());
+    }
+
+    #[test]
+    fn test_shard_lengths_stay_balanced() {
+        let ida = ShardedIda::new(5);
+        let ids: Vec<usize> = (0..1_003).map(|_| ida.alloc().unwrap()).collect();
+        let lengths = ida.shard_lengths();
+        assert_eq!(lengths.iter().sum::<usize>(), ids.len());
+        let (min, max) = (lengths.iter().min().unwrap(), lengths.iter().max().unwrap());
+        assert!(max - min <= 1, "{lengths:?}");
+
+        // Frees come off the owning shard's count.
+        for &id in ids.iter().filter(|&&id| ida.shard_of(id) == 2) {
+            ida.free(id);
+        }
+        assert_eq!(ida.shard_lengths()[2], 0);
+        assert_eq!(ida.shard_lengths()[0], lengths[0]);
+    }
+}
@@ -0,0 +1,433 @@
+//! Slabs that store a value under each allocated ID.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::Ida;
+
+/// A slab of values keyed by IDs from an [`Ida`].
+///
+/// Inserting a value allocates the lowest free ID for it, and removing the
+/// value frees the ID again, so the IDs stay as dense as the values they
+/// key. This suits the "reserve a slot, then fill it" pattern: reserve with
+/// [`insert_default`](Self::insert_default) and initialize in place through
+/// [`get_mut`](Self::get_mut).
+///
+/// # Examples
+///
+/// ```
+/// use ida_rs::IdaSlab;
+///
+/// let slab: IdaSlab<String> = IdaSlab::new();
+/// let id = slab.insert_default();
+/// slab.get_mut(id).unwrap().push_str("ready");
+///
+/// assert_eq!(slab.remove(id).as_deref(), Some("ready"));
+/// assert!(slab.get_mut(id).is_none());
+/// ```
+#[derive(Debug)]
+pub struct IdaSlab<V> {
+    ida: Ida,
+    values: Mutex<BTreeMap<usize, V>>,
+}
+
+impl<V> IdaSlab<V> {
+    /// Creates a new, empty slab.
+    pub fn new() -> Self {
+        Self {
+            ida: Ida::new(),
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Stores `value` under a newly allocated ID and returns the ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every ID is already in use.
+    #[track_caller]
+    pub fn insert(&self, value: V) -> usize {
+        let mut values = self.values.lock();
+        let id = self.ida.alloc().expect("IdaSlab ran out of IDs");
+        values.insert(id, value);
+        id
+    }
+
+    /// Stores `V::default()` under a newly allocated ID and returns the ID.
+    ///
+    /// The value can then be filled in through [`get_mut`](Self::get_mut).
+    ///
+    /// # Panics
+    ///
+    /// Panics if every ID is already in use.
+    #[track_caller]
+    pub fn insert_default(&self) -> usize
+    where
+        V: Default,
+    {
+        self.insert(V::default())
+    }
+
+    /// Returns a guard giving mutable access to the value stored under `id`,
+    /// or `None` if no value is stored there.
+    ///
+    /// # Thread Safety
+    ///
+    /// The slab's values are locked for as long as the guard is alive, so
+    /// calling any other slab method while holding it deadlocks.
+    pub fn get_mut(&self, id: usize) -> Option<GuardMut<'_, V>> {
+        let values = self.values.lock();
+        values
+            .contains_key(&id)
+            .then_some(GuardMut { guard: values, id })
+    }
+
+    /// Removes and returns the value stored under `id`, freeing the ID.
+    ///
+    /// Returns `None` if no value is stored under `id`.
+    pub fn remove(&self, id: usize) -> Option<V> {
+        let mut values = self.values.lock();
+        let value = values.remove(&id)?;
+        self.ida.free(id);
+        Some(value)
+    }
+
+    /// Returns the number of values stored in the slab.
+    pub fn len(&self) -> usize {
+        self.values.lock().len()
+    }
+
+    /// Returns `true` if the slab stores no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<V> Default for IdaSlab<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Mutable access to a value in an [`IdaSlab`], returned by
+/// [`IdaSlab::get_mut`].
+///
+/// The slab's values stay locked until the guard is dropped.
+pub struct GuardMut<'a, V> {
+    guard: MutexGuard<'a, BTreeMap<usize, V>>,
+    id: usize,
+}
+
+impl<V> Deref for GuardMut<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // The entry was present when the guard was created, and the lock it
+        // holds keeps anyone from removing it.
+        &self.guard[&self.id]
+    }
+}
+
+impl<V> DerefMut for GuardMut<'_, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.guard
+            .get_mut(&self.id)
+            .expect("guarded entry is present")
+    }
+}
+
+impl<V: core::fmt::Debug> core::fmt::Debug for GuardMut<'_, V> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("GuardMut")
+            .field("id", &self.id)
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// A generational handle to a value in a [`GenSlab`].
+///
+/// The ID names the slot and the generation tells apart the successive
+/// values stored in it, so a handle kept after its value was removed never
+/// resolves to whatever later reuses the slot. The generation is 64 bits
+/// wide, so it cannot wrap around to a stale handle's value in practice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity {
+    /// The slot's ID.
+    pub id: usize,
+    /// The generation of the slot when the value was inserted.
+    pub generation: u64,
+}
+
+/// A slab of values behind generational [`Entity`] handles.
+///
+/// Slots are numbered by IDs from an [`Ida`], so freed slots are reused
+/// lowest first and the backing storage stays dense. Each slot carries a
+/// generation that is bumped whenever its value is removed; a handle whose
+/// generation no longer matches is stale and resolves to nothing.
+///
+/// # Examples
+///
+/// ```
+/// use ida_rs::GenSlab;
+///
+/// let slab = GenSlab::new();
+/// let old = slab.insert("first");
+/// slab.remove(old);
+///
+/// // The slot is reused, but the old handle does not see the new value.
+/// let new = slab.insert("second");
+/// assert_eq!(new.id, old.id);
+/// assert!(slab.get(old).is_none());
+/// assert_eq!(*slab.get(new).unwrap(), "second");
+/// ```
+#[derive(Debug)]
+pub struct GenSlab<V> {
+    ida: Ida,
+    slots: Mutex<Vec<(u64, Option<V>)>>,
+}
+
+impl<V> GenSlab<V> {
+    /// Creates a new, empty slab.
+    pub fn new() -> Self {
+        Self {
+            ida: Ida::new(),
+            slots: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stores `value` in a free slot and returns a handle to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every ID is already in use.
+    #[track_caller]
+    pub fn insert(&self, value: V) -> Entity {
+        let mut slots = self.slots.lock();
+        let id = self.ida.alloc().expect("GenSlab ran out of IDs");
+        if slots.len() <= id {
+            slots.resize_with(id + 1, || (0, None));
+        }
+        let slot = &mut slots[id];
+        slot.1 = Some(value);
+        Entity {
+            id,
+            generation: slot.0,
+        }
+    }
+
+    /// Returns a guard giving shared access to the value behind `entity`,
+    /// or `None` if the handle is stale.
+    ///
+    /// # Thread Safety
+    ///
+    /// The slab's values are locked for as long as the guard is alive, so
+    /// calling any other slab method while holding it deadlocks.
+    pub fn get(&self, entity: Entity) -> Option<GenGuard<'_, V>> {
+        let slots = self.slots.lock();
+        Self::is_live(&slots, entity).then_some(GenGuard {
+            guard: slots,
+            id: entity.id,
+        })
+    }
+
+    /// Returns a guard giving mutable access to the value behind `entity`,
+    /// or `None` if the handle is stale.
+    ///
+    /// # Thread Safety
+    ///
+    /// The slab's values are locked for as long as the guard is alive, so
+    /// calling any other slab method while holding it deadlocks.
+    pub fn get_mut(&self, entity: Entity) -> Option<GenGuardMut<'_, V>> {
+        let slots = self.slots.lock();
+        Self::is_live(&slots, entity).then_some(GenGuardMut {
+            guard: slots,
+            id: entity.id,
+        })
+    }
+
+    /// Returns `true` if `entity` still refers to a stored value.
+    pub fn contains(&self, entity: Entity) -> bool {
+        Self::is_live(&self.slots.lock(), entity)
+    }
+
+    /// Removes and returns the value behind `entity`, freeing its slot for
+    /// reuse under a new generation.
+    ///
+    /// Returns `None` if the handle is stale.
+    pub fn remove(&self, entity: Entity) -> Option<V> {
+        let mut slots = self.slots.lock();
+        if !Self::is_live(&slots, entity) {
+            return None;
+        }
+        let slot = &mut slots[entity.id];
+        slot.0 = slot.0.wrapping_add(1);
+        let value = slot.1.take();
+        self.ida.free(entity.id);
+        value
+    }
+
+    /// Returns the number of values stored in the slab.
+    pub fn len(&self) -> usize {
+        self.ida.len()
+    }
+
+    /// Returns `true` if the slab stores no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_live(slots: &[(u64, Option<V>)], entity: Entity) -> bool {
+        slots
+            .get(entity.id)
+            .is_some_and(|(generation, value)| *generation == entity.generation && value.is_some())
+    }
+}
+
+impl<V> Default for GenSlab<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Shared access to a value in a [`GenSlab`], returned by [`GenSlab::get`].
+///
+/// The slab's values stay locked until the guard is dropped.
+pub struct GenGuard<'a, V> {
+    guard: MutexGuard<'a, Vec<(u64, Option<V>)>>,
+    id: usize,
+}
+
+impl<V> Deref for GenGuard<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // The slot was live when the guard was created, and the lock it
+        // holds keeps anyone from emptying it.
+        self.guard[self.id]
+            .1
+            .as_ref()
+            .expect("guarded slot is live")
+    }
+}
+
+impl<V: core::fmt::Debug> core::fmt::Debug for GenGuard<'_, V> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("GenGuard")
+            .field("id", &self.id)
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+/// Mutable access to a value in a [`GenSlab`], returned by
+/// [`GenSlab::get_mut`].
+///
+/// The slab's values stay locked until the guard is dropped.
+pub struct GenGuardMut<'a, V> {
+    guard: MutexGuard<'a, Vec<(u64, Option<V>)>>,
+    id: usize,
+}
+
+impl<V> Deref for GenGuardMut<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // As for `GenGuard`, the held lock keeps the slot live.
+        self.guard[self.id]
+            .1
+            .as_ref()
+            .expect("guarded slot is live")
+    }
+}
+
+impl<V> DerefMut for GenGuardMut<'_, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.guard[self.id]
+            .1
+            .as_mut()
+            .expect("guarded slot is live")
+    }
+}
+
+impl<V: core::fmt::Debug> core::fmt::Debug for GenGuardMut<'_, V> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("GenGuardMut")
+            .field("id", &self.id)
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slab_insert_default_and_mutate() {
+        let slab: IdaSlab<Vec<u32>> = IdaSlab::new();
+        let ids: Vec<usize> = (0..3).map(|_| slab.insert_default()).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert!(slab.get_mut(ids[1]).unwrap().is_empty());
+
+        for (k, &id) in ids.iter().enumerate() {
+            let mut value = slab.get_mut(id).unwrap();
+            value.push(k as u32);
+            value.push(10);
+        }
+        // Values persist across guards until removed.
+        assert_eq!(*slab.get_mut(ids[2]).unwrap(), [2, 10]);
+        assert_eq!(slab.remove(ids[0]), Some(vec![0, 10]));
+        assert!(slab.get_mut(ids[0]).is_none());
+        assert_eq!(slab.remove(ids[0]), None);
+        assert_eq!(slab.len(), 2);
+
+        // The freed ID is reused for the next value.
+        assert_eq!(slab.insert(vec![7]), ids[0]);
+        assert_eq!(*slab.get_mut(ids[1]).unwrap(), [1, 10]);
+        assert!(slab.get_mut(99).is_none());
+    }
+
+    #[test]
+    fn test_gen_slab_rejects_stale_handles() {
+        let slab = GenSlab::new();
+        let a = slab.insert(10);
+        let b = slab.insert(20);
+        assert_eq!((a.id, b.id), (0, 1));
+        assert_eq!(a.generation, 0);
+
+        assert_eq!(slab.remove(a), Some(10));
+        assert!(!slab.contains(a));
+        assert_eq!(slab.remove(a), None);
+
+        // The reused slot gets a new generation.
+        let c = slab.insert(30);
+        assert_eq!(c.id, a.id);
+        assert_eq!(c.generation, 1);
+        assert!(slab.get(a).is_none());
+        assert_eq!(slab.remove(a), None);
+        *slab.get_mut(c).unwrap() += 1;
+        assert_eq!(*slab.get(c).unwrap(), 31);
+
+        // Handles to other slots are unaffected.
+        assert_eq!(*slab.get(b).unwrap(), 20);
+        assert_eq!(slab.len(), 2);
+
+        // Handles from the future, or for slots never used, resolve to
+        // nothing.
+        let bogus = Entity {
+            id: c.id,
+            generation: 7,
+        };
+        assert!(slab.get(bogus).is_none());
+        assert!(slab.get_mut(bogus).is_none());
+        assert!(slab.get_mut(a).is_none());
+        assert!(!slab.contains(Entity {
+            id: 50,
+            generation: 0
+        }));
+    }
+}
@@ -0,0 +1,118 @@
+//! An allocator split into a reserved pool and a dynamic pool.
+
+use crate::{AllocError, Ida};
+
+/// An ID allocator split into a reserved pool and a dynamic pool.
+///
+/// IDs below the boundary form the reserved pool and are only handed out when
+/// requested by exact index through [`reserve`](Self::reserve). IDs at or above
+/// the boundary form the dynamic pool and are assigned automatically by
+/// [`alloc_dynamic`](Self::alloc_dynamic). Keeping both pools in one allocator
+/// with a fixed boundary guarantees the two can never collide.
+///
+/// # Examples
+///
+/// ```
+/// use ida_rs::TwoPoolIda;
+///
+/// let pools = TwoPoolIda::new(16);
+///
+/// // Well-known IDs are reserved by index
+/// pools.reserve(3).unwrap();
+///
+/// // Everything else comes from above the boundary
+/// assert_eq!(pools.alloc_dynamic(), Some(16));
+/// assert_eq!(pools.alloc_dynamic(), Some(17));
+/// ```
+#[derive(Debug)]
+pub struct TwoPoolIda {
+    ida: Ida,
+    boundary: usize,
+}
+
+impl TwoPoolIda {
+    /// Creates a new allocator whose reserved pool is `0..boundary`.
+    pub fn new(boundary: usize) -> Self {
+        Self {
+            ida: Ida::new(),
+            boundary,
+        }
+    }
+
+    /// Returns the first ID of the dynamic pool.
+    pub fn boundary(&self) -> usize {
+        self.boundary
+    }
+
+    /// Reserves the specific ID `index` from the reserved pool.
+    ///
+    /// # Errors
+    ///
+    /// - [`AllocError::OutOfRange`] - If `index` is not below the boundary
+    /// - [`AllocError::AlreadyAllocated`] - If `index` is already reserved
+    pub fn reserve(&self, index: usize) -> Result<(), AllocError> {
+        if index >= self.boundary {
+            return Err(AllocError::OutOfRange);
+        }
+        self.ida.alloc_at(index)
+    }
+
+    /// Allocates the lowest free ID from the dynamic pool.
+    ///
+    /// Returns `None` if the dynamic pool is exhausted.
+    pub fn alloc_dynamic(&self) -> Option<usize> {
+        self.ida.alloc_in_range(self.boundary..)
+    }
+
+    /// Frees an ID from whichever pool it belongs to.
+    pub fn free(&self, id: usize) {
+        self.ida.free(id);
+    }
+
+    /// Checks if a given ID is currently allocated in either pool.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        self.ida.is_allocated(id)
+    }
+
+    /// Returns `true` if `id` belongs to the reserved pool.
+    pub fn is_reserved_id(&self, id: usize) -> bool {
+        id < self.boundary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_pools_do_not_collide() {
+        let pools = TwoPoolIda::new(8);
+        for index in [0, 3, 7] {
+            pools.reserve(index).unwrap();
+        }
+        assert_eq!(pools.reserve(3), Err(AllocError::AlreadyAllocated));
+        assert_eq!(pools.reserve(8), Err(AllocError::OutOfRange));
+
+        let dynamic: Vec<usize> = (0..10).map(|_| pools.alloc_dynamic().unwrap()).collect();
+        assert_eq!(dynamic, (8..18).collect::<Vec<_>>());
+        assert!(dynamic.iter().all(|&id| !pools.is_reserved_id(id)));
+    }
+
+    #[test]
+    fn test_pools_free_independently() {
+        let pools = TwoPoolIda::new(8);
+        pools.reserve(5).unwrap();
+        let id = pools.alloc_dynamic().unwrap();
+
+        pools.free(5);
+        assert!(!pools.is_allocated(5));
+        assert!(pools.is_allocated(id));
+        // Freeing a reserved ID never feeds the dynamic pool.
+        assert_eq!(pools.alloc_dynamic(), Some(9));
+
+        pools.free(id);
+        assert_eq!(pools.alloc_dynamic(), Some(8));
+        assert_eq!(pools.reserve(5), Ok(()));
+    }
+}